@@ -1,149 +1,941 @@
-use crate::Song;
+mod rate_limit;
+
+use crate::{
+    cache::Db,
+    tags::{self, Field, SongTag},
+    Song,
+};
 use anyhow::{anyhow, Context, Result};
-use id3::{Tag, TagLike, Version};
+use rate_limit::{Counters, RateLimiter};
+use reqwest::{blocking::Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
-    ffi::OsStr,
-    fs,
-    path::{Path, PathBuf},
+    hash::{BuildHasher, BuildHasherDefault},
+    path::Path,
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use twox_hash::XxHash64;
 
-#[derive(Debug, Serialize, Deserialize)]
+pub use rate_limit::LookupStats;
+
+/// Public AcoustID web service, see https://acoustid.org/webservice
+pub const DEFAULT_BASE_URL: &str = "https://api.acoustid.org/v2";
+
+/// Metadata asked for on every lookup, part of the cache key
+const META: &str = "recordings releasegroups releases tracks compress";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Artist {
     name: Option<String>,
     id: String,
+    /// Text after the name in a credit, e.g. " feat. "
+    joinphrase: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Names with their join phrases, e.g. "A feat. B & C"
+fn credit(artists: &[Artist]) -> Option<String> {
+    let named: Vec<_> = artists.iter().filter(|x| x.name.is_some()).collect();
+    let mut credit = String::new();
+    for (i, artist) in named.iter().enumerate() {
+        credit += artist.name.as_deref().unwrap_or_default();
+        let is_last = i + 1 == named.len();
+        match &artist.joinphrase {
+            Some(joinphrase) => credit += joinphrase,
+            None if !is_last => credit += ", ",
+            None => {}
+        }
+    }
+    let credit = credit.trim().to_owned();
+    (!credit.is_empty()).then_some(credit)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Recordings {
     duration: Option<u64>,
     id: String,
     title: Option<String>,
     artists: Option<Vec<Artist>>,
+    #[serde(default)]
+    releasegroups: Vec<ReleaseGroup>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ReleaseGroup {
+    id: String,
+    title: Option<String>,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    #[serde(default)]
+    secondarytypes: Vec<String>,
+    artists: Option<Vec<Artist>>,
+    #[serde(default)]
+    releases: Vec<Release>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Release {
+    id: String,
+    /// Missing when compressed and equal to the release group title
+    title: Option<String>,
+    /// Missing when compressed and equal to the release group artists
+    artists: Option<Vec<Artist>>,
+    date: Option<ReleaseDate>,
+    medium_count: Option<u32>,
+    #[serde(default)]
+    mediums: Vec<Medium>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ReleaseDate {
+    year: Option<i32>,
+    month: Option<u8>,
+    day: Option<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Medium {
+    position: Option<u32>,
+    track_count: Option<u32>,
+    /// Only the tracks of the looked up recording
+    #[serde(default)]
+    tracks: Vec<Track>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Track {
+    id: String,
+    position: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ServiceError {
+    code: Option<u32>,
+    message: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Post {
+    #[serde(default)]
     results: Vec<SongMatch>,
     status: String,
+    error: Option<ServiceError>,
 }
 
+/// Results of one fingerprint out of a batch request, `index` is the `N` of `fingerprint.N`
 #[derive(Debug, Serialize, Deserialize)]
-struct TrackId(String);
+struct IndexedResults {
+    index: BatchIndex,
+    #[serde(default)]
+    results: Vec<SongMatch>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum BatchIndex {
+    Number(usize),
+    Text(String),
+}
+
+impl BatchIndex {
+    fn get(&self) -> Option<usize> {
+        match self {
+            BatchIndex::Number(n) => Some(*n),
+            BatchIndex::Text(s) => s.parse().ok(),
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
+struct BatchPost {
+    #[serde(default)]
+    fingerprints: Vec<IndexedResults>,
+    status: String,
+    error: Option<ServiceError>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TrackId(String);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct SongMatch {
     id: TrackId,
     score: f64,
     recordings: Vec<Recordings>,
 }
 
+/// Lookup response as stored in the cache, `fetched_at` in seconds since the unix epoch
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CachedLookup {
+    fetched_at: u64,
+    candidates: Vec<SongMatch>,
+}
+
+impl CachedLookup {
+    pub(crate) fn fetched_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.fetched_at)
+    }
+
+    /// AcoustID tracks in the response
+    pub(crate) fn tracks(&self) -> usize {
+        self.candidates.len()
+    }
+}
+
+/// How lookups use the responses cached in [`Db`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Answer from the cache while entries are younger than the ttl
+    #[default]
+    Use,
+    /// Always ask the web service, overwrite cached entries with the new response
+    Refresh,
+    /// Neither read nor write the cache
+    Bypass,
+}
+
+/// How the credited artists of a recording end up in the artist (TPE1) and album artist
+/// (TPE2) frames
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ArtistPolicy {
+    /// Artist is the whole credit, e.g. "A feat. B", album artist comes from the release
+    #[default]
+    Joined,
+    /// Artist is the first credited artist only, album artist comes from the release
+    First,
+    /// Artist holds every credited artist as a separate value (TPE1 in ID3, ARTISTS next to
+    /// the whole credit in Vorbis comments and MP4), album artist comes from the release
+    Multi,
+    /// Artist is the whole credit, album artist is the first credited artist
+    PrimaryAlbumArtist,
+}
+
+/// What gets written into a song's tag, optional fields are left untouched when `None`
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct SongData {
     pub title: String,
+    /// Used for the file name and the artist frame
     pub artist: String,
+    /// Each credited artist, written next to `artist` when there are more than one
+    pub artists: Vec<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track: Option<u32>,
+    pub total_tracks: Option<u32>,
+    pub disc: Option<u32>,
+    pub total_discs: Option<u32>,
+    pub year: Option<i32>,
+    pub musicbrainz: MusicBrainzIds,
+}
+
+/// MusicBrainz identifiers, stored the same way MusicBrainz Picard does
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MusicBrainzIds {
+    pub recording_id: Option<String>,
+    pub release_id: Option<String>,
+    pub release_group_id: Option<String>,
+    pub artist_ids: Vec<String>,
 }
 
+/// The release, e.g. an album, a candidate recording was found on
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReleaseInfo {
+    pub release_id: String,
+    pub release_group_id: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub year: Option<i32>,
+    pub track: Option<u32>,
+    pub total_tracks: Option<u32>,
+    pub disc: Option<u32>,
+    pub total_discs: Option<u32>,
+}
+
+/// A recording AcoustID suggests for a fingerprint
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    /// Between 0 and 1, shared by all recordings of the same AcoustID track
+    pub score: f64,
+    pub recording_id: String,
+    pub title: Option<String>,
+    pub artists: Vec<String>,
+    /// Artists joined with MusicBrainz join phrases, e.g. "A feat. B"
+    pub artist_credit: Option<String>,
+    pub artist_ids: Vec<String>,
+    pub duration: Option<Duration>,
+    pub release: Option<ReleaseInfo>,
+}
+
+impl Candidate {
+    /// Title and artists plus release details, if the recording has both title and artists
+    pub fn song_data(&self, policy: ArtistPolicy) -> Option<SongData> {
+        let release = self.release.clone().unwrap_or_default();
+        let first = self.artists.first()?.clone();
+        let credit = self.artist_credit.clone().unwrap_or(first.clone());
+        let (artist, artists, album_artist) = match policy {
+            ArtistPolicy::Joined => (credit, vec![], release.artist),
+            ArtistPolicy::First => (first, vec![], release.artist),
+            ArtistPolicy::Multi => (credit, self.artists.clone(), release.artist),
+            ArtistPolicy::PrimaryAlbumArtist => (credit, vec![], Some(first)),
+        };
+        Some(SongData {
+            title: self.title.clone()?,
+            artist,
+            artists,
+            album: release.title,
+            album_artist,
+            track: release.track,
+            total_tracks: release.total_tracks,
+            disc: release.disc,
+            total_discs: release.total_discs,
+            year: release.year,
+            musicbrainz: MusicBrainzIds {
+                recording_id: Some(self.recording_id.clone()),
+                release_id: self.release.as_ref().map(|x| x.release_id.clone()),
+                release_group_id: self.release.as_ref().map(|x| x.release_group_id.clone()),
+                artist_ids: self.artist_ids.clone(),
+            },
+        })
+    }
+}
+
+fn credit_of(artists: &Option<Vec<Artist>>) -> Option<String> {
+    credit(artists.as_ref()?)
+}
+
+fn release_year(release: &Release) -> Option<i32> {
+    release.date.as_ref()?.year
+}
+
+/// Prefers plain albums over compilations, singles etc., then the earliest release
+fn preferred_release(groups: &[ReleaseGroup]) -> Option<ReleaseInfo> {
+    let (group, release) = groups
+        .iter()
+        .flat_map(|group| group.releases.iter().map(move |release| (group, release)))
+        .min_by_key(|(group, release)| {
+            (
+                !group.secondarytypes.is_empty(),
+                group.kind.as_deref() != Some("Album"),
+                release_year(release).unwrap_or(i32::MAX),
+            )
+        })?;
+
+    let medium = release.mediums.iter().find(|x| !x.tracks.is_empty());
+    let track = medium.and_then(|x| x.tracks.first());
+    Some(ReleaseInfo {
+        release_id: release.id.clone(),
+        release_group_id: group.id.clone(),
+        title: release.title.clone().or(group.title.clone()),
+        artist: credit_of(&release.artists).or(credit_of(&group.artists)),
+        year: release_year(release),
+        track: track.and_then(|x| x.position),
+        total_tracks: medium.and_then(|x| x.track_count),
+        disc: medium.and_then(|x| x.position),
+        total_discs: release.medium_count,
+    })
+}
+
+/// Recordings of every result, best scored first
+fn ranked(mut matches: Vec<SongMatch>) -> Vec<Candidate> {
+    matches.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+    matches
+        .into_iter()
+        .flat_map(|x| {
+            let score = x.score;
+            x.recordings.into_iter().map(move |recording| {
+                let artists = recording.artists.unwrap_or_default();
+                Candidate {
+                    score,
+                    release: preferred_release(&recording.releasegroups),
+                    recording_id: recording.id,
+                    title: recording.title,
+                    artist_credit: credit(&artists),
+                    artist_ids: artists.iter().map(|x| x.id.clone()).collect(),
+                    artists: artists.into_iter().filter_map(|x| x.name).collect(),
+                    duration: recording.duration.map(Duration::from_secs),
+                }
+            })
+        })
+        .collect()
+}
+
+/// Best scored candidate that has both title and artists
+pub fn most_likely_song(candidates: &[Candidate], policy: ArtistPolicy) -> Result<SongData> {
+    if candidates.is_empty() {
+        return Err(anyhow!("no match found"));
+    }
+    candidates
+        .iter()
+        .find_map(|x| x.song_data(policy))
+        .ok_or(anyhow!("no recording with title and artists"))
+}
+
+/// Values joined by null, the way id3v2.4 keeps multiple values in one frame
+fn joined(tag: &dyn SongTag, field: Field) -> Option<String> {
+    let values = tag.get(field);
+    (!values.is_empty()).then(|| values.join("\0"))
+}
+
+fn split(value: &Option<String>) -> Vec<String> {
+    value
+        .iter()
+        .flat_map(|x| x.split('\0'))
+        .map(str::to_owned)
+        .collect()
+}
+
+fn set_some(tag: &mut dyn SongTag, field: Field, value: Option<impl ToString>) {
+    if let Some(value) = value {
+        tag.set(field, vec![value.to_string()]);
+    }
+}
+
+/// Writes `new` into the tag of `songfile`, whatever its format
 pub fn write_song_data(songfile: impl AsRef<Path>, new: &SongData) -> Result<()> {
-    let mut tag = Tag::read_from_path(&songfile).unwrap_or_default();
+    let songfile = songfile.as_ref();
+    let mut tag = tags::read(songfile)?;
+    let tag = tag.as_mut();
 
-    tag.set_artist(new.artist.clone());
-    tag.set_title(new.title.clone());
-    tag.set_album_artist(new.title.clone());
+    tag.set(Field::Artist, vec![new.artist.clone()]);
+    if new.artists.len() > 1 {
+        tag.set(Field::Artists, new.artists.clone());
+    }
+    tag.set(Field::Title, vec![new.title.clone()]);
+    set_some(tag, Field::AlbumArtist, new.album_artist.as_ref());
+    set_some(tag, Field::Album, new.album.as_ref());
+    set_some(tag, Field::Track, new.track);
+    set_some(tag, Field::TotalTracks, new.total_tracks);
+    set_some(tag, Field::Disc, new.disc);
+    set_some(tag, Field::TotalDiscs, new.total_discs);
+    set_some(tag, Field::Year, new.year);
 
-    tag.write_to_path(&songfile, Version::Id3v24)
-        .with_context(|| "failed to write id3 tag")
+    let ids = &new.musicbrainz;
+    set_some(
+        tag,
+        Field::MusicBrainzRecordingId,
+        ids.recording_id.as_ref(),
+    );
+    set_some(tag, Field::MusicBrainzReleaseId, ids.release_id.as_ref());
+    set_some(
+        tag,
+        Field::MusicBrainzReleaseGroupId,
+        ids.release_group_id.as_ref(),
+    );
+    if !ids.artist_ids.is_empty() {
+        tag.set(Field::MusicBrainzArtistId, ids.artist_ids.clone());
+    }
+
+    tag.save(songfile)
 }
 
-pub fn rename_file_as_artist_dash_title(songfile: &Path) -> Result<PathBuf> {
-    let tag = Tag::read_from_path(songfile).with_context(|| "tag missing")?;
-    let dir = songfile.parent().with_context(|| "no parent dir")?;
+/// Fields of a song's tag as they are now, `None` where a field is missing.
+/// Fields with many values have them joined by null
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TagSnapshot {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub artists: Option<String>,
+    pub album_artist: Option<String>,
+    pub album: Option<String>,
+    pub track: Option<u32>,
+    pub total_tracks: Option<u32>,
+    pub disc: Option<u32>,
+    pub total_discs: Option<u32>,
+    pub year: Option<i32>,
+    /// The date as written in the tag, it may hold more than the year
+    pub date: Option<String>,
+    pub musicbrainz_recording_id: Option<String>,
+    pub musicbrainz_release_id: Option<String>,
+    pub musicbrainz_release_group_id: Option<String>,
+    pub musicbrainz_artist_id: Option<String>,
+}
 
-    let new_artist = tag.artist().with_context(|| "tag artist missing")?;
-    let new_title = tag.title().with_context(|| "tag title missing")?;
-    if new_artist.trim().is_empty() || new_title.is_empty() {
-        return Err(anyhow!("file has tag but wither artist or title is empty"));
+impl TagSnapshot {
+    /// The fields of `changes` that have a value replace these
+    pub fn with_changes(self, changes: &TagSnapshot) -> Self {
+        let changes = changes.clone();
+        TagSnapshot {
+            title: changes.title.or(self.title),
+            artist: changes.artist.or(self.artist),
+            artists: changes.artists.or(self.artists),
+            album_artist: changes.album_artist.or(self.album_artist),
+            album: changes.album.or(self.album),
+            track: changes.track.or(self.track),
+            total_tracks: changes.total_tracks.or(self.total_tracks),
+            disc: changes.disc.or(self.disc),
+            total_discs: changes.total_discs.or(self.total_discs),
+            year: changes.year.or(self.year),
+            date: changes.date.or(self.date),
+            musicbrainz_recording_id: changes
+                .musicbrainz_recording_id
+                .or(self.musicbrainz_recording_id),
+            musicbrainz_release_id: changes
+                .musicbrainz_release_id
+                .or(self.musicbrainz_release_id),
+            musicbrainz_release_group_id: changes
+                .musicbrainz_release_group_id
+                .or(self.musicbrainz_release_group_id),
+            musicbrainz_artist_id: changes.musicbrainz_artist_id.or(self.musicbrainz_artist_id),
+        }
     }
 
-    let extension = songfile.extension().unwrap_or(OsStr::new(""));
-    let mut newfile: PathBuf = [
-        dir.to_str()
-            .with_context(|| "song dir not unicode")?
-            .to_owned(),
-        format!("{} - {}", new_artist, new_title),
+    /// The tag as it is after writing `new` over it
+    pub fn with_song_data(mut self, new: &SongData) -> Self {
+        fn some<T: Clone>(field: &mut Option<T>, value: &Option<T>) {
+            if value.is_some() {
+                *field = value.clone();
+            }
+        }
+        self.title = Some(new.title.clone());
+        self.artist = Some(new.artist.clone());
+        if new.artists.len() > 1 {
+            self.artists = Some(new.artists.join("\0"));
+        }
+        some(&mut self.album_artist, &new.album_artist);
+        some(&mut self.album, &new.album);
+        some(&mut self.track, &new.track);
+        some(&mut self.total_tracks, &new.total_tracks);
+        some(&mut self.disc, &new.disc);
+        some(&mut self.total_discs, &new.total_discs);
+        if new.year.is_some() {
+            self.year = new.year;
+            self.date = new.year.map(|x| x.to_string());
+        }
+        let ids = &new.musicbrainz;
+        some(&mut self.musicbrainz_recording_id, &ids.recording_id);
+        some(&mut self.musicbrainz_release_id, &ids.release_id);
+        some(
+            &mut self.musicbrainz_release_group_id,
+            &ids.release_group_id,
+        );
+        if !ids.artist_ids.is_empty() {
+            self.musicbrainz_artist_id = Some(ids.artist_ids.join("\0"));
+        }
+        self
+    }
+}
+
+pub fn read_tag_snapshot(songfile: impl AsRef<Path>) -> TagSnapshot {
+    let Ok(tag) = tags::read(songfile.as_ref()) else {
+        return TagSnapshot::default();
+    };
+    let tag = tag.as_ref();
+    TagSnapshot {
+        title: joined(tag, Field::Title),
+        artist: joined(tag, Field::Artist),
+        artists: joined(tag, Field::Artists),
+        album_artist: joined(tag, Field::AlbumArtist),
+        album: joined(tag, Field::Album),
+        track: tag.number(Field::Track),
+        total_tracks: tag.number(Field::TotalTracks),
+        disc: tag.number(Field::Disc),
+        total_discs: tag.number(Field::TotalDiscs),
+        year: tag.first(Field::Year).and_then(|x| x.parse().ok()),
+        date: tag.first(Field::Date),
+        musicbrainz_recording_id: joined(tag, Field::MusicBrainzRecordingId),
+        musicbrainz_release_id: joined(tag, Field::MusicBrainzReleaseId),
+        musicbrainz_release_group_id: joined(tag, Field::MusicBrainzReleaseGroupId),
+        musicbrainz_artist_id: joined(tag, Field::MusicBrainzArtistId),
+    }
+}
+
+/// Year a date like "2015-06-01" starts with
+fn year(date: &str) -> Option<i32> {
+    let digits = date.trim().split(|x: char| !x.is_ascii_digit()).next()?;
+    digits.parse().ok()
+}
+
+/// Values of every field of `snapshot`, none where the field is missing
+fn snapshot_fields(snapshot: &TagSnapshot) -> Vec<(Field, Vec<String>)> {
+    let number = |x: Option<u32>| x.iter().map(u32::to_string).collect();
+    // the full date unless the year was changed since
+    let date = match &snapshot.date {
+        Some(date) if year(date) == snapshot.year => (Field::Date, vec![date.clone()]),
+        _ => (
+            Field::Year,
+            snapshot.year.iter().map(i32::to_string).collect(),
+        ),
+    };
+    vec![
+        (Field::Title, split(&snapshot.title)),
+        (Field::Artist, split(&snapshot.artist)),
+        (Field::Artists, split(&snapshot.artists)),
+        (Field::AlbumArtist, split(&snapshot.album_artist)),
+        (Field::Album, split(&snapshot.album)),
+        // track and disc numbers may share a frame with their totals, "3/12"
+        (Field::Track, number(snapshot.track)),
+        (Field::TotalTracks, number(snapshot.total_tracks)),
+        (Field::Disc, number(snapshot.disc)),
+        (Field::TotalDiscs, number(snapshot.total_discs)),
+        date,
+        (
+            Field::MusicBrainzRecordingId,
+            split(&snapshot.musicbrainz_recording_id),
+        ),
+        (
+            Field::MusicBrainzReleaseId,
+            split(&snapshot.musicbrainz_release_id),
+        ),
+        (
+            Field::MusicBrainzReleaseGroupId,
+            split(&snapshot.musicbrainz_release_group_id),
+        ),
+        (
+            Field::MusicBrainzArtistId,
+            split(&snapshot.musicbrainz_artist_id),
+        ),
     ]
-    .iter()
-    .collect();
-    newfile.set_extension(extension);
-    if songfile.canonicalize()? != newfile.canonicalize()? {
-        fs::rename(songfile, &newfile).with_context(|| "renaming failed")?;
-    }
-    println!(
-        "Renamed `{}` as `{}`",
-        songfile.display(),
-        newfile.display()
-    );
+}
+
+/// Puts back the fields of `snapshot`, fields that were missing get removed
+pub fn restore_tag_snapshot(songfile: impl AsRef<Path>, snapshot: &TagSnapshot) -> Result<()> {
+    let songfile = songfile.as_ref();
+    let mut tag = tags::read(songfile)?;
+    for (field, values) in snapshot_fields(snapshot) {
+        tag.set(field, values);
+    }
+    tag.save(songfile)
+}
+
+/// Writes the fields of `changes` that have a value, the others are left as they are
+pub fn write_tag_changes(songfile: impl AsRef<Path>, changes: &TagSnapshot) -> Result<()> {
+    let songfile = songfile.as_ref();
+    let mut tag = tags::read(songfile)?;
+    for (field, values) in snapshot_fields(changes) {
+        if !values.is_empty() {
+            tag.set(field, values);
+        }
+    }
+    tag.save(songfile)
+}
+
+/// Where and how to reach an AcoustID compatible web service
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// E.g. `https://api.acoustid.org/v2`, `/lookup` gets appended
+    pub base_url: String,
+    /// Application API key https://acoustid.org/webservice#lookup
+    pub api_key: String,
+    pub timeout: Duration,
+    pub user_agent: String,
+    /// AcoustID allows ~3 requests per second per application
+    pub requests_per_second: f64,
+    /// How many times a request failing with 429, 5xx or a timeout is sent again
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled on every further attempt
+    pub retry_backoff: Duration,
+    pub cache: CacheMode,
+    /// Cached responses older than this are fetched again
+    pub cache_ttl: Duration,
+}
+
+impl ClientConfig {
+    pub fn new(api_key: impl Into<String>) -> Self {
+        ClientConfig {
+            base_url: DEFAULT_BASE_URL.to_owned(),
+            api_key: api_key.into(),
+            timeout: Duration::from_secs(30),
+            user_agent: format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            requests_per_second: 3.0,
+            max_retries: 4,
+            retry_backoff: Duration::from_millis(500),
+            cache: CacheMode::Use,
+            cache_ttl: Duration::from_secs(30 * 24 * 60 * 60),
+        }
+    }
+
+    /// Reads the api key from `ACOUSTID_API_KEY` and, if set, the base url from `ACOUSTID_API_URL`
+    pub fn from_env() -> Result<Self> {
+        let api_key = std::env::var("ACOUSTID_API_KEY").with_context(|| {
+            "reading env var ACOUSTID_API_KEY, register app at https://acoustid.org/my-applications or use the same client as in examples at https://acoustid.org/webservice"
+        })?;
+        let mut config = ClientConfig::new(api_key);
+        if let Ok(base_url) = std::env::var("ACOUSTID_API_URL") {
+            config.base_url = base_url;
+        }
+        Ok(config)
+    }
+}
+
+/// Blocking AcoustID client, build it once and share it between lookups,
+/// clones and threads share the same rate limit
+#[derive(Debug, Clone)]
+pub struct Client {
+    config: ClientConfig,
+    http: reqwest::blocking::Client,
+    limiter: Arc<RateLimiter>,
+    counters: Arc<Counters>,
+    db: Db,
+}
+
+impl Client {
+    pub fn new(config: ClientConfig) -> Result<Self> {
+        Client::with_db(config, Db::new())
+    }
+
+    /// Client caching lookup responses in `db` instead of the user's default database
+    pub fn with_db(config: ClientConfig, db: Db) -> Result<Self> {
+        let http = reqwest::blocking::Client::builder()
+            .timeout(config.timeout)
+            .user_agent(config.user_agent.clone())
+            .build()
+            .with_context(|| "building http client")?;
+        let limiter = Arc::new(RateLimiter::new(config.requests_per_second, 1));
+        Ok(Client {
+            config,
+            http,
+            limiter,
+            counters: Default::default(),
+            db,
+        })
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
 
-    Ok(newfile)
+    pub fn stats(&self) -> LookupStats {
+        self.counters.snapshot()
+    }
+
+    /// Posts the form respecting the rate limit, transient failures are retried with backoff
+    fn post<T: Serialize + ?Sized>(&self, form: &T) -> Result<Response> {
+        let mut backoff = self.config.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            self.limiter.acquire();
+            self.counters.request();
+            let res = self.http.post(self.lookup_url()).form(form).send();
+
+            let retry_after = match &res {
+                Ok(response) if is_transient_status(response.status()) => {
+                    Some(retry_after(response).unwrap_or(backoff))
+                }
+                Err(err) if err.is_timeout() || err.is_connect() => Some(backoff),
+                _ => None,
+            };
+            match retry_after {
+                Some(wait) if attempt < self.config.max_retries => {
+                    attempt += 1;
+                    self.counters.retry();
+                    eprintln!("Retry {attempt}/{} in {wait:?}", self.config.max_retries);
+                    thread::sleep(wait);
+                    backoff *= 2;
+                }
+                Some(_) => {
+                    self.counters.dropped();
+                    return Ok(res?.error_for_status()?);
+                }
+                None => return Ok(res?.error_for_status()?),
+            }
+        }
+    }
+
+    fn lookup_url(&self) -> String {
+        format!("{}/lookup", self.config.base_url.trim_end_matches('/'))
+    }
+
+    /// Looks up a chromaprint fingerprint and returns the most likely title and artist
+    pub fn lookup(&self, fingerprint: &str, duration: Duration) -> Result<SongData> {
+        most_likely_song(
+            &self.lookup_candidates(fingerprint, duration)?,
+            ArtistPolicy::default(),
+        )
+    }
+
+    /// Every recording matching the fingerprint, best scored first
+    pub fn lookup_candidates(
+        &self,
+        fingerprint: &str,
+        duration: Duration,
+    ) -> Result<Vec<Candidate>> {
+        Ok(ranked(self.lookup_matches(fingerprint, duration)?))
+    }
+
+    fn lookup_matches(&self, fingerprint: &str, duration: Duration) -> Result<Vec<SongMatch>> {
+        if let Some(candidates) = self.cached(fingerprint, duration) {
+            return Ok(candidates);
+        }
+
+        let display_short_acoustid: String = fingerprint.chars().take(15).collect();
+        let duration_secs = duration.as_secs().to_string();
+        let map = HashMap::from([
+            // Test https://acoustid.org/webservice#lookup
+            //("fingerprint", "AQABz0qUkZK4oOfhL-CPc4e5C_wW2H2QH9uDL4cvoT8UNQ-eHtsE8cceeFJx-LiiHT-aPzhxoc-Opj_eI5d2hOFyMJRzfDk-QSsu7fBxqZDMHcfxPfDIoPWxv9C1o3yg44d_3Df2GJaUQeeR-cb2HfaPNsdxHj2PJnpwPMN3aPcEMzd-_MeB_Ej4D_CLP8ghHjkJv_jh_UDuQ8xnILwunPg6hF2R8HgzvLhxHVYP_ziJX0eKPnIE1UePMByDJyg7wz_6yELsB8n4oDmDa0Gv40hf6D3CE3_wH6HFaxCPUD9-hNeF5MfWEP3SCGym4-SxnXiGs0mRjEXD6fgl4LmKWrSChzzC33ge9PB3otyJMk-IVC6R8MTNwD9qKQ_CC8kPv4THzEGZS8GPI3x0iGVUxC1hRSizC5VzoamYDi-uR7iKPhGSI82PkiWeB_eHijvsaIWfBCWH5AjjCfVxZ1TQ3CvCTclGnEMfHbnZFA8pjD6KXwd__Cn-Y8e_I9cq6CR-4S9KLXqQcsxxoWh3eMxiHI6TIzyPv0M43YHz4yte-Cv-4D16Hv9F9C9SPUdyGtZRHV-OHEeeGD--BKcjVLOK_NCDXMfx44dzHEiOZ0Z44Rf6DH5R3uiPj4d_PKolJNyRJzyu4_CTD2WOvzjKH9GPb4cUP1Av9EuQd8fGCFee4JlRHi18xQh96NLxkCgfWFKOH6WGeoe4I3za4c5hTscTPEZTES1x8kE-9MQPjT8a8gh5fPgQZtqCFj9MDvp6fDx6NCd07bjx7MLR9AhtnFnQ70GjOcV0opmm4zpY3SOa7HiwdTtyHa6NC4e-HN-OfC5-OP_gLe2QDxfUCz_0w9l65HiPAz9-IaGOUA7-4MZ5CWFOlIfe4yUa6AiZGxf6w0fFxsjTOdC6Itbh4mGD63iPH9-RFy909XAMj7mC5_BvlDyO6kGTZKJxHUd4NDwuZUffw_5RMsde5CWkJAgXnDReNEaP6DTOQ65yaD88HoeX8fge-DSeHo9Qa8cTHc80I-_RoHxx_UHeBxrJw62Q34Kd7MEfpCcu6BLeB1ePw6OO4sOF_sHhmB504WWDZiEu8sKPpkcfCT9xfej0o0lr4T5yNJeOvjmu40w-TDmqHXmYgfFhFy_M7tD1o0cO_B2ms2j-ACEEQgQgAIwzTgAGmBIKIImNQAABwgQATAlhDGCCEIGIIM4BaBgwQBogEBIOESEIA8ARI5xAhxEFmAGAMCKAURKQQpQzRAAkCCBQEAKkQYIYIQQxCixCDADCABMAE0gpJIgyxhEDiCKCCIGAEIgJIQByAhFgGACCACMRQEyBAoxQiHiCBCFOECQFAIgAABR2QAgFjCDMA0AUMIoAIMChQghChASGEGeYEAIAIhgBSErnJPPEGWYAMgw05AhiiGHiBBBGGSCQcQgwRYJwhDDhgCSCSSEIQYwILoyAjAIigBFEUQK8gAYAQ5BCAAjkjCCAEEMZAUQAZQCjCCkpCgFMCCiIcVIAZZgilAQAiSHQECOcQAQIc4QClAHAjDDGkAGAMUoBgyhihgEChFCAAWEIEYwIJYwViAAlHCBIGEIEAEIQAoBwwgwiEBAEEEOoEwBY4wRwxAhBgAcKAESIQAwwIowRFhoBhAE"),
+            //("duration", "641"), // song duration
+            ("format", "json"), // response format
+            ("client", self.config.api_key.as_str()),
+            ("duration", &duration_secs), // song duration
+            ("fingerprint", fingerprint),
+            ("meta", META),
+        ]);
+
+        eprintln!(
+            "Request: song with duration {duration_secs} sec. and acoustid {display_short_acoustid}"
+        );
+
+        let bytes = self.post(&map)?.bytes()?;
+        let json: Post =
+            serde_json::from_slice(bytes.as_ref()).with_context(|| "unexpected response")?;
+        check_service_error(json.error)?;
+
+        self.store(fingerprint, duration, &json.results);
+        Ok(json.results)
+    }
+
+    /// Looks up many fingerprints with a single request, results are in the same order as
+    /// `songs`, a fingerprint without a usable match gets its own error
+    pub fn lookup_batch(&self, songs: &[(&str, Duration)]) -> Result<Vec<Result<SongData>>> {
+        Ok(self
+            .lookup_batch_candidates(songs)?
+            .iter()
+            .map(|candidates| most_likely_song(candidates, ArtistPolicy::default()))
+            .collect())
+    }
+
+    /// Candidates of every fingerprint in `songs`, in the same order, using a single request
+    pub fn lookup_batch_candidates(
+        &self,
+        songs: &[(&str, Duration)],
+    ) -> Result<Vec<Vec<Candidate>>> {
+        let mut matches: Vec<Option<Vec<SongMatch>>> = songs
+            .iter()
+            .map(|(fingerprint, duration)| self.cached(fingerprint, *duration))
+            .collect();
+        // positions in `songs` of the fingerprints not in the cache, `fingerprint.N` refers to
+        // the N-th of them
+        let missing: Vec<usize> = (0..songs.len()).filter(|&i| matches[i].is_none()).collect();
+        if !missing.is_empty() {
+            for (i, candidates) in self.request_batch(songs, &missing)? {
+                matches[i] = Some(candidates);
+            }
+        }
+
+        Ok(matches
+            .into_iter()
+            .map(|candidates| ranked(candidates.unwrap_or_default()))
+            .collect())
+    }
+
+    fn request_batch(
+        &self,
+        songs: &[(&str, Duration)],
+        missing: &[usize],
+    ) -> Result<Vec<(usize, Vec<SongMatch>)>> {
+        let mut form = vec![
+            ("format".to_owned(), "json".to_owned()),
+            ("client".to_owned(), self.config.api_key.clone()),
+            ("meta".to_owned(), META.to_owned()),
+        ];
+        for (n, &i) in missing.iter().enumerate() {
+            let (fingerprint, duration) = songs[i];
+            form.push((format!("duration.{n}"), duration.as_secs().to_string()));
+            form.push((format!("fingerprint.{n}"), fingerprint.to_owned()));
+        }
+
+        eprintln!(
+            "Request: batch of {} songs, {} cached",
+            missing.len(),
+            songs.len() - missing.len()
+        );
+
+        let bytes = self.post(&form)?.bytes()?;
+        let json: BatchPost =
+            serde_json::from_slice(bytes.as_ref()).with_context(|| "unexpected response")?;
+        check_service_error(json.error)?;
+
+        let mut found = vec![];
+        for fingerprint in json.fingerprints {
+            match fingerprint.index.get().and_then(|n| missing.get(n)) {
+                Some(&i) => {
+                    let (fp, duration) = songs[i];
+                    self.store(fp, duration, &fingerprint.results);
+                    found.push((i, fingerprint.results));
+                }
+                None => eprintln!(
+                    "ignoring result with unexpected index {:?}",
+                    fingerprint.index
+                ),
+            }
+        }
+        Ok(found)
+    }
+
+    fn cached(&self, fingerprint: &str, duration: Duration) -> Option<Vec<SongMatch>> {
+        if self.config.cache != CacheMode::Use {
+            return None;
+        }
+        let cached = self.db.get_lookup(&lookup_key(fingerprint, duration))?;
+        let age = now_secs().saturating_sub(cached.fetched_at);
+        (age <= self.config.cache_ttl.as_secs()).then_some(cached.candidates)
+    }
+
+    fn store(&self, fingerprint: &str, duration: Duration, candidates: &[SongMatch]) {
+        if self.config.cache == CacheMode::Bypass {
+            return;
+        }
+        let cached = CachedLookup {
+            fetched_at: now_secs(),
+            candidates: candidates.to_vec(),
+        };
+        let _dont_care = self
+            .db
+            .insert_lookup(&lookup_key(fingerprint, duration), cached);
+    }
+}
+
+/// Cache key, the web service is asked with whole seconds so that's what makes a lookup unique,
+/// asking for other metadata changes the response too
+pub(crate) fn lookup_key(fingerprint: &str, duration: Duration) -> String {
+    let hasher: BuildHasherDefault<XxHash64> = Default::default();
+    format!(
+        "{}-{}",
+        hasher.hash_one((fingerprint, META)),
+        duration.as_secs()
+    )
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+fn check_service_error(error: Option<ServiceError>) -> Result<()> {
+    match error {
+        Some(err) => Err(anyhow!(
+            "acoustid error {}: {}",
+            err.code.unwrap_or_default(),
+            err.message
+        )),
+        None => Ok(()),
+    }
 }
 
-pub fn lookup_by_fingerprint(mut song: Song) -> Result<SongData> {
-    // API key https://acoustid.org/webservice#lookup
-    let api_key = std::env::var("ACOUSTID_API_KEY").with_context(|| {
-        "reading env var ACOUSTID_API_KEY, register app at https://acoustid.org/my-applications or use the same client as in examples at https://acoustid.org/webservice"
-    })?;
+fn is_transient_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
 
+/// `Retry-After` header given in seconds
+fn retry_after(response: &Response) -> Option<Duration> {
+    let seconds = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+pub fn lookup_by_fingerprint(client: &Client, mut song: Song) -> Result<SongData> {
     let acoustid = song
         .get_acoustid()
         .with_context(|| format!("{}", song.path.display()))?;
-    let display_short_acoustid = &acoustid.to_string()[0..15];
-    let url = "https://api.acoustid.org/v2/lookup";
-    let client = reqwest::blocking::Client::new();
-    let duration = song.get_duration()?.as_secs().to_string();
-    let fingerprint = acoustid.to_string();
-    let map = HashMap::from([
-        // Test https://acoustid.org/webservice#lookup
-        //("fingerprint", "AQABz0qUkZK4oOfhL-CPc4e5C_wW2H2QH9uDL4cvoT8UNQ-eHtsE8cceeFJx-LiiHT-aPzhxoc-Opj_eI5d2hOFyMJRzfDk-QSsu7fBxqZDMHcfxPfDIoPWxv9C1o3yg44d_3Df2GJaUQeeR-cb2HfaPNsdxHj2PJnpwPMN3aPcEMzd-_MeB_Ej4D_CLP8ghHjkJv_jh_UDuQ8xnILwunPg6hF2R8HgzvLhxHVYP_ziJX0eKPnIE1UePMByDJyg7wz_6yELsB8n4oDmDa0Gv40hf6D3CE3_wH6HFaxCPUD9-hNeF5MfWEP3SCGym4-SxnXiGs0mRjEXD6fgl4LmKWrSChzzC33ge9PB3otyJMk-IVC6R8MTNwD9qKQ_CC8kPv4THzEGZS8GPI3x0iGVUxC1hRSizC5VzoamYDi-uR7iKPhGSI82PkiWeB_eHijvsaIWfBCWH5AjjCfVxZ1TQ3CvCTclGnEMfHbnZFA8pjD6KXwd__Cn-Y8e_I9cq6CR-4S9KLXqQcsxxoWh3eMxiHI6TIzyPv0M43YHz4yte-Cv-4D16Hv9F9C9SPUdyGtZRHV-OHEeeGD--BKcjVLOK_NCDXMfx44dzHEiOZ0Z44Rf6DH5R3uiPj4d_PKolJNyRJzyu4_CTD2WOvzjKH9GPb4cUP1Av9EuQd8fGCFee4JlRHi18xQh96NLxkCgfWFKOH6WGeoe4I3za4c5hTscTPEZTES1x8kE-9MQPjT8a8gh5fPgQZtqCFj9MDvp6fDx6NCd07bjx7MLR9AhtnFnQ70GjOcV0opmm4zpY3SOa7HiwdTtyHa6NC4e-HN-OfC5-OP_gLe2QDxfUCz_0w9l65HiPAz9-IaGOUA7-4MZ5CWFOlIfe4yUa6AiZGxf6w0fFxsjTOdC6Itbh4mGD63iPH9-RFy909XAMj7mC5_BvlDyO6kGTZKJxHUd4NDwuZUffw_5RMsde5CWkJAgXnDReNEaP6DTOQ65yaD88HoeX8fge-DSeHo9Qa8cTHc80I-_RoHxx_UHeBxrJw62Q34Kd7MEfpCcu6BLeB1ePw6OO4sOF_sHhmB504WWDZiEu8sKPpkcfCT9xfej0o0lr4T5yNJeOvjmu40w-TDmqHXmYgfFhFy_M7tD1o0cO_B2ms2j-ACEEQgQgAIwzTgAGmBIKIImNQAABwgQATAlhDGCCEIGIIM4BaBgwQBogEBIOESEIA8ARI5xAhxEFmAGAMCKAURKQQpQzRAAkCCBQEAKkQYIYIQQxCixCDADCABMAE0gpJIgyxhEDiCKCCIGAEIgJIQByAhFgGACCACMRQEyBAoxQiHiCBCFOECQFAIgAABR2QAgFjCDMA0AUMIoAIMChQghChASGEGeYEAIAIhgBSErnJPPEGWYAMgw05AhiiGHiBBBGGSCQcQgwRYJwhDDhgCSCSSEIQYwILoyAjAIigBFEUQK8gAYAQ5BCAAjkjCCAEEMZAUQAZQCjCCkpCgFMCCiIcVIAZZgilAQAiSHQECOcQAQIc4QClAHAjDDGkAGAMUoBgyhihgEChFCAAWEIEYwIJYwViAAlHCBIGEIEAEIQAoBwwgwiEBAEEEOoEwBY4wRwxAhBgAcKAESIQAwwIowRFhoBhAE"),
-        //("duration", "641"), // song duration
-        ("format", "json"), // response format
-        ("client", &api_key),
-        ("duration", &duration), // song duration
-        ("fingerprint", &fingerprint),
-        ("meta", "recordings"),
-    ]);
-
-    eprintln!("Request: song with duration {duration} sec. and acoustid {display_short_acoustid}");
-
-    let bytes = client
-        .post(url)
-        .form(&map)
-        .send()?
-        .error_for_status()?
-        .bytes()?;
-    eprintln!("response bytes: {bytes:?}");
-    let json: Post =
-        serde_json::from_slice(bytes.as_ref()).with_context(|| "unexpected response")?;
-
-    let mut candidates = json.results;
-    candidates.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
-    let most_likely_candidate = candidates
-        .first()
-        .ok_or(anyhow!(""))?
-        .recordings
-        .iter()
-        .find(|x| x.title.is_some() && x.artists.is_some())
-        .ok_or(anyhow!(""))?;
-
-    let title = most_likely_candidate.title.clone().unwrap();
-    let artist = most_likely_candidate
-        .artists
-        .as_ref()
-        .unwrap() // won't panic because of find(...)
-        .first()
-        .ok_or(anyhow!(""))?
-        .name
-        .clone()
-        .ok_or(anyhow!(""))?;
-
-    Ok(SongData { title, artist })
+    let duration = song.get_duration()?;
+    client.lookup(&acoustid.to_string(), duration)
 }
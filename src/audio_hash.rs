@@ -0,0 +1,178 @@
+//! Hashing only the audio of a song, so writing tags doesn't change the hash
+
+use std::{
+    fs::File,
+    hash::{BuildHasher, BuildHasherDefault, Hasher},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::{anyhow, Context, Result};
+use ogg::PacketReader;
+use twox_hash::XxHash64;
+
+use crate::{tags::id3v2_len, FileHash};
+
+/// XxHash64 of the audio in `path`, the format is picked by file extension.
+/// - mp3 and others: the bytes between a leading ID3v2 tag and trailing APE and ID3v1 tags
+/// - flac: the frames after the metadata blocks
+/// - ogg and opus: the packets after the header packets
+/// - m4a and mp4: the `mdat` atoms
+///
+/// Untagged mp3 files hash like their whole content
+pub fn audio_hash(path: &Path) -> Result<FileHash> {
+    let extension = path
+        .extension()
+        .map(|x| x.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let mut file = BufReader::with_capacity(
+        64 * 1024,
+        File::open(path).with_context(|| format!("opening {}", path.display()))?,
+    );
+    let mut hasher = BuildHasherDefault::<XxHash64>::default().build_hasher();
+    match extension.as_str() {
+        "flac" => flac(&mut file, &mut hasher),
+        "ogg" | "oga" | "opus" => ogg(&mut file, &mut hasher),
+        "m4a" | "m4b" | "mp4" => mp4(&mut file, &mut hasher),
+        _ => {
+            let start = id3v2_start(&mut file)?;
+            let end = tags_end(&mut file)?;
+            // like `Hash` for a byte slice, it writes its length first
+            hasher.write_usize(end.saturating_sub(start) as usize);
+            hash_range(&mut file, start, end, &mut hasher)
+        }
+    }
+    .with_context(|| format!("hashing the audio of {}", path.display()))?;
+    Ok(FileHash(hasher.finish().to_string()))
+}
+
+/// Feeds the bytes from `start` to `end` into `hasher`, a buffer at a time
+fn hash_range(
+    file: &mut BufReader<File>,
+    start: u64,
+    end: u64,
+    hasher: &mut impl Hasher,
+) -> Result<()> {
+    file.seek(SeekFrom::Start(start))?;
+    let mut left = end.saturating_sub(start);
+    while left > 0 {
+        let buf = file.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        let len = buf.len().min(left as usize);
+        hasher.write(&buf[..len]);
+        file.consume(len);
+        left -= len as u64;
+    }
+    Ok(())
+}
+
+/// Where the audio starts, after an ID3v2 tag if there's one
+fn id3v2_start(file: &mut BufReader<File>) -> Result<u64> {
+    let mut head = [0; 10];
+    file.seek(SeekFrom::Start(0))?;
+    match file.read_exact(&mut head) {
+        Ok(()) => Ok(id3v2_len(&head)),
+        Err(_) => Ok(0),
+    }
+}
+
+/// Where the audio ends, before an ID3v1 tag and an APEv2 tag in front of it
+fn tags_end(file: &mut BufReader<File>) -> Result<u64> {
+    let mut end = file.seek(SeekFrom::End(0))?;
+    let mut tail = [0; 128];
+    if end >= 128 {
+        file.seek(SeekFrom::Start(end - 128))?;
+        file.read_exact(&mut tail)?;
+        if tail.starts_with(b"TAG") {
+            end -= 128;
+        }
+    }
+    // https://wiki.hydrogenaud.io/index.php?title=APEv2_specification
+    let mut footer = [0; 32];
+    if end >= 32 {
+        file.seek(SeekFrom::Start(end - 32))?;
+        file.read_exact(&mut footer)?;
+        if footer.starts_with(b"APETAGEX") {
+            let size = u64::from(u32::from_le_bytes(footer[12..16].try_into()?));
+            let flags = u32::from_le_bytes(footer[20..24].try_into()?);
+            let header = if flags & (1 << 31) != 0 { 32 } else { 0 };
+            end = end.saturating_sub(size + header);
+        }
+    }
+    Ok(end)
+}
+
+/// The frames after the last metadata block, see https://xiph.org/flac/format.html
+fn flac(file: &mut BufReader<File>, hasher: &mut impl Hasher) -> Result<()> {
+    let mut pos = id3v2_start(file)?;
+    file.seek(SeekFrom::Start(pos))?;
+    let mut magic = [0; 4];
+    file.read_exact(&mut magic)?;
+    if &magic != b"fLaC" {
+        return Err(anyhow!("not a flac file"));
+    }
+    pos += 4;
+    loop {
+        let mut header = [0; 4];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut header)?;
+        pos += 4 + u64::from(u32::from_be_bytes([0, header[1], header[2], header[3]]));
+        if header[0] & 0x80 != 0 {
+            break;
+        }
+    }
+    let end = tags_end(file)?;
+    hash_range(file, pos, end, hasher)
+}
+
+/// Packets of the first stream after its identification, comment and setup headers
+fn ogg(file: &mut BufReader<File>, hasher: &mut impl Hasher) -> Result<()> {
+    let mut reader = PacketReader::new(file);
+    let first = reader.read_packet()?.ok_or(anyhow!("empty ogg file"))?;
+    let serial = first.stream_serial();
+    let headers = match &first.data {
+        x if x.starts_with(b"OpusHead") => 2,
+        _ => 3,
+    };
+    let mut seen = 1;
+    while let Some(packet) = reader.read_packet()? {
+        if packet.stream_serial() != serial {
+            continue;
+        }
+        seen += 1;
+        if seen > headers {
+            hasher.write(&packet.data);
+        }
+    }
+    Ok(())
+}
+
+/// The payloads of the top level `mdat` atoms, `moov` holds the tags and moves around
+fn mp4(file: &mut BufReader<File>, hasher: &mut impl Hasher) -> Result<()> {
+    let file_len = file.seek(SeekFrom::End(0))?;
+    let mut pos = 0;
+    while pos + 8 <= file_len {
+        file.seek(SeekFrom::Start(pos))?;
+        let mut header = [0; 8];
+        file.read_exact(&mut header)?;
+        let (header_len, len) = match u32::from_be_bytes(header[..4].try_into()?) {
+            0 => (8, file_len - pos),
+            1 => {
+                let mut large = [0; 8];
+                file.read_exact(&mut large)?;
+                (16, u64::from_be_bytes(large))
+            }
+            size => (8, u64::from(size)),
+        };
+        if len < header_len {
+            return Err(anyhow!("mp4 atom has a bad size"));
+        }
+        if &header[4..] == b"mdat" {
+            hash_range(file, pos + header_len, pos + len, hasher)?;
+        }
+        pos += len;
+    }
+    Ok(())
+}
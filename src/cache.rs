@@ -1,78 +1,596 @@
-use std::time::Duration;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Display,
+    io::{Read, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use anyhow::{anyhow, Context, Result};
 use directories::ProjectDirs;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::{AcoustId, FileHash};
+use crate::{
+    acoustid::{lookup_key, CachedLookup},
+    ratings::Verdict,
+    AcoustId, FileHash, FINGERPRINT_ALGORITHM,
+};
 
-#[derive(Debug, Default)]
+/// Version of the trees and records below, bumped whenever their layout changes
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Key of the schema version in the default tree
+const SCHEMA_KEY: &[u8] = b"schema-version";
+
+/// sled allows a single open handle per directory, every `Db::new` shares this one
+static SHARED: OnceLock<Option<Trees>> = OnceLock::new();
+
+#[derive(Debug, Default, Clone)]
 pub struct Db {
-    tree: Option<sled::Db>,
+    trees: Option<Trees>,
+}
+
+/// How every value is stored, records written with another schema are ignored
+#[derive(Debug, Serialize, Deserialize)]
+struct Record<T> {
+    version: u32,
+    value: T,
+}
+
+/// Chromaprint fingerprint of a song's audio
+#[derive(Debug, Serialize, Deserialize)]
+struct Fingerprint {
+    /// Chromaprint algorithm it was made with, fingerprints of different ones don't match
+    algorithm: u32,
+    /// How much of the song was fingerprinted, unknown for records older than the setting
+    #[serde(default)]
+    max_analysed: Option<Duration>,
+    fingerprint: String,
+}
+
+/// Hashes of a file as it was when hashed, stale once the size or mtime change
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct IndexedHash {
+    size: u64,
+    /// Since the unix epoch
+    modified: Duration,
+    /// Audio hash, the key of everything cached about the file
+    #[serde(default)]
+    hash: Option<String>,
+    /// Hash of all the file's bytes, tags included
+    #[serde(default)]
+    file_hash: Option<String>,
+}
+
+/// A sled tree holding records of one kind
+#[derive(Debug)]
+struct Typed<T> {
+    tree: sled::Tree,
+    record: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Typed<T> {
+    fn clone(&self) -> Self {
+        Typed {
+            tree: self.tree.clone(),
+            record: PhantomData,
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Typed<T> {
+    fn open(db: &sled::Db, name: &str) -> Result<Self> {
+        let tree = db
+            .open_tree(name)
+            .with_context(|| format!("opening cache tree {name}"))?;
+        Ok(Typed {
+            tree,
+            record: PhantomData,
+        })
+    }
+
+    fn decode(bytes: &[u8]) -> Option<T> {
+        let record: Record<T> = serde_json::from_slice(bytes).ok()?;
+        (record.version == SCHEMA_VERSION).then_some(record.value)
+    }
+
+    fn get(&self, key: impl AsRef<[u8]>) -> Option<T> {
+        Self::decode(&self.tree.get(key).ok()??)
+    }
+
+    /// Stores `value` under `key` and gives back the record it replaced
+    fn insert(&self, key: impl AsRef<[u8]>, value: T) -> Option<T> {
+        let record = Record {
+            version: SCHEMA_VERSION,
+            value,
+        };
+        let bytes = serde_json::to_vec(&record).ok()?;
+        Self::decode(&self.tree.insert(key.as_ref(), bytes).ok()??)
+    }
+
+    /// Every record that decodes, keys as text
+    fn entries(&self) -> BTreeMap<String, T> {
+        self.tree
+            .iter()
+            .filter_map(Result::ok)
+            .filter_map(|(key, value)| {
+                Some((
+                    String::from_utf8_lossy(&key).into_owned(),
+                    Self::decode(&value)?,
+                ))
+            })
+            .collect()
+    }
+
+    /// Removes the records whose key `keep` refuses, and the ones that don't decode, gives
+    /// back how many were removed. With `dry_run` they're only counted
+    fn retain(&self, keep: impl Fn(&[u8]) -> bool, dry_run: bool) -> Result<usize> {
+        let mut removed = 0;
+        for entry in self.tree.iter() {
+            let (key, value) = entry?;
+            if !keep(&key) || Self::decode(&value).is_none() {
+                if !dry_run {
+                    self.tree.remove(key)?;
+                }
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn import(&self, entries: BTreeMap<String, T>) -> usize {
+        let count = entries.len();
+        for (key, value) in entries {
+            self.insert(key, value);
+        }
+        count
+    }
+}
+
+/// One tree per record kind
+#[derive(Debug, Clone)]
+struct Trees {
+    db: sled::Db,
+    durations: Typed<Duration>,
+    fingerprints: Typed<Fingerprint>,
+    lookups: Typed<CachedLookup>,
+    file_hashes: Typed<IndexedHash>,
+    /// Listening verdicts, they aren't cache and survive pruning and clearing
+    verdicts: Typed<Vec<Verdict>>,
+}
+
+impl Trees {
+    fn open(db: &sled::Db) -> Result<Self> {
+        let trees = Trees {
+            db: db.clone(),
+            durations: Typed::open(db, "durations")?,
+            fingerprints: Typed::open(db, "fingerprints")?,
+            lookups: Typed::open(db, "lookups")?,
+            file_hashes: Typed::open(db, "file_hashes")?,
+            verdicts: Typed::open(db, "verdicts")?,
+        };
+        trees.migrate(db)?;
+        Ok(trees)
+    }
+
+    /// Upgrades databases written by older versions of risto, refuses newer ones
+    fn migrate(&self, db: &sled::Db) -> Result<()> {
+        let version = match db.get(SCHEMA_KEY)? {
+            Some(bytes) => u32::from_be_bytes(
+                bytes
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| anyhow!("cache schema version is corrupt"))?,
+            ),
+            None => 0,
+        };
+        if version > SCHEMA_VERSION {
+            return Err(anyhow!(
+                "cache has schema version {version}, this risto only knows up to {SCHEMA_VERSION}"
+            ));
+        }
+        if version == 0 {
+            self.upgrade_unversioned(db)?;
+        }
+        db.insert(SCHEMA_KEY, &SCHEMA_VERSION.to_be_bytes())?;
+        db.flush()?;
+        Ok(())
+    }
+
+    /// Moves the `<path>-hash` entries of the default tree into their own tree. The
+    /// `<hash>-duration`, `<hash>-acoustid` and `<key>-lookup` entries are dropped, they're
+    /// keyed by whole-file hashes and whole-song fingerprints that are never asked for again
+    fn upgrade_unversioned(&self, db: &sled::Db) -> Result<()> {
+        for entry in db.iter() {
+            let (key, value) = entry?;
+            if key.as_ref() == SCHEMA_KEY {
+                continue;
+            }
+            let legacy = String::from_utf8_lossy(&key).into_owned();
+            if let Some(path) = legacy.strip_suffix("-hash") {
+                // these hashed the whole file, they aren't audio hashes
+                if let Ok(legacy) = serde_json::from_slice::<LegacyHash>(&value) {
+                    let indexed = IndexedHash {
+                        size: legacy.size,
+                        modified: legacy.modified,
+                        hash: None,
+                        file_hash: Some(legacy.hash),
+                    };
+                    self.file_hashes.insert(path, indexed);
+                }
+            }
+            db.remove(&key)?;
+        }
+        Ok(())
+    }
+}
+
+/// Index entry of unversioned databases
+#[derive(Deserialize)]
+struct LegacyHash {
+    size: u64,
+    modified: Duration,
+    hash: String,
+}
+
+/// Records per tree
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counts {
+    /// Paths in the file hash index
+    pub files: usize,
+    pub durations: usize,
+    pub fingerprints: usize,
+    pub lookups: usize,
+    /// Songs with verdicts
+    pub verdicts: usize,
+}
+
+impl Display for Counts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} files, {} durations, {} fingerprints, {} lookups, {} verdicts",
+            self.files, self.durations, self.fingerprints, self.lookups, self.verdicts
+        )
+    }
 }
 
-macro_rules! key_duration {
-    ($e:expr) => {{
-        format!("{}-duration", $e)
-    }};
+/// What's cached for one song's audio
+#[derive(Debug, Clone, Default)]
+pub struct Cached {
+    pub duration: Option<Duration>,
+    pub fingerprint: Option<AcoustId>,
+    /// When the AcoustID response was fetched and how many tracks it had
+    pub lookup: Option<(SystemTime, usize)>,
 }
 
-macro_rules! key_acoustid {
-    ($e:expr) => {{
-        format!("{}-acoustid", $e)
-    }};
+/// A file of the hash index and what's cached for its audio
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub hash: FileHash,
+    pub cached: Cached,
+}
+
+/// Everything in the database as `risto cache export` writes it
+#[derive(Debug, Serialize, Deserialize)]
+struct Export {
+    schema: u32,
+    durations: BTreeMap<String, Duration>,
+    fingerprints: BTreeMap<String, Fingerprint>,
+    lookups: BTreeMap<String, CachedLookup>,
+    file_hashes: BTreeMap<String, IndexedHash>,
+    #[serde(default)]
+    verdicts: BTreeMap<String, Vec<Verdict>>,
+}
+
+/// Where `Db::new` keeps the database
+pub fn location() -> Option<PathBuf> {
+    Some(
+        ProjectDirs::from("", "music-rater", "risto")?
+            .data_dir()
+            .to_path_buf(),
+    )
+}
+
+fn path_key(path: &Path) -> &[u8] {
+    path.as_os_str().as_encoded_bytes()
 }
 
 impl Db {
+    /// Database in the user's data dir, without it nothing gets cached
     pub fn new() -> Self {
-        match ProjectDirs::from("", "music-rater", "risto") {
-            Some(appdir) => Self {
-                tree: sled::open(appdir.data_dir()).ok(),
-            },
-            None => Self { tree: None },
+        let trees = SHARED.get_or_init(|| {
+            let db = sled::open(location()?).ok()?;
+            Trees::open(&db)
+                .inspect_err(|err| eprintln!("Not caching, {err:#}"))
+                .ok()
+        });
+        Self {
+            trees: trees.clone(),
         }
     }
 
+    /// Database at a custom location, e.g. for tests
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let db = sled::open(path).with_context(|| format!("opening cache {}", path.display()))?;
+        Self::from_sled(&db).with_context(|| format!("opening cache {}", path.display()))
+    }
+
+    /// Database on top of an open sled database, upgraded to the current schema
+    pub fn from_sled(db: &sled::Db) -> Result<Self> {
+        Ok(Self {
+            trees: Some(Trees::open(db)?),
+        })
+    }
+
+    /// Audio hash of the file at `path` if it was hashed with this `size` and `modified` time
+    pub fn get_audio_hash(&self, path: &Path, size: u64, modified: SystemTime) -> Option<FileHash> {
+        self.indexed(path, size, modified)?.hash.map(FileHash)
+    }
+
+    pub fn insert_audio_hash(
+        &self,
+        path: &Path,
+        size: u64,
+        modified: SystemTime,
+        hash: &FileHash,
+    ) -> Option<()> {
+        self.index(path, size, modified, |indexed| {
+            indexed.hash = Some(hash.0.clone())
+        })
+    }
+
+    /// Whole-file hash of the file at `path` if it was hashed with this `size` and `modified`
+    /// time
+    pub fn get_file_hash(&self, path: &Path, size: u64, modified: SystemTime) -> Option<FileHash> {
+        self.indexed(path, size, modified)?.file_hash.map(FileHash)
+    }
+
+    pub fn insert_file_hash(
+        &self,
+        path: &Path,
+        size: u64,
+        modified: SystemTime,
+        hash: &FileHash,
+    ) -> Option<()> {
+        self.index(path, size, modified, |indexed| {
+            indexed.file_hash = Some(hash.0.clone())
+        })
+    }
+
+    /// The index entry of `path` unless the file changed since
+    fn indexed(&self, path: &Path, size: u64, modified: SystemTime) -> Option<IndexedHash> {
+        let trees = self.trees.as_ref()?;
+        let res = trees.file_hashes.get(path_key(path))?;
+        let modified = modified.duration_since(UNIX_EPOCH).ok()?;
+        (res.size == size && res.modified == modified).then_some(res)
+    }
+
+    /// Updates the index entry of `path`, starting over if the file changed since
+    fn index(
+        &self,
+        path: &Path,
+        size: u64,
+        modified: SystemTime,
+        update: impl FnOnce(&mut IndexedHash),
+    ) -> Option<()> {
+        let trees = self.trees.as_ref()?;
+        let since_epoch = modified.duration_since(UNIX_EPOCH).ok()?;
+        let mut indexed = self
+            .indexed(path, size, modified)
+            .unwrap_or_else(|| IndexedHash {
+                size,
+                modified: since_epoch,
+                ..IndexedHash::default()
+            });
+        update(&mut indexed);
+        trees.file_hashes.insert(path_key(path), indexed);
+        Some(())
+    }
+
     pub fn get_duration(&self, key: &FileHash) -> Option<Duration> {
-        let Some(tree) = &self.tree else {
-            return None;
-        };
-        let key = key_duration!(key);
-        let res = tree.get(&key).ok()??;
-        let res = Duration::from_secs(u64::from_be_bytes(res.as_ref().try_into().ok()?));
-        // eprintln!("cache-hit for key={key}, {res:?}");
-        Some(res)
+        self.trees.as_ref()?.durations.get(key)
     }
 
     pub fn insert_duration(&self, key: &FileHash, duration: Duration) -> Option<Duration> {
-        let Some(tree) = &self.tree else {
-            return None;
+        self.trees.as_ref()?.durations.insert(key, duration)
+    }
+
+    /// Fingerprint of the first `max_analysed` of the audio, unless it was made of another length
+    /// or with another chromaprint algorithm
+    pub fn get_acoustid(&self, key: &FileHash, max_analysed: Duration) -> Option<AcoustId> {
+        let res = self.trees.as_ref()?.fingerprints.get(key)?;
+        (res.algorithm == FINGERPRINT_ALGORITHM && res.max_analysed == Some(max_analysed))
+            .then_some(AcoustId(res.fingerprint))
+    }
+
+    pub fn insert_acoustid(
+        &self,
+        key: &FileHash,
+        max_analysed: Duration,
+        id: AcoustId,
+    ) -> Option<AcoustId> {
+        let record = Fingerprint {
+            algorithm: FINGERPRINT_ALGORITHM,
+            max_analysed: Some(max_analysed),
+            fingerprint: id.0,
         };
-        let key = key_duration!(key);
-        let old = tree.insert(key, &duration.as_secs().to_be_bytes()).ok()??;
-        Some(Duration::from_secs(u64::from_be_bytes(
-            old.as_ref().try_into().ok()?,
-        )))
+        let old = self.trees.as_ref()?.fingerprints.insert(key, record)?;
+        Some(AcoustId(old.fingerprint))
     }
 
-    pub fn get_acoustid(&self, key: &FileHash) -> Option<AcoustId> {
-        let Some(tree) = &self.tree else {
-            return None;
+    /// Fingerprint of the audio whatever length it was made of
+    fn fingerprint(&self, key: &FileHash) -> Option<AcoustId> {
+        let res = self.trees.as_ref()?.fingerprints.get(key)?;
+        (res.algorithm == FINGERPRINT_ALGORITHM).then_some(AcoustId(res.fingerprint))
+    }
+
+    pub(crate) fn get_lookup(&self, key: &str) -> Option<CachedLookup> {
+        self.trees.as_ref()?.lookups.get(key)
+    }
+
+    pub(crate) fn insert_lookup(&self, key: &str, lookup: CachedLookup) -> Option<()> {
+        self.trees.as_ref()?.lookups.insert(key, lookup);
+        Some(())
+    }
+
+    fn trees(&self) -> Result<&Trees> {
+        self.trees
+            .as_ref()
+            .ok_or(anyhow!("the cache isn't available"))
+    }
+
+    /// Every verdict on the audio, oldest first
+    pub fn verdicts(&self, hash: &FileHash) -> Result<Vec<Verdict>> {
+        Ok(self.trees()?.verdicts.get(hash).unwrap_or_default())
+    }
+
+    /// Adds to the verdicts on the audio and flushes, a crashed run keeps them
+    pub fn add_verdict(&self, hash: &FileHash, verdict: Verdict) -> Result<()> {
+        let trees = self.trees()?;
+        let mut verdicts = trees.verdicts.get(hash).unwrap_or_default();
+        verdicts.push(verdict);
+        trees.verdicts.insert(hash, verdicts);
+        trees.db.flush()?;
+        Ok(())
+    }
+
+    /// Records per tree and bytes taken on disk
+    pub fn stats(&self) -> Result<(Counts, u64)> {
+        let trees = self.trees()?;
+        let counts = Counts {
+            files: trees.file_hashes.tree.len(),
+            durations: trees.durations.tree.len(),
+            fingerprints: trees.fingerprints.tree.len(),
+            lookups: trees.lookups.tree.len(),
+            verdicts: trees.verdicts.tree.len(),
         };
-        let key = key_acoustid!(key);
-        let res = tree.get(&key).ok()??;
-        let res = std::str::from_utf8(res.as_ref()).unwrap().to_string();
-        // let short_id: String = res.chars().take(10).collect();
-        // eprintln!("cache-hit for key={key}, {short_id}...");
-        Some(AcoustId(res))
+        Ok((counts, trees.db.size_on_disk()?))
     }
 
-    pub fn insert_acoustid(&self, key: &FileHash, id: AcoustId) -> Option<AcoustId> {
-        let Some(tree) = &self.tree else {
-            return None;
+    /// Duration, fingerprint and AcoustID response cached for `hash`
+    pub fn cached(&self, hash: &FileHash) -> Cached {
+        let duration = self.get_duration(hash);
+        let fingerprint = self.fingerprint(hash);
+        let lookup = match (&fingerprint, duration) {
+            (Some(fingerprint), Some(duration)) => self
+                .get_lookup(&lookup_key(&fingerprint.0, duration))
+                .map(|x| (x.fetched_at(), x.tracks())),
+            _ => None,
+        };
+        Cached {
+            duration,
+            fingerprint,
+            lookup,
+        }
+    }
+
+    /// Every audio-hashed file of the index, sorted by path
+    pub fn entries(&self) -> Result<Vec<Entry>> {
+        let trees = self.trees()?;
+        Ok(trees
+            .file_hashes
+            .entries()
+            .into_iter()
+            .filter_map(|(path, indexed)| {
+                let hash = FileHash(indexed.hash?);
+                Some(Entry {
+                    path: PathBuf::from(path),
+                    cached: self.cached(&hash),
+                    hash,
+                })
+            })
+            .collect())
+    }
+
+    /// Drops everything but the records of the `keep` files, gives back how many records were
+    /// dropped from each tree. With `dry_run` nothing is dropped, only counted
+    pub fn prune(&self, keep: &[(PathBuf, FileHash)], dry_run: bool) -> Result<Counts> {
+        let trees = self.trees()?;
+        let paths: HashSet<&[u8]> = keep.iter().map(|(path, _)| path_key(path)).collect();
+        let hashes: HashSet<&[u8]> = keep.iter().map(|(_, hash)| hash.as_ref()).collect();
+        let lookups: HashSet<Vec<u8>> = keep
+            .iter()
+            .filter_map(|(_, hash)| {
+                let fingerprint = self.fingerprint(hash)?;
+                Some(lookup_key(&fingerprint.0, self.get_duration(hash)?).into_bytes())
+            })
+            .collect();
+        Ok(Counts {
+            files: trees
+                .file_hashes
+                .retain(|key| paths.contains(key), dry_run)?,
+            durations: trees
+                .durations
+                .retain(|key| hashes.contains(key), dry_run)?,
+            fingerprints: trees
+                .fingerprints
+                .retain(|key| hashes.contains(key), dry_run)?,
+            lookups: trees.lookups.retain(|key| lookups.contains(key), dry_run)?,
+            verdicts: 0,
+        })
+    }
+
+    /// Drops every cached record, gives back how many there were. Verdicts are kept
+    pub fn clear(&self) -> Result<Counts> {
+        let (counts, _) = self.stats()?;
+        let trees = self.trees()?;
+        trees.file_hashes.tree.clear()?;
+        trees.durations.tree.clear()?;
+        trees.fingerprints.tree.clear()?;
+        trees.lookups.tree.clear()?;
+        trees.db.flush()?;
+        Ok(Counts {
+            verdicts: 0,
+            ..counts
+        })
+    }
+
+    /// Writes every record as JSON
+    pub fn export(&self, writer: impl Write) -> Result<Counts> {
+        let trees = self.trees()?;
+        let export = Export {
+            schema: SCHEMA_VERSION,
+            durations: trees.durations.entries(),
+            fingerprints: trees.fingerprints.entries(),
+            lookups: trees.lookups.entries(),
+            file_hashes: trees.file_hashes.entries(),
+            verdicts: trees.verdicts.entries(),
+        };
+        let counts = Counts {
+            files: export.file_hashes.len(),
+            durations: export.durations.len(),
+            fingerprints: export.fingerprints.len(),
+            lookups: export.lookups.len(),
+            verdicts: export.verdicts.len(),
+        };
+        serde_json::to_writer_pretty(writer, &export).context("writing the export failed")?;
+        Ok(counts)
+    }
+
+    /// Adds the records of an export, replacing the ones with the same keys
+    pub fn import(&self, reader: impl Read) -> Result<Counts> {
+        let trees = self.trees()?;
+        let export: Export =
+            serde_json::from_reader(reader).context("reading the export failed")?;
+        if export.schema != SCHEMA_VERSION {
+            return Err(anyhow!(
+                "the export has schema version {}, this risto reads {SCHEMA_VERSION}",
+                export.schema
+            ));
+        }
+        let counts = Counts {
+            files: trees.file_hashes.import(export.file_hashes),
+            durations: trees.durations.import(export.durations),
+            fingerprints: trees.fingerprints.import(export.fingerprints),
+            lookups: trees.lookups.import(export.lookups),
+            verdicts: trees.verdicts.import(export.verdicts),
         };
-        let key = key_acoustid!(key);
-        let old = tree.insert(key, id.0.as_str()).ok()??;
-        Some(AcoustId(
-            std::str::from_utf8(old.as_ref()).unwrap().to_string(),
-        ))
+        trees.db.flush()?;
+        Ok(counts)
     }
 }
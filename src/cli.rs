@@ -1,8 +1,12 @@
 use std::io;
 use std::path::PathBuf;
 
+pub mod cache;
 pub mod classify_music;
+pub mod find_dupes;
+pub mod match_library;
 pub mod rename_music_files;
+pub mod tag_from_filename;
 
 pub fn read_files_from_stdin() -> Vec<PathBuf> {
     let mut lines = vec![];
@@ -11,3 +15,12 @@ pub fn read_files_from_stdin() -> Vec<PathBuf> {
     }
     lines
 }
+
+/// Markdown for a tag value going from `old` to `new`
+pub fn change(old: Option<&str>, new: &str) -> String {
+    match old {
+        Some(old) if old == new => format!("`{new}`"),
+        Some(old) => format!("`{old}` → `{new}`"),
+        None => format!("*none* → `{new}`"),
+    }
+}
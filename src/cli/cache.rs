@@ -0,0 +1,201 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context, Error, Result};
+use rayon::prelude::*;
+use risto::{
+    cache::{location, Cached, Counts, Db},
+    is_audio, mp3_files, Song,
+};
+use termimad::{MadSkin, Question};
+
+fn short(fingerprint: &str) -> String {
+    fingerprint.chars().take(10).collect()
+}
+
+fn ago(time: SystemTime) -> String {
+    let age = SystemTime::now()
+        .duration_since(time)
+        .unwrap_or_default()
+        .as_secs();
+    format!("{} days ago", age / (24 * 60 * 60))
+}
+
+fn seconds(duration: Option<Duration>) -> String {
+    duration
+        .map(|x| format!("{:.1}s", x.as_secs_f64()))
+        .unwrap_or("-".to_owned())
+}
+
+/// Markdown table rows of what's cached for one file
+fn cached_rows(cached: &Cached) -> String {
+    let fingerprint = cached
+        .fingerprint
+        .as_ref()
+        .map(|x| format!("`{}…`", short(&x.to_string())))
+        .unwrap_or("-".to_owned());
+    let lookup = cached
+        .lookup
+        .map(|(fetched, tracks)| format!("{tracks} tracks, fetched {}", ago(fetched)))
+        .unwrap_or("-".to_owned());
+    format!(
+        "|duration|{}|\n|fingerprint|{fingerprint}|\n|AcoustID lookup|{lookup}|\n",
+        seconds(cached.duration)
+    )
+}
+
+pub fn stats(skin: &MadSkin, db: &Db, dir: Option<&Path>) -> Result<()> {
+    let (counts, size) = db.stats()?;
+    let dir = dir.map(Path::to_path_buf).or_else(location);
+    let dir = dir.map_or("*nowhere*".to_owned(), |x| format!("`{}`", x.display()));
+    let mib = size as f64 / (1024.0 * 1024.0);
+    let text = format!(
+        "|location|{dir}|\n|-|-|\n|size on disk|{mib:.1} MiB|\n\
+         |files|{}|\n|durations|{}|\n|fingerprints|{}|\n|lookups|{}|\n|verdicts|{}|\n",
+        counts.files, counts.durations, counts.fingerprints, counts.lookups, counts.verdicts
+    );
+    skin.print_text(&text);
+    Ok(())
+}
+
+/// One line per indexed file, tab separated: path, duration, fingerprint, lookup
+pub fn ls(db: &Db) -> Result<()> {
+    for entry in db.entries()? {
+        let cached = entry.cached;
+        println!(
+            "{}\t{}\t{}\t{}",
+            entry.path.display(),
+            seconds(cached.duration),
+            cached
+                .fingerprint
+                .map(|x| short(&x.to_string()))
+                .unwrap_or("-".to_owned()),
+            cached
+                .lookup
+                .map(|(_, tracks)| format!("{tracks} tracks"))
+                .unwrap_or("-".to_owned()),
+        );
+    }
+    Ok(())
+}
+
+pub fn get(skin: &MadSkin, db: &Db, files: &[PathBuf]) -> Result<Vec<Error>> {
+    let mut errors = vec![];
+    for file in files.iter().filter(|x| is_audio(x)) {
+        // hashed without the cache, looking a file up doesn't add it to the index
+        let hashes = Song::new(file)
+            .map(|song| song.with_cache(Db::default()))
+            .and_then(|song| Ok((song.hash()?, song.file_hash()?)))
+            .with_context(|| format!("❌ hashing failed {}", file.display()));
+        let (hash, file_hash) = match hashes {
+            Ok(hashes) => hashes,
+            Err(err) => {
+                errors.push(err);
+                continue;
+            }
+        };
+        skin.print_text(&format!(
+            "\n## `{}`\n|audio hash|`{hash}`|\n|-|-|\n|file hash|`{file_hash}`|\n{}",
+            file.display(),
+            cached_rows(&db.cached(&hash))
+        ));
+    }
+    Ok(errors)
+}
+
+/// Hashes every song under `roots` and drops the records of any other file, asks first
+/// unless `yes`. A root that is missing or holds no songs, e.g. an unmounted drive, is refused
+pub fn prune(
+    skin: &MadSkin,
+    db: &Db,
+    roots: &[PathBuf],
+    yes: bool,
+) -> Result<(Option<Counts>, Vec<Error>)> {
+    let mut files = vec![];
+    for root in roots {
+        if !root.is_dir() {
+            return Err(anyhow!(
+                "{} is not a folder, is it mounted?",
+                root.display()
+            ));
+        }
+        let songs: Vec<_> = mp3_files(root)
+            .into_iter()
+            .filter(|x| is_audio(x))
+            .collect();
+        if songs.is_empty() {
+            return Err(anyhow!("no songs in {}, is it mounted?", root.display()));
+        }
+        files.extend(songs);
+    }
+    let (hashed, errors): (Vec<_>, Vec<_>) = files
+        .par_iter()
+        .map(|file| {
+            let song = Song::new(file)?.with_cache(db.clone());
+            let hash = song.hash()?;
+            Ok((song.path, hash))
+        })
+        .partition(Result::is_ok);
+    let keep: Vec<_> = hashed.into_iter().filter_map(Result::ok).collect();
+    let errors = errors
+        .into_iter()
+        .filter_map(Result::err)
+        .map(|err: Error| err.context("❌ hashing failed, its records are dropped"))
+        .collect();
+
+    let dropping = db.prune(&keep, true)?;
+    if dropping == Counts::default() {
+        return Ok((Some(dropping), errors));
+    }
+    eprintln!("\n# {} songs found", keep.len());
+    if !yes {
+        let mut question = Question::new(format!("Drop {dropping}?"));
+        question.add_answer('y', "**y**es");
+        question.add_answer('n', "**n**o");
+        question.set_default("n");
+        if question.ask(skin)? != "y" {
+            return Ok((None, errors));
+        }
+    }
+    Ok((Some(db.prune(&keep, false)?), errors))
+}
+
+/// Drops everything, asks first unless `yes`
+pub fn clear(skin: &MadSkin, db: &Db, yes: bool) -> Result<Option<Counts>> {
+    if !yes {
+        let (counts, _) = db.stats()?;
+        let counts = Counts {
+            verdicts: 0,
+            ..counts
+        };
+        let mut question = Question::new(format!("Clear {counts}? Verdicts are kept"));
+        question.add_answer('y', "**y**es");
+        question.add_answer('n', "**n**o");
+        question.set_default("n");
+        if question.ask(skin)? != "y" {
+            return Ok(None);
+        }
+    }
+    db.clear().map(Some)
+}
+
+/// Writes every record as JSON into `file` or else stdout
+pub fn export(db: &Db, file: Option<&Path>) -> Result<Counts> {
+    match file {
+        Some(file) => {
+            let writer = File::create(file)
+                .with_context(|| format!("creating {} failed", file.display()))?;
+            db.export(BufWriter::new(writer))
+        }
+        None => db.export(io::stdout().lock()),
+    }
+}
+
+pub fn import(db: &Db, file: &Path) -> Result<Counts> {
+    let reader = File::open(file).with_context(|| format!("opening {} failed", file.display()))?;
+    db.import(BufReader::new(reader))
+}
@@ -1,62 +1,91 @@
-use risto::mp3_files;
+use risto::{
+    cache::Db,
+    is_audio, mp3_files,
+    ratings::{Like, Verdict},
+    Song,
+};
 use rodio::{Decoder, OutputStream, Sink};
-use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashSet,
     fs::File,
-    io::{BufReader, Write},
+    io::{self, BufReader, Write},
     path::{Path, PathBuf},
     sync::mpsc::channel,
     thread,
     time::Duration,
 };
-use termimad::{ask, mad_print_inline, MadSkin};
+use termimad::{mad_print_inline, MadSkin, Question};
 
 use anyhow::{Context, Result};
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Songs(Vec<Song>);
+/// Where older versions kept verdicts, `risto import-likes` moves them into the database
+pub const LEGACY_LIKES: &str = "likes.json";
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Song {
-    path: String,
-    like: Like,
+pub struct Options {
+    /// Ask for labels of every song that's kept
+    pub labels: bool,
+    /// Write stars and labels into the songs' tags
+    pub write_tags: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-enum Like {
-    Yes,
-    No,
-    DontKnow,
-    ExtensionNotSupported,
+fn did_you_like_it(skin: &MadSkin) -> (Like, Option<u8>) {
+    let mut question = Question::new("Do you like it?");
+    question.add_answer('y', "**y**es");
+    for stars in 1..=5 {
+        question.add_answer(stars, format!("{}, keep it rated", "★".repeat(stars)));
+    }
+    question.add_answer('n', "**n**o, please   move to trash");
+    question.add_answer('r', "**r**epeat");
+    question.set_default('y');
+    match question.ask(skin).as_deref() {
+        Ok("y") => (Like::Yes, None),
+        Ok("n") => (Like::No, None),
+        Ok("r") | Err(_) => (Like::DontKnow, None),
+        Ok(stars) => (Like::Yes, stars.parse().ok()),
+    }
 }
 
-fn did_you_like_it(skin: &MadSkin) -> Like {
-    ask!(skin, "Do you like it?", ('y') {
-        ('y', "**y**es") => { Like::Yes }
-        ('n', "**n**o, please   move to trash") => { Like::No }
-        ('r', "**r**epeat") => { Like::DontKnow }
-    })
+/// Comma separated labels typed in, none when just hitting enter
+fn which_labels(skin: &MadSkin) -> Vec<String> {
+    mad_print_inline!(skin, "*labels*, comma separated: ");
+    let _ = io::stdout().flush();
+    let mut line = String::new();
+    if io::stdin().read_line(&mut line).is_err() {
+        return vec![];
+    }
+    line.split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(str::to_owned)
+        .collect()
 }
 
-fn load_likes(path: &Path) -> Result<Vec<Song>> {
-    if !path.exists() {
-        let mut f = File::create(path).with_context(|| format!("failed to create {path:?}"))?;
-        let _ = f.write(b"[]");
-    };
-    let file = File::open(path).with_context(|| format!("couldn't load {path:?}"))?;
-    let reader = BufReader::new(file);
-    let files = serde_json::from_reader(reader)?;
-    Ok(files)
+fn play(skin: &MadSkin, path: &Path, options: &Options) -> Result<Verdict> {
+    let skin = skin.clone();
+    let ask_labels = options.labels;
+    let answer = play_while(path, move || {
+        let (like, stars) = did_you_like_it(&skin);
+        let labels = match like {
+            Like::Yes if ask_labels => which_labels(&skin),
+            _ => vec![],
+        };
+        (like, stars, labels)
+    })?;
+    Ok(match answer {
+        Some((like, stars, labels)) => Verdict::new(like, path).with_rating(stars, labels),
+        None => Verdict::new(Like::ExtensionNotSupported, path),
+    })
 }
 
-fn play(skin: &MadSkin, path: &Path) -> Result<Like> {
+/// Plays `path` until the song ends or `ask` answers, `None` when the format isn't supported
+pub fn play_while<T: Send + 'static>(
+    path: &Path,
+    ask: impl FnOnce() -> T + Send + 'static,
+) -> Result<Option<T>> {
     // Create an output stream
     let (_stream, stream_handle) =
         OutputStream::try_default().with_context(|| "output stream".to_owned())?;
     let sink = Sink::try_new(&stream_handle).with_context(|| "creating sink".to_owned())?;
 
-    let (tx_like, rx_like) = channel();
     let (tx_stop_song, rx_stop_song) = channel();
 
     let supported_extensions = ["mp3", "flac", "ogg", "wav", "mp4", "acc"];
@@ -66,7 +95,7 @@ fn play(skin: &MadSkin, path: &Path) -> Result<Like> {
         .to_str()
         .unwrap_or("unkown");
     if !supported_extensions.contains(&ext) {
-        return Ok(Like::ExtensionNotSupported);
+        return Ok(None);
     }
 
     let file = File::open(path).with_context(|| format!("couldn't open file {path:?}"))?;
@@ -96,50 +125,59 @@ fn play(skin: &MadSkin, path: &Path) -> Result<Like> {
         sink.stop();
     });
 
-    let skin = skin.clone();
     let th_ipnut_reader = thread::spawn(move || {
-        let like = did_you_like_it(&skin);
+        let answer = ask();
         // don't care if send fails
-        let _ = tx_like.send(like);
         let _ = tx_stop_song.send(true);
+        answer
     });
 
-    let like = match rx_like.recv() {
-        Ok(x) => x,
-        Err(_) => Like::DontKnow,
-    };
-
-    th_ipnut_reader.join().expect("player thread panicked!");
+    let answer = th_ipnut_reader.join().expect("input thread panicked!");
     th_player.join().expect("player thread panicked!");
 
-    Ok(like)
+    Ok(Some(answer))
 }
 
-pub fn keep_asking(skin: &MadSkin, music_dir: Option<&PathBuf>) -> Result<()> {
+pub fn keep_asking(skin: &MadSkin, music_dir: Option<&PathBuf>, options: &Options) -> Result<()> {
     let pwd = Path::new(".").to_path_buf();
     let music_dir: &PathBuf = music_dir.unwrap_or(&pwd);
-    let likes_path = Path::new("likes.json");
-    let mut songs = load_likes(likes_path)?;
-    let already_listened_longs: HashSet<String> = songs.iter().map(|x| x.path.clone()).collect();
-    for file in mp3_files(music_dir).iter().filter(|x| {
-        let keep = !already_listened_longs.contains(x.to_str().unwrap_or(""));
-        if !keep {
-            mad_print_inline!(skin, "*skipped* $0\n", x.display());
+    if Path::new(LEGACY_LIKES).exists() {
+        mad_print_inline!(
+            skin,
+            "*found* $0, import it with `risto import-likes`\n",
+            LEGACY_LIKES
+        );
+    }
+    let db = Db::new();
+    for file in mp3_files(music_dir).into_iter().filter(|x| is_audio(x)) {
+        let hashed = Song::new(&file)
+            .map(|song| song.with_cache(db.clone()))
+            .and_then(|song| Ok((song.hash()?, song)))
+            .with_context(|| format!("❌ hashing failed {}", file.display()));
+        // one unreadable file doesn't end the session
+        let (hash, song) = match hashed {
+            Ok(hashed) => hashed,
+            Err(err) => {
+                eprintln!("{err:#}");
+                continue;
+            }
+        };
+        if !db.verdicts(&hash)?.is_empty() {
+            mad_print_inline!(skin, "*skipped* $0\n", file.display());
+            continue;
         }
-        keep
-    }) {
         mad_print_inline!(skin, "**playing** $0\n", file.display());
-        let mut like;
+        let mut verdict;
         loop {
-            like = play(skin, file)?;
-            match like {
+            verdict = play(skin, &song.path, options)?;
+            match verdict.like {
                 Like::Yes => {
                     mad_print_inline!(skin, "*liked*  $0\n", file.display());
                     break;
                 }
                 Like::No => {
                     mad_print_inline!(skin, "*trash*  $0\n", file.display());
-                    trash::delete(file)?;
+                    trash::delete(&file)?;
                     break;
                 }
                 Like::DontKnow => {
@@ -152,17 +190,13 @@ pub fn keep_asking(skin: &MadSkin, music_dir: Option<&PathBuf>) -> Result<()> {
                 }
             }
         }
-        songs.push(Song {
-            path: file
-                .to_str()
-                .unwrap_or_else(|| panic!("couldn't convert {file:?} to string"))
-                .to_owned(),
-            like,
-        });
-        serde_json::to_writer_pretty(
-            File::create(likes_path).unwrap_or_else(|_| panic!("couldn't open {likes_path:?}")),
-            &songs,
-        )?;
+        let rated = verdict.stars.is_some() || !verdict.labels.is_empty();
+        if options.write_tags && rated && verdict.like == Like::Yes {
+            if let Err(err) = verdict.write_tag(&song.path) {
+                mad_print_inline!(skin, "*not tagged*  $0\n", format!("{err:#}"));
+            }
+        }
+        db.add_verdict(&hash, verdict)?;
     }
     Ok(())
 }
@@ -0,0 +1,185 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Error, Result};
+use rayon::prelude::*;
+use risto::dupes::{self, Audio, DupeFile, Keep, Log, Resolution};
+use termimad::{mad_print_inline, MadSkin, Question};
+
+use super::{classify_music::play_while, rename_music_files::fingerprint};
+
+pub struct Options {
+    /// How much of every song is fingerprinted
+    pub max_analysed: Duration,
+    pub dupes: dupes::Options,
+}
+
+/// Fingerprint of `file` decoded into its raw items
+pub fn audio(file: &Path, max_analysed: Duration) -> Result<Audio> {
+    let fingerprinted = fingerprint(file, max_analysed)?;
+    let raw = fingerprinted
+        .acoustid
+        .raw()
+        .with_context(|| format!("❌ fingerprint decoding failed {}", file.display()))?;
+    Ok(Audio {
+        path: fingerprinted.file,
+        duration: fingerprinted.duration,
+        fingerprint: raw,
+    })
+}
+
+/// Groups of copies of the same song, highest bitrate first
+pub fn find_dupes(files: &[PathBuf], options: &Options) -> (Vec<Vec<DupeFile>>, Vec<Error>) {
+    let (songs, errors): (Vec<_>, Vec<_>) = files
+        .par_iter()
+        .map(|file| audio(file, options.max_analysed))
+        .partition(Result::is_ok);
+    let songs: Vec<_> = songs.into_iter().map(Result::unwrap).collect();
+    let mut errors: Vec<_> = errors.into_iter().filter_map(Result::err).collect();
+
+    let mut groups = vec![];
+    for group in dupes::groups(&songs, &options.dupes) {
+        let mut copies = vec![];
+        for i in group {
+            let song = &songs[i];
+            match DupeFile::new(song.clone()) {
+                Ok(copy) => copies.push(copy),
+                Err(err) => errors.push(
+                    err.context(format!("❌ reading bitrate failed {}", song.path.display())),
+                ),
+            }
+        }
+        copies.sort_by(|a, b| b.bitrate.total_cmp(&a.bitrate));
+        groups.push(copies);
+    }
+    (groups, errors)
+}
+
+/// Markdown table rows of `copies`, the first column left for the caller
+pub fn copy_rows(copies: &[DupeFile], first: impl Fn(usize) -> String) -> String {
+    let mut rows = String::new();
+    for (i, copy) in copies.iter().enumerate() {
+        rows += &format!(
+            "|{}|{}|{}|{:.0} kbps|{:.1} MB|{:.1}s|\n",
+            first(i),
+            copy.path().display(),
+            copy.format,
+            copy.bitrate / 1000.0,
+            copy.size as f64 / 1_000_000.0,
+            copy.audio.duration.as_secs_f64()
+        );
+    }
+    rows
+}
+
+fn print_group(skin: &MadSkin, n: usize, copies: &[DupeFile]) {
+    skin.print_text(&format!(
+        "\n## Duplicates {}\n||file|format|bitrate|size|length|\n|-|-|-|-|-|-|\n{}",
+        n + 1,
+        copy_rows(copies, |i| (i + 1).to_string())
+    ));
+}
+
+pub fn print_groups(skin: &MadSkin, groups: &[Vec<DupeFile>]) {
+    for (n, copies) in groups.iter().enumerate() {
+        print_group(skin, n, copies);
+    }
+}
+
+enum Choice {
+    Keep(usize),
+    Skip,
+    Quit,
+}
+
+fn question(copies: usize, suggested: usize) -> Question {
+    let mut question = Question::new("Which copy do you keep?");
+    for i in 1..=copies {
+        question.add_answer(i, format!("keep **{i}**, trash the others"));
+    }
+    for i in 1..=copies {
+        question.add_answer(format!("p{i}"), format!("**p**lay {i}"));
+    }
+    question.add_answer('s', "**s**kip, keep them all");
+    question.add_answer('q', "**q**uit");
+    question.set_default(suggested + 1);
+    question
+}
+
+/// Asks which copy to keep, copies are played while asking again when the user wants to
+/// hear them
+fn choose(skin: &MadSkin, copies: &[DupeFile], suggested: usize) -> Result<Choice> {
+    let mut answer = question(copies.len(), suggested).ask(skin)?;
+    while let Some(i) = answer.strip_prefix('p').map(str::parse::<usize>) {
+        let path = copies[i? - 1].path();
+        mad_print_inline!(skin, "**playing** $0\n", path.display());
+        let (len, player_skin) = (copies.len(), skin.clone());
+        answer = match play_while(path, move || question(len, suggested).ask(&player_skin))? {
+            Some(answer) => answer?,
+            None => {
+                mad_print_inline!(skin, "$0 *not supported*\n", path.display());
+                question(copies.len(), suggested).ask(skin)?
+            }
+        };
+    }
+    Ok(match answer.as_str() {
+        "s" => Choice::Skip,
+        "q" => Choice::Quit,
+        i => Choice::Keep(i.parse::<usize>()? - 1),
+    })
+}
+
+/// Keeps one copy of every group, picked by `keep` or else asked for, and trashes the
+/// others that match it, every group resolved is recorded in `log`
+pub fn resolve(
+    skin: &MadSkin,
+    groups: &[Vec<DupeFile>],
+    keep: Option<&Keep>,
+    options: &dupes::Options,
+    log: &mut Log,
+) -> (Vec<Resolution>, Vec<Error>) {
+    let mut resolutions = vec![];
+    let mut errors = vec![];
+    for (n, copies) in groups.iter().enumerate() {
+        print_group(skin, n, copies);
+        let (choice, by) = match keep {
+            Some(keep) => match keep.pick(copies) {
+                Some(i) => (Choice::Keep(i), keep.to_string()),
+                None => (Choice::Skip, keep.to_string()),
+            },
+            None => {
+                let suggested = Keep::BestQuality.pick(copies).unwrap_or_default();
+                match choose(skin, copies, suggested) {
+                    Ok(choice) => (choice, "chosen".to_owned()),
+                    Err(err) => {
+                        errors.push(err.context("❌ asking which copy to keep failed"));
+                        break;
+                    }
+                }
+            }
+        };
+        match choice {
+            Choice::Keep(i) => match dupes::resolve(copies, i, &by, options, log) {
+                Ok(resolution) => {
+                    for trashed in &resolution.trashed {
+                        mad_print_inline!(skin, "*trash*  $0\n", trashed.display());
+                    }
+                    for unmatched in &resolution.unmatched {
+                        mad_print_inline!(
+                            skin,
+                            "*kept*   $0, it doesn't match the kept copy itself\n",
+                            unmatched.display()
+                        );
+                    }
+                    resolutions.push(resolution);
+                }
+                Err(err) => errors.push(err),
+            },
+            Choice::Skip => mad_print_inline!(skin, "*skipped*, all copies kept\n"),
+            Choice::Quit => break,
+        }
+    }
+    (resolutions, errors)
+}
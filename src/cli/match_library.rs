@@ -0,0 +1,65 @@
+use std::path::PathBuf;
+
+use anyhow::{Error, Result};
+use rayon::prelude::*;
+use risto::dupes::Index;
+use termimad::MadSkin;
+
+use super::find_dupes::{audio, Options};
+
+/// An incoming song and the library song it is
+pub struct Matched {
+    pub file: PathBuf,
+    /// Library song and similarity, the share of fingerprint bits that are the same
+    pub found: Option<(PathBuf, f64)>,
+}
+
+/// Looks up every `incoming` song in `library`, fingerprints come from the cache when it
+/// has them
+pub fn match_library(
+    library: &[PathBuf],
+    incoming: &[PathBuf],
+    options: &Options,
+) -> (Vec<Matched>, Vec<Error>) {
+    let (songs, errors): (Vec<_>, Vec<_>) = library
+        .par_iter()
+        .map(|file| audio(file, options.max_analysed))
+        .partition(Result::is_ok);
+    let index = Index::new(songs.into_iter().map(Result::unwrap).collect());
+    let mut errors: Vec<_> = errors.into_iter().filter_map(Result::err).collect();
+
+    let (matched, incoming_errors): (Vec<_>, Vec<_>) = incoming
+        .par_iter()
+        .map(|file| -> Result<Matched> {
+            let song = audio(file, options.max_analysed)?;
+            let found = index
+                .find(&song, &options.dupes)
+                .map(|(x, rate)| (x.path.clone(), 1.0 - rate));
+            Ok(Matched {
+                file: song.path,
+                found,
+            })
+        })
+        .partition(Result::is_ok);
+    errors.extend(incoming_errors.into_iter().filter_map(Result::err));
+    let matched = matched.into_iter().map(Result::unwrap).collect();
+    (matched, errors)
+}
+
+pub fn print_matches(skin: &MadSkin, matches: &[Matched]) {
+    let mut rows = String::new();
+    for matched in matches {
+        rows += &match &matched.found {
+            Some((path, similarity)) => format!(
+                "|{}|{}|{:.1}%|\n",
+                matched.file.display(),
+                path.display(),
+                similarity * 100.0
+            ),
+            None => format!("|{}|*no*||\n", matched.file.display()),
+        };
+    }
+    skin.print_text(&format!(
+        "\n|file|already in library|similarity|\n|-|-|-|\n{rows}"
+    ));
+}
@@ -1,31 +1,395 @@
-use anyhow::{Context, Error, Result};
-use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use anyhow::{anyhow, Context, Error, Result};
+use rayon::{
+    prelude::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator},
+    slice::ParallelSlice,
+};
 use risto::{
-    acoustid::{lookup_by_fingerprint, rename_file_as_artist_dash_title, write_song_data},
-    Song,
+    acoustid::{
+        most_likely_song, read_tag_snapshot, write_song_data, ArtistPolicy, Candidate, Client,
+        SongData, TagSnapshot,
+    },
+    journal::{Entry, Journal},
+    rename::{Naming, Outcome, Renamer},
+    AcoustId, Song,
+};
+use std::{
+    collections::HashSet,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
 };
-use std::path::PathBuf;
+use termimad::{mad_print_inline, MadSkin, Question};
+
+use super::change;
 
-fn lookup_write_id3_and_rename_file(file: &PathBuf) -> Result<PathBuf> {
+pub struct Fingerprinted {
+    pub file: PathBuf,
+    pub acoustid: AcoustId,
+    pub duration: Duration,
+}
+
+/// Fingerprint and length of `file`, from the cache when it has them
+pub fn fingerprint(file: &Path, max_analysed: Duration) -> Result<Fingerprinted> {
     let filename = file.display();
     eprintln!("\n# File `{}`", filename);
-    let song = Song::new(file).with_context(|| format!("❌ open song failed {filename}"))?;
-    let song_data = lookup_by_fingerprint(song)
-        .with_context(|| format!("❌ fingerprint lookup failed {filename}"))?;
-    write_song_data(file, &song_data).with_context(|| format!("❌ write id3 failed {filename}"))?;
-    let newfile = rename_file_as_artist_dash_title(file)
-        .with_context(|| format!("❌ rename file failed {filename}"))?;
-    Ok(newfile)
+    let mut song = Song::new(file)
+        .with_context(|| format!("❌ open song failed {filename}"))?
+        .with_max_analysed(max_analysed);
+    let acoustid = song
+        .get_acoustid()
+        .with_context(|| format!("❌ fingerprint failed {filename}"))?;
+    let duration = song
+        .get_duration()
+        .with_context(|| format!("❌ duration failed {filename}"))?;
+    Ok(Fingerprinted {
+        file: file.to_path_buf(),
+        acoustid,
+        duration,
+    })
+}
+
+struct LookedUp {
+    file: PathBuf,
+    duration: Duration,
+    candidates: Result<Vec<Candidate>>,
+}
+
+fn lookup_batch(client: &Client, batch: &[Fingerprinted]) -> Vec<LookedUp> {
+    let fingerprints: Vec<String> = batch.iter().map(|x| x.acoustid.to_string()).collect();
+    let songs: Vec<(&str, Duration)> = fingerprints
+        .iter()
+        .zip(batch)
+        .map(|(fingerprint, x)| (fingerprint.as_str(), x.duration))
+        .collect();
+
+    let results = match client.lookup_batch_candidates(&songs) {
+        Ok(results) => results.into_iter().map(Ok).collect(),
+        Err(err) => {
+            // anyhow errors can't be cloned, every file in the batch gets the message
+            let err = format!("{err:#}");
+            batch
+                .iter()
+                .map(|_| Err(anyhow!("batch lookup failed: {err}")))
+                .collect::<Vec<_>>()
+        }
+    };
+    batch
+        .iter()
+        .zip(results)
+        .map(|(x, candidates)| LookedUp {
+            file: x.file.clone(),
+            duration: x.duration,
+            candidates,
+        })
+        .collect()
+}
+
+fn read_line(skin: &MadSkin, prompt: &str) -> Result<String> {
+    mad_print_inline!(skin, "$0: ", prompt);
+    io::stdout().flush()?;
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    Ok(line.trim().to_owned())
+}
+
+fn duration_delta(candidate: &Candidate, local: Duration) -> String {
+    match candidate.duration {
+        Some(duration) => format!("{:+}s", duration.as_secs() as i64 - local.as_secs() as i64),
+        None => "?s".to_owned(),
+    }
+}
+
+/// Lets the user choose one of the ranked candidates, skip the file (`None`) or type
+/// artist and title
+fn pick_candidate(
+    skin: &MadSkin,
+    file: &Path,
+    duration: Duration,
+    candidates: &[Candidate],
+    policy: ArtistPolicy,
+) -> Result<Option<SongData>> {
+    // compilations repeat the same recording many times, keep the best scored of each
+    let mut seen = HashSet::new();
+    let choices: Vec<(&Candidate, SongData)> = candidates
+        .iter()
+        .filter_map(|x| Some((x, x.song_data(policy)?)))
+        .filter(|(x, _)| seen.insert((x.title.clone(), x.artists.clone())))
+        .collect();
+
+    mad_print_inline!(skin, "\n**Candidates for** `$0`\n", file.display());
+    let mut question = Question::new("Which one?");
+    for (i, (candidate, _)) in choices.iter().enumerate() {
+        question.add_answer(
+            i + 1,
+            format!(
+                "{:>3.0}% **{}** by *{}* ({})",
+                candidate.score * 100.0,
+                candidate.title.as_deref().unwrap_or_default(),
+                candidate
+                    .artist_credit
+                    .clone()
+                    .unwrap_or(candidate.artists.join(", ")),
+                duration_delta(candidate, duration),
+            ),
+        );
+    }
+    question.add_answer('s', "**s**kip this file");
+    question.add_answer('m', "**m**anually type artist and title");
+    question.set_default(if choices.is_empty() { "s" } else { "1" });
+
+    let answer = question.ask(skin)?;
+    match answer.as_str() {
+        "s" => Ok(None),
+        "m" => {
+            let artist = read_line(skin, "artist")?;
+            let title = read_line(skin, "title")?;
+            if artist.is_empty() || title.is_empty() {
+                mad_print_inline!(skin, "*empty artist or title, skipped*\n");
+                return Ok(None);
+            }
+            Ok(Some(SongData {
+                title,
+                artist,
+                ..Default::default()
+            }))
+        }
+        n => {
+            let i: usize = n.parse()?;
+            Ok(Some(choices[i - 1].1.clone()))
+        }
+    }
+}
+
+fn write_tags_and_rename_file(
+    journal: &Journal,
+    renamer: &Renamer,
+    file: PathBuf,
+    song_data: SongData,
+) -> Result<Outcome> {
+    let filename = file.display();
+    // absolute paths, undo may run from another directory
+    let original = file
+        .canonicalize()
+        .with_context(|| format!("❌ file not found {filename}"))?;
+    let previous = read_tag_snapshot(&original);
+    write_song_data(&original, &song_data)
+        .with_context(|| format!("❌ write tags failed {filename}"))?;
+    let outcome = renamer
+        .rename(&original)
+        .with_context(|| format!("❌ rename file failed {filename}"));
+
+    let done = outcome.as_ref().ok();
+    journal.record(&Entry {
+        original,
+        renamed: done.and_then(Outcome::renamed).map(Path::to_path_buf),
+        previous,
+        trashed: done.and_then(Outcome::trashed).map(Path::to_path_buf),
+    })?;
+    outcome
 }
 
-pub fn as_title_artist(files: &Vec<PathBuf>) -> Result<(Vec<PathBuf>, Vec<Error>)> {
-    let (newfiles, errors): (Vec<_>, Vec<_>) = files
+/// Prints where files would go, returns what would happen to each file
+fn print_renames(
+    skin: &MadSkin,
+    naming: &Naming,
+    songs: &[(PathBuf, TagSnapshot)],
+) -> (Vec<Outcome>, Vec<Error>) {
+    let renamer = Renamer::new(naming.clone());
+    let mut renames = String::from("|old|new|\n|-|-|\n");
+    let mut planned = vec![];
+    let mut errors = vec![];
+    for (file, tags) in songs {
+        match renamer.plan(file, tags) {
+            Ok(outcome) => {
+                let new = match outcome.renamed() {
+                    Some(newfile) => newfile.display().to_string(),
+                    None => "*not renamed*".to_owned(),
+                };
+                renames += &format!("|{}|{}|\n", file.display(), new);
+                planned.push(outcome);
+            }
+            Err(err) => {
+                errors.push(err.context(format!("❌ rename file failed {}", file.display())))
+            }
+        }
+    }
+
+    skin.print_text("\n## Planned renames (dry run)\n");
+    skin.print_text(&renames);
+    (planned, errors)
+}
+
+/// Prints the tags and paths a run would write, returns what would happen to each file
+fn print_plan(
+    skin: &MadSkin,
+    naming: &Naming,
+    chosen: &[(PathBuf, SongData)],
+) -> (Vec<Outcome>, Vec<Error>) {
+    let mut tags = String::from("|file|artist|title|album|\n|-|-|-|-|\n");
+    let mut songs = vec![];
+    for (file, song_data) in chosen {
+        let old = read_tag_snapshot(file);
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        let album = match &song_data.album {
+            Some(album) => change(old.album.as_deref(), album),
+            None => "*unchanged*".to_owned(),
+        };
+        tags += &format!(
+            "|{}|{}|{}|{}|\n",
+            name,
+            change(old.artist.as_deref(), &song_data.artist),
+            change(old.title.as_deref(), &song_data.title),
+            album,
+        );
+        songs.push((file.clone(), old.with_song_data(song_data)));
+    }
+
+    skin.print_text("\n## Planned tag changes (dry run)\n");
+    skin.print_text(&tags);
+    print_renames(skin, naming, &songs)
+}
+
+pub struct Options {
+    /// How many fingerprints go in one AcoustID request
+    pub batch_size: usize,
+    /// Ask which candidate to use instead of taking the best scored one
+    pub interactive: bool,
+    /// Candidates scoring less than this are ignored
+    pub min_score: f64,
+    /// How much of every song is fingerprinted
+    pub max_analysed: Duration,
+    /// Only print what would be tagged and renamed
+    pub dry_run: bool,
+    /// Where to record changes for `risto undo`
+    pub journal: PathBuf,
+    pub artist_policy: ArtistPolicy,
+    /// Where renamed files go and what happens when a name is taken
+    pub naming: Naming,
+}
+
+/// Fingerprints every file, looks them up `batch_size` at a time and renames them
+pub fn as_title_artist(
+    skin: &MadSkin,
+    client: &Client,
+    files: &Vec<PathBuf>,
+    options: &Options,
+) -> Result<(Vec<Outcome>, Vec<Error>)> {
+    let (fingerprinted, fingerprint_errors): (Vec<_>, Vec<_>) = files
         .par_iter()
-        .map(lookup_write_id3_and_rename_file)
+        .map(|file| fingerprint(file, options.max_analysed))
         .partition(Result::is_ok);
+    let fingerprinted: Vec<_> = fingerprinted.into_iter().map(Result::unwrap).collect();
+    let mut errors: Vec<_> = fingerprint_errors
+        .into_iter()
+        .filter_map(Result::err)
+        .collect();
+
+    let looked_up: Vec<_> = fingerprinted
+        .par_chunks(options.batch_size.max(1))
+        .flat_map_iter(|batch| lookup_batch(client, batch))
+        .collect();
 
-    let newfiles: Vec<_> = newfiles.into_iter().map(Result::unwrap).collect();
-    let errors: Vec<_> = errors.into_iter().map(Result::unwrap_err).collect();
+    let mut chosen = vec![];
+    for x in looked_up {
+        let filename = x.file.display();
+        let mut candidates = match x.candidates {
+            Ok(candidates) => candidates,
+            Err(err) => {
+                errors.push(err.context(format!("❌ fingerprint lookup failed {filename}")));
+                continue;
+            }
+        };
+        let best_score = candidates.first().map(|x| x.score);
+        candidates.retain(|x| x.score >= options.min_score);
+        if let (Some(best_score), true) = (best_score, candidates.is_empty()) {
+            errors.push(anyhow!(
+                "❌ best score {best_score:.2} below minimum {} {filename}",
+                options.min_score
+            ));
+            continue;
+        }
+        let song_data = if options.interactive {
+            pick_candidate(
+                skin,
+                &x.file,
+                x.duration,
+                &candidates,
+                options.artist_policy,
+            )
+        } else {
+            most_likely_song(&candidates, options.artist_policy).map(Some)
+        };
+        match song_data {
+            Ok(Some(song_data)) => chosen.push((x.file, song_data)),
+            Ok(None) => mad_print_inline!(skin, "*skipped* $0\n", filename),
+            Err(err) => {
+                errors.push(err.context(format!("❌ fingerprint lookup failed {filename}")))
+            }
+        }
+    }
+
+    if options.dry_run {
+        let (planned, plan_errors) = print_plan(skin, &options.naming, &chosen);
+        errors.extend(plan_errors);
+        return Ok((planned, errors));
+    }
+
+    let journal = Journal::create(&options.journal)?;
+    let renamer = Renamer::new(options.naming.clone());
+    let (outcomes, rename_errors): (Vec<_>, Vec<_>) = chosen
+        .into_par_iter()
+        .map(|(file, song_data)| write_tags_and_rename_file(&journal, &renamer, file, song_data))
+        .partition(Result::is_ok);
+
+    let outcomes: Vec<_> = outcomes.into_iter().map(Result::unwrap).collect();
+    errors.extend(rename_errors.into_iter().map(Result::unwrap_err));
+
+    Ok((outcomes, errors))
+}
+
+fn rename_file(journal: &Journal, renamer: &Renamer, file: &Path) -> Result<Outcome> {
+    let filename = file.display();
+    let original = file
+        .canonicalize()
+        .with_context(|| format!("❌ file not found {filename}"))?;
+    let previous = read_tag_snapshot(&original);
+    let outcome = renamer
+        .rename(&original)
+        .with_context(|| format!("❌ rename file failed {filename}"))?;
+    if outcome.renamed().is_some() || outcome.trashed().is_some() {
+        journal.record(&Entry {
+            original,
+            renamed: outcome.renamed().map(Path::to_path_buf),
+            previous,
+            trashed: outcome.trashed().map(Path::to_path_buf),
+        })?;
+    }
+    Ok(outcome)
+}
+
+/// Renames files after the tags they already have, nothing is fingerprinted nor looked up
+pub fn from_tags(
+    skin: &MadSkin,
+    files: &[PathBuf],
+    options: &Options,
+) -> Result<(Vec<Outcome>, Vec<Error>)> {
+    if options.dry_run {
+        let songs: Vec<_> = files
+            .iter()
+            .map(|file| (file.clone(), read_tag_snapshot(file)))
+            .collect();
+        return Ok(print_renames(skin, &options.naming, &songs));
+    }
+
+    let journal = Journal::create(&options.journal)?;
+    let renamer = Renamer::new(options.naming.clone());
+    let (outcomes, errors): (Vec<_>, Vec<_>) = files
+        .par_iter()
+        .map(|file| rename_file(&journal, &renamer, file))
+        .partition(Result::is_ok);
 
-    Ok((newfiles, errors))
+    Ok((
+        outcomes.into_iter().map(Result::unwrap).collect(),
+        errors.into_iter().map(Result::unwrap_err).collect(),
+    ))
 }
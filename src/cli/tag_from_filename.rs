@@ -0,0 +1,100 @@
+use anyhow::{Context, Error, Result};
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use risto::{
+    acoustid::{read_tag_snapshot, write_tag_changes, TagSnapshot},
+    journal::{Entry, Journal},
+    rename::Template,
+};
+use std::path::{Path, PathBuf};
+use termimad::MadSkin;
+
+use super::change;
+
+pub struct Options {
+    /// How file names are read, e.g. `{artist} - {title}`
+    pub pattern: Template,
+    /// Only print the tags that would be written
+    pub dry_run: bool,
+    /// Where to record changes for `risto undo`
+    pub journal: PathBuf,
+}
+
+/// The current tag of `file` and the fields read from its name
+fn parse(file: &Path, pattern: &Template) -> Result<(TagSnapshot, TagSnapshot)> {
+    let parsed = pattern
+        .parse_path(file)
+        .with_context(|| format!("❌ can't read tags from name {}", file.display()))?;
+    Ok((read_tag_snapshot(file), parsed))
+}
+
+fn write_tags(journal: &Journal, pattern: &Template, file: &Path) -> Result<PathBuf> {
+    let filename = file.display();
+    let original = file
+        .canonicalize()
+        .with_context(|| format!("❌ file not found {filename}"))?;
+    let (previous, parsed) = parse(&original, pattern)?;
+    write_tag_changes(&original, &parsed)
+        .with_context(|| format!("❌ write tags failed {filename}"))?;
+    journal.record(&Entry {
+        original: original.clone(),
+        renamed: None,
+        previous,
+        trashed: None,
+    })?;
+    Ok(original)
+}
+
+fn print_plan(skin: &MadSkin, files: &[PathBuf], pattern: &Template) -> (Vec<PathBuf>, Vec<Error>) {
+    let mut table = String::from("|file|artist|title|album|track|\n|-|-|-|-|-|\n");
+    let mut planned = vec![];
+    let mut errors = vec![];
+    for file in files {
+        let (old, new) = match parse(file, pattern) {
+            Ok((old, parsed)) => (old.clone(), old.with_changes(&parsed)),
+            Err(err) => {
+                errors.push(err);
+                continue;
+            }
+        };
+        let field = |old: Option<String>, new: Option<String>| match new {
+            Some(new) => change(old.as_deref(), &new),
+            None => "*none*".to_owned(),
+        };
+        let number = |x: Option<u32>| x.map(|x| x.to_string());
+        table += &format!(
+            "|{}|{}|{}|{}|{}|\n",
+            file.file_name().unwrap_or_default().to_string_lossy(),
+            field(old.artist, new.artist),
+            field(old.title, new.title),
+            field(old.album, new.album),
+            field(number(old.track), number(new.track)),
+        );
+        planned.push(file.clone());
+    }
+
+    skin.print_text("\n## Planned tag changes (dry run)\n");
+    skin.print_text(&table);
+    (planned, errors)
+}
+
+/// Writes the fields `pattern` finds in each file name into the file's tag
+pub fn tag_from_filename(
+    skin: &MadSkin,
+    files: &[PathBuf],
+    options: &Options,
+) -> Result<(Vec<PathBuf>, Vec<Error>)> {
+    if options.dry_run {
+        return Ok(print_plan(skin, files, &options.pattern));
+    }
+
+    let journal = Journal::create(&options.journal)?;
+    let (tagged, errors): (Vec<_>, Vec<_>) = files
+        .par_iter()
+        .map(|file| write_tags(&journal, &options.pattern, file))
+        .partition(Result::is_ok);
+
+    Ok((
+        tagged.into_iter().map(Result::unwrap).collect(),
+        errors.into_iter().map(Result::unwrap_err).collect(),
+    ))
+}
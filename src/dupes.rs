@@ -0,0 +1,338 @@
+//! Songs with the same audio, whatever their encoding, bitrate or container
+
+use std::{
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context, Error, Result};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    fingerprint::{bit_error_rate, ITEM_SECONDS},
+    Song,
+};
+
+/// When two songs count as the same
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// Share of differing fingerprint bits up to which songs are the same, unrelated songs
+    /// are around 0.5
+    pub max_bit_error_rate: f64,
+    /// Songs whose lengths differ more are never compared, also how far fingerprints are
+    /// shifted against each other to skip silence trimmed off one of them
+    pub max_length_diff: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            max_bit_error_rate: 0.15,
+            max_length_diff: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A song and its raw fingerprint
+#[derive(Debug, Clone)]
+pub struct Audio {
+    pub path: PathBuf,
+    pub duration: Duration,
+    pub fingerprint: Vec<u32>,
+}
+
+/// Bit error rate of `a` and `b` if they're the same song
+pub fn same(a: &Audio, b: &Audio, options: &Options) -> Option<f64> {
+    if a.duration.abs_diff(b.duration) > options.max_length_diff {
+        return None;
+    }
+    let max_offset = (options.max_length_diff.as_secs_f64() / ITEM_SECONDS).ceil() as usize;
+    let min_overlap = a.fingerprint.len().min(b.fingerprint.len()) / 2;
+    bit_error_rate(&a.fingerprint, &b.fingerprint, max_offset, min_overlap)
+        .filter(|x| *x <= options.max_bit_error_rate)
+}
+
+fn root(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+/// Groups of two or more indices into `songs` that are the same song, pairs of a group need
+/// not match each other directly as long as they're linked by matching songs. Only songs of
+/// about the same length are compared
+pub fn groups(songs: &[Audio], options: &Options) -> Vec<Vec<usize>> {
+    let mut by_length: Vec<usize> = (0..songs.len()).collect();
+    by_length.sort_by_key(|&i| songs[i].duration);
+    let by_length = &by_length;
+    let pairs: Vec<(usize, usize)> = (0..by_length.len())
+        .into_par_iter()
+        .flat_map_iter(|n| {
+            let i = by_length[n];
+            by_length[n + 1..]
+                .iter()
+                .take_while(move |&&j| {
+                    songs[j].duration - songs[i].duration <= options.max_length_diff
+                })
+                .filter(move |&&j| same(&songs[i], &songs[j], options).is_some())
+                .map(move |&j| (i, j))
+        })
+        .collect();
+
+    let mut parents: Vec<usize> = (0..songs.len()).collect();
+    for (i, j) in pairs {
+        let (i, j) = (root(&mut parents, i), root(&mut parents, j));
+        parents[i.max(j)] = i.min(j);
+    }
+    let mut groups: Vec<Vec<usize>> = vec![vec![]; songs.len()];
+    for i in 0..songs.len() {
+        let group = root(&mut parents, i);
+        groups[group].push(i);
+    }
+    groups.retain(|x| x.len() > 1);
+    groups
+}
+
+/// Songs of a library sorted by length, to look up which of them another song is
+#[derive(Debug, Clone, Default)]
+pub struct Index {
+    songs: Vec<Audio>,
+}
+
+impl Index {
+    pub fn new(mut songs: Vec<Audio>) -> Self {
+        songs.sort_by_key(|x| x.duration);
+        Index { songs }
+    }
+
+    pub fn len(&self) -> usize {
+        self.songs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.songs.is_empty()
+    }
+
+    /// The library song that is the same as `song` with the lowest bit error rate, only
+    /// songs of about the same length are compared. `song`'s own file is never a match
+    pub fn find(&self, song: &Audio, options: &Options) -> Option<(&Audio, f64)> {
+        let shortest = song.duration.saturating_sub(options.max_length_diff);
+        let longest = song.duration.saturating_add(options.max_length_diff);
+        let start = self.songs.partition_point(|x| x.duration < shortest);
+        let end = self.songs.partition_point(|x| x.duration <= longest);
+        self.songs[start..end.max(start)]
+            .iter()
+            .filter(|x| !same_file::is_same_file(&x.path, &song.path).unwrap_or(false))
+            .filter_map(|x| same(song, x, options).map(|rate| (x, rate)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+    }
+}
+
+/// One file of a group of duplicates
+#[derive(Debug, Clone)]
+pub struct DupeFile {
+    pub audio: Audio,
+    /// Upper-cased extension, e.g. FLAC
+    pub format: String,
+    /// Bits per second
+    pub bitrate: f64,
+    /// Bytes
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+impl DupeFile {
+    pub fn new(audio: Audio) -> Result<Self> {
+        let song = Song::new(&audio.path)?;
+        let metadata = fs::metadata(&audio.path)?;
+        Ok(DupeFile {
+            format: audio
+                .path
+                .extension()
+                .map(|x| x.to_string_lossy().to_uppercase())
+                .unwrap_or_default(),
+            bitrate: song.bitrate()?,
+            size: metadata.len(),
+            modified: metadata.modified()?,
+            audio,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.audio.path
+    }
+
+    /// Decodes to the original audio, no matter the bitrate
+    pub fn is_lossless(&self) -> bool {
+        ["FLAC", "WAV", "AIFF", "AIF", "APE", "WV"].contains(&self.format.as_str())
+    }
+}
+
+/// Which copy of a group is kept, the others go to the trash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Keep {
+    /// Lossless first, then the highest bitrate
+    BestQuality,
+    Largest,
+    /// Least recently modified
+    Oldest,
+    /// The copy inside this folder, groups without one are left alone
+    InDir(PathBuf),
+}
+
+impl FromStr for Keep {
+    type Err = Error;
+
+    /// `best-quality`, `largest`, `oldest` or `in-dir=<PATH>`, a leading `~` in the path is
+    /// the home folder
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('=') {
+            Some(("in-dir", dir)) if !dir.is_empty() => {
+                Ok(Keep::InDir(PathBuf::from(shellexpand::tilde(dir).as_ref())))
+            }
+            _ => match s {
+                "best-quality" => Ok(Keep::BestQuality),
+                "largest" => Ok(Keep::Largest),
+                "oldest" => Ok(Keep::Oldest),
+                "in-dir" => Err(anyhow!("in-dir needs a folder, e.g. in-dir=~/Music")),
+                _ => Err(anyhow!(
+                    "unknown policy {s:?}, use best-quality, largest, oldest or in-dir=<PATH>"
+                )),
+            },
+        }
+    }
+}
+
+impl Display for Keep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Keep::BestQuality => write!(f, "best-quality"),
+            Keep::Largest => write!(f, "largest"),
+            Keep::Oldest => write!(f, "oldest"),
+            Keep::InDir(dir) => write!(f, "in-dir={}", dir.display()),
+        }
+    }
+}
+
+impl Keep {
+    /// Index of the copy to keep, the first one on ties
+    pub fn pick(&self, copies: &[DupeFile]) -> Option<usize> {
+        let best = |better: &dyn Fn(&DupeFile, &DupeFile) -> bool| {
+            (0..copies.len()).reduce(|best, i| {
+                if better(&copies[i], &copies[best]) {
+                    i
+                } else {
+                    best
+                }
+            })
+        };
+        match self {
+            Keep::BestQuality => {
+                best(&|a, b| (a.is_lossless(), a.bitrate) > (b.is_lossless(), b.bitrate))
+            }
+            Keep::Largest => best(&|a, b| a.size > b.size),
+            Keep::Oldest => best(&|a, b| a.modified < b.modified),
+            Keep::InDir(dir) => {
+                let dir = dir.canonicalize().unwrap_or_else(|_| dir.clone());
+                copies.iter().position(|x| {
+                    let path = x
+                        .path()
+                        .canonicalize()
+                        .unwrap_or_else(|_| x.path().to_owned());
+                    path.starts_with(&dir)
+                })
+            }
+        }
+    }
+}
+
+/// A group of duplicates that was dealt with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Resolution {
+    pub kept: PathBuf,
+    pub trashed: Vec<PathBuf>,
+    /// Copies left alone, they're in the group through other copies but don't match the kept
+    /// one themselves
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unmatched: Vec<PathBuf>,
+    /// The [`Keep`] policy that picked the kept copy, or `chosen` when asked
+    pub by: String,
+}
+
+/// Append only JSON lines file, one [`Resolution`] per line
+#[derive(Debug)]
+pub struct Log {
+    path: PathBuf,
+    file: File,
+}
+
+impl Log {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("failed to create resolution log {path:?}"))?;
+        Ok(Log { path, file })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends and flushes right away
+    pub fn record(&mut self, resolution: &Resolution) -> Result<()> {
+        let mut line = serde_json::to_vec(resolution)?;
+        line.push(b'\n');
+        self.file
+            .write_all(&line)
+            .and_then(|_| self.file.flush())
+            .with_context(|| format!("failed to write resolution log {:?}", self.path))
+    }
+}
+
+/// Keeps `copies[keep]` and moves the other copies that are the same song as it to the
+/// trash, logging what was trashed even when trashing fails halfway
+pub fn resolve(
+    copies: &[DupeFile],
+    keep: usize,
+    by: &str,
+    options: &Options,
+    log: &mut Log,
+) -> Result<Resolution> {
+    let kept = &copies[keep];
+    let mut resolution = Resolution {
+        kept: kept.path().to_owned(),
+        trashed: vec![],
+        unmatched: vec![],
+        by: by.to_owned(),
+    };
+    let mut res = Ok(());
+    for (i, copy) in copies.iter().enumerate() {
+        if i == keep {
+            continue;
+        }
+        if same(&kept.audio, &copy.audio, options).is_none() {
+            resolution.unmatched.push(copy.path().to_owned());
+            continue;
+        }
+        res = trash::delete(copy.path())
+            .with_context(|| format!("trashing {} failed", copy.path().display()));
+        if res.is_err() {
+            break;
+        }
+        resolution.trashed.push(copy.path().to_owned());
+    }
+    if !resolution.trashed.is_empty() {
+        log.record(&resolution)?;
+    }
+    res.map(|_| resolution)
+}
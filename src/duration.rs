@@ -0,0 +1,273 @@
+//! Song lengths read from container headers, nothing is decoded
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use ogg::PacketReader;
+
+use crate::tags::id3v2_len;
+
+/// Length of `path` as its headers tell, the format is picked by file extension. `None` when
+/// the headers don't say, e.g. constant bitrate mp3 files without a Xing frame
+pub fn from_headers(path: &Path) -> Result<Option<Duration>> {
+    let extension = path
+        .extension()
+        .map(|x| x.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let mut file = File::open(path).with_context(|| format!("opening song {}", path.display()))?;
+    match extension.as_str() {
+        "mp3" => mp3(&mut file),
+        "flac" => flac(&mut file),
+        "ogg" | "oga" | "opus" => ogg(&mut file),
+        "wav" => wav(&mut file),
+        "m4a" | "m4b" | "mp4" => mp4(&mut file),
+        _ => Ok(None),
+    }
+    .with_context(|| format!("reading the length of {}", path.display()))
+}
+
+fn samples(count: u64, sample_rate: u32) -> Option<Duration> {
+    (sample_rate > 0).then(|| Duration::from_secs_f64(count as f64 / f64::from(sample_rate)))
+}
+
+/// Up to `len` bytes from the current position, fewer at the end of the file
+fn read_up_to(file: &mut File, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    file.take(len as u64).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Frame count from the Xing/Info or VBRI frame VBR encoders put first, encoder delay and
+/// padding from the LAME extension are left out,
+/// see http://gabriel.mp3-tech.org/mp3infotag.html
+fn mp3(file: &mut File) -> Result<Option<Duration>> {
+    let mut id3 = [0; 10];
+    file.read_exact(&mut id3)?;
+    let audio_start = id3v2_len(&id3);
+    file.seek(SeekFrom::Start(audio_start))?;
+    let data = read_up_to(file, 64 * 1024)?;
+
+    let Some(start) = (0..data.len().saturating_sub(4))
+        .find(|&i| data[i] == 0xff && data[i + 1] & 0xe0 == 0xe0 && data[i + 2] >> 4 != 0xf)
+    else {
+        return Ok(None);
+    };
+    let frame = &data[start..];
+    let (version, layer) = ((frame[1] >> 3) & 3, (frame[1] >> 1) & 3);
+    let mono = frame[3] >> 6 == 3;
+    let sample_rate = match (frame[2] >> 2) & 3 {
+        0 => 44100,
+        1 => 48000,
+        2 => 32000,
+        _ => return Err(anyhow!("bad mp3 sample rate")),
+    } >> match version {
+        3 => 0,
+        2 => 1,
+        0 => 2,
+        _ => return Err(anyhow!("bad mp3 version")),
+    };
+    let samples_per_frame: u64 = match (layer, version) {
+        (3, _) => 384,
+        (1, 3) | (2, _) => 1152,
+        (1, _) => 576,
+        _ => return Err(anyhow!("bad mp3 layer")),
+    };
+    let side_info = match (version == 3, mono) {
+        (true, false) => 32,
+        (true, true) | (false, false) => 17,
+        (false, true) => 9,
+    };
+    let be32 = |at: usize| -> Option<u32> {
+        Some(u32::from_be_bytes(frame.get(at..at + 4)?.try_into().ok()?))
+    };
+
+    let xing = 4 + side_info;
+    match frame.get(xing..xing + 4) {
+        Some(b"Xing" | b"Info") => {
+            let flags = be32(xing + 4).unwrap_or_default();
+            if flags & 1 == 0 {
+                return Ok(None);
+            }
+            let frames = u64::from(be32(xing + 8).ok_or(anyhow!("xing frame truncated"))?);
+            // the LAME tag follows the fields flagged as present
+            let fields = [4, 4, 100, 4];
+            let lame = xing
+                + 8
+                + (0..4)
+                    .filter(|i| flags & (1 << i) != 0)
+                    .map(|i| fields[i])
+                    .sum::<usize>();
+            let (delay, padding) = match frame.get(lame..lame + 24) {
+                Some(tag) if tag.starts_with(b"LAME") || tag.starts_with(b"Lavc") => {
+                    let x = &tag[21..24];
+                    (
+                        u64::from(x[0]) << 4 | u64::from(x[1] >> 4),
+                        u64::from(x[1] & 0xf) << 8 | u64::from(x[2]),
+                    )
+                }
+                _ => (0, 0),
+            };
+            let count = (frames * samples_per_frame).saturating_sub(delay + padding);
+            Ok(samples(count, sample_rate))
+        }
+        _ => match frame.get(36..40) {
+            Some(b"VBRI") => {
+                let frames = u64::from(be32(36 + 14).ok_or(anyhow!("vbri frame truncated"))?);
+                Ok(samples(frames * samples_per_frame, sample_rate))
+            }
+            _ => Ok(None),
+        },
+    }
+}
+
+/// Total samples of STREAMINFO, see https://xiph.org/flac/format.html#metadata_block_streaminfo
+fn flac(file: &mut File) -> Result<Option<Duration>> {
+    let mut head = [0; 4 + 4 + 18];
+    file.read_exact(&mut head)?;
+    if !head.starts_with(b"fLaC") || head[4] & 0x7f != 0 {
+        return Err(anyhow!("not a flac file"));
+    }
+    let info = &head[8..];
+    let sample_rate =
+        u32::from(info[10]) << 12 | u32::from(info[11]) << 4 | u32::from(info[12]) >> 4;
+    let total =
+        u64::from(info[13] & 0xf) << 32 | u64::from(u32::from_be_bytes(info[14..18].try_into()?));
+    // 0 means the encoder didn't know
+    Ok(if total == 0 {
+        None
+    } else {
+        samples(total, sample_rate)
+    })
+}
+
+/// Granule position of the last page of the first stream, Opus pre-skip left out
+fn ogg(file: &mut File) -> Result<Option<Duration>> {
+    let header = PacketReader::new(&mut *file)
+        .read_packet()?
+        .ok_or(anyhow!("empty ogg file"))?;
+    let serial = header.stream_serial();
+    let le32 = |data: &[u8], at: usize| -> Result<u32> {
+        let bytes = data
+            .get(at..at + 4)
+            .ok_or(anyhow!("ogg header truncated"))?;
+        Ok(u32::from_le_bytes(bytes.try_into()?))
+    };
+    let (sample_rate, pre_skip) = match &header.data {
+        x if x.starts_with(b"\x01vorbis") => (le32(x, 12)?, 0),
+        x if x.starts_with(b"OpusHead") => {
+            let pre_skip = x.get(10..12).ok_or(anyhow!("opus header truncated"))?;
+            (48000, u64::from(u16::from_le_bytes(pre_skip.try_into()?)))
+        }
+        _ => return Ok(None),
+    };
+
+    // a page is at most 65307 bytes
+    let len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(len.saturating_sub(65307)))?;
+    let tail = read_up_to(file, 65307)?;
+    let granule = (0..tail.len().saturating_sub(27))
+        .rev()
+        .filter(|&i| tail[i..].starts_with(b"OggS"))
+        .filter(|&i| tail[i + 14..i + 18] == serial.to_le_bytes())
+        .map(|i| u64::from_le_bytes(tail[i + 6..i + 14].try_into().expect("8 bytes")))
+        // pages without a finished packet have no position
+        .find(|&x| x != u64::MAX);
+    Ok(granule.and_then(|x| samples(x.saturating_sub(pre_skip), sample_rate)))
+}
+
+/// Size of the `data` chunk over the byte rate of `fmt `
+fn wav(file: &mut File) -> Result<Option<Duration>> {
+    let mut riff = [0; 12];
+    file.read_exact(&mut riff)?;
+    if !riff.starts_with(b"RIFF") || &riff[8..] != b"WAVE" {
+        return Err(anyhow!("not a wav file"));
+    }
+    let mut byte_rate = None;
+    loop {
+        let mut chunk = [0; 8];
+        if file.read_exact(&mut chunk).is_err() {
+            return Ok(None);
+        }
+        let size = u32::from_le_bytes(chunk[4..].try_into()?);
+        match &chunk[..4] {
+            b"fmt " => {
+                let fmt = read_up_to(file, size as usize)?;
+                let rate = fmt.get(8..12).ok_or(anyhow!("wav fmt chunk truncated"))?;
+                byte_rate = Some(u32::from_le_bytes(rate.try_into()?));
+                file.seek(SeekFrom::Current(i64::from(size & 1)))?;
+            }
+            b"data" => {
+                return Ok(byte_rate.and_then(|rate| samples(u64::from(size), rate)));
+            }
+            // chunks are padded to an even size
+            _ => {
+                file.seek(SeekFrom::Current(i64::from(size) + i64::from(size & 1)))?;
+            }
+        }
+    }
+}
+
+/// Duration over timescale of `moov.mvhd`, only the atom headers before `moov` are read
+fn mp4(file: &mut File) -> Result<Option<Duration>> {
+    let moov = loop {
+        let mut header = [0; 8];
+        if file.read_exact(&mut header).is_err() {
+            return Ok(None);
+        }
+        let mut len = u64::from(u32::from_be_bytes(header[..4].try_into()?));
+        let mut header_len = 8;
+        if len == 1 {
+            let mut large = [0; 8];
+            file.read_exact(&mut large)?;
+            len = u64::from_be_bytes(large);
+            header_len = 16;
+        }
+        // size 0 is the last atom, it runs to the end of the file
+        if len == 0 {
+            if &header[4..] == b"moov" {
+                let mut moov = vec![];
+                file.read_to_end(&mut moov)?;
+                break moov;
+            }
+            return Ok(None);
+        }
+        let payload = len
+            .checked_sub(header_len)
+            .ok_or(anyhow!("mp4 atom has a bad size"))?;
+        if &header[4..] == b"moov" {
+            break read_up_to(file, payload as usize)?;
+        }
+        file.seek(SeekFrom::Current(payload as i64))?;
+    };
+
+    let mut start = 0;
+    while let Some(header) = moov.get(start..start + 8) {
+        let len = u32::from_be_bytes(header[..4].try_into()?) as usize;
+        if &header[4..] == b"mvhd" {
+            let mvhd = moov
+                .get(start + 8..start + len)
+                .ok_or(anyhow!("mvhd truncated"))?;
+            let be = |at: usize, size: usize| -> Result<u64> {
+                let x = mvhd.get(at..at + size).ok_or(anyhow!("mvhd truncated"))?;
+                Ok(x.iter().fold(0, |n, x| n << 8 | u64::from(*x)))
+            };
+            // version 1 has 64 bit times and duration
+            let (timescale, duration) = match mvhd.first() {
+                Some(1) => (be(20, 4)?, be(24, 8)?),
+                _ => (be(12, 4)?, be(16, 4)?),
+            };
+            return Ok((timescale > 0 && duration != u64::from(u32::MAX))
+                .then(|| Duration::from_secs_f64(duration as f64 / timescale as f64)));
+        }
+        if len < 8 {
+            return Err(anyhow!("mp4 atom has a bad size"));
+        }
+        start += len;
+    }
+    Ok(None)
+}
@@ -0,0 +1,103 @@
+//! Raw chromaprint fingerprints and how alike two of them are
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+/// Seconds of audio each item of a raw fingerprint covers
+pub const ITEM_SECONDS: f64 = 0.1238;
+
+/// Bits packed little-endian `width` at a time, as chromaprint's compressor writes them
+fn unpack(data: &[u8], width: usize, count: usize) -> Vec<u8> {
+    (0..count)
+        .map(|i| {
+            (0..width)
+                .filter(|bit| {
+                    let at = i * width + bit;
+                    data.get(at / 8).is_some_and(|x| x & (1 << (at % 8)) != 0)
+                })
+                .fold(0, |value, bit| value | 1 << bit)
+        })
+        .collect()
+}
+
+/// Decodes a compressed, base64 fingerprint as AcoustID and `fpcalc` give them into the
+/// algorithm and the 32 bit items, see chromaprint's `fingerprint_decompressor.cpp`
+pub fn decode(fingerprint: &str) -> Result<(u8, Vec<u32>)> {
+    let data = URL_SAFE_NO_PAD
+        .decode(fingerprint.trim_end_matches('='))
+        .map_err(|err| anyhow!("fingerprint isn't base64, {err}"))?;
+    let [algorithm, a, b, c, ref packed @ ..] = data[..] else {
+        return Err(anyhow!("fingerprint header truncated"));
+    };
+    let items = u32::from_be_bytes([0, a, b, c]) as usize;
+
+    // every item ends with a 0, 3 bits each
+    let most = packed.len() * 8 / 3;
+    let mut bits = unpack(packed, 3, most);
+    let ends = bits
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| **x == 0)
+        .map(|(i, _)| i)
+        .nth(items.saturating_sub(1));
+    let len = match (items, ends) {
+        (0, _) => 0,
+        (_, Some(last)) => last + 1,
+        (_, None) => return Err(anyhow!("fingerprint truncated")),
+    };
+    bits.truncate(len);
+
+    // 7 means the gap goes on in the 5 bit exceptions after the normal bits
+    let exceptions_at = (len * 3).div_ceil(8);
+    let exceptional = bits.iter().filter(|x| **x == 7).count();
+    let exceptions = unpack(
+        packed.get(exceptions_at..).unwrap_or_default(),
+        5,
+        exceptional,
+    );
+    if (exceptions_at * 8 + exceptional * 5).div_ceil(8) > packed.len() {
+        return Err(anyhow!("fingerprint exceptions truncated"));
+    }
+    let mut exceptions = exceptions.into_iter();
+    for bit in bits.iter_mut().filter(|x| **x == 7) {
+        *bit += exceptions.next().unwrap_or_default();
+    }
+
+    let mut raw = Vec::with_capacity(items);
+    let (mut value, mut last_bit) = (0u32, 0u32);
+    for gap in bits {
+        if gap == 0 {
+            let previous = raw.last().copied().unwrap_or_default();
+            raw.push(value ^ previous);
+            (value, last_bit) = (0, 0);
+        } else {
+            last_bit += u32::from(gap);
+            if last_bit > 32 {
+                return Err(anyhow!("fingerprint bit out of range"));
+            }
+            value |= 1 << (last_bit - 1);
+        }
+    }
+    Ok((algorithm, raw))
+}
+
+/// Share of differing bits where `a` and `b` overlap best, trying to shift one against the
+/// other by up to `max_offset` items. `None` when they overlap less than `min_overlap` items
+pub fn bit_error_rate(a: &[u32], b: &[u32], max_offset: usize, min_overlap: usize) -> Option<f64> {
+    let offsets = -(max_offset.min(b.len()) as isize)..=(max_offset.min(a.len()) as isize);
+    offsets
+        .filter_map(|offset| {
+            let (a, b) = if offset >= 0 {
+                (&a[offset as usize..], b)
+            } else {
+                (a, &b[offset.unsigned_abs()..])
+            };
+            let overlap = a.len().min(b.len());
+            if overlap < min_overlap.max(1) {
+                return None;
+            }
+            let errors: u32 = a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum();
+            Some(f64::from(errors) / (overlap * 32) as f64)
+        })
+        .min_by(f64::total_cmp)
+}
@@ -0,0 +1,145 @@
+//! Record of what `rename-files` changed so a run can be undone
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Context, Error, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::acoustid::{restore_tag_snapshot, TagSnapshot};
+
+/// One tagged (and maybe renamed or trashed) file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub original: PathBuf,
+    /// `None` when the tag was written but renaming failed
+    pub renamed: Option<PathBuf>,
+    /// Tag frames before they were overwritten
+    pub previous: TagSnapshot,
+    /// Sent to the trash to make room, or the file itself when the other copy was kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trashed: Option<PathBuf>,
+}
+
+/// Append only JSON lines file, one [`Entry`] per line, safe to share between threads
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Journal {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("failed to create journal {path:?}"))?;
+        Ok(Journal {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends and flushes right away, a crashed run can still be undone
+    pub fn record(&self, entry: &Entry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.write_all(&line)
+            .and_then(|_| file.flush())
+            .with_context(|| format!("failed to write journal {:?}", self.path))
+    }
+}
+
+pub fn read(path: impl AsRef<Path>) -> Result<Vec<Entry>> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("couldn't open journal {path:?}"))?;
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|(i, line)| {
+            serde_json::from_str(&line?)
+                .with_context(|| format!("{}:{} is not a journal entry", path.display(), i + 1))
+        })
+        .collect()
+}
+
+fn undo_entry(entry: &Entry) -> Result<PathBuf> {
+    if let Some(renamed) = &entry.renamed {
+        if renamed != &entry.original && renamed.exists() {
+            if entry.original.exists() {
+                return Err(anyhow!(
+                    "can't move {renamed:?} back, {:?} exists",
+                    entry.original
+                ));
+            }
+            fs::rename(renamed, &entry.original)
+                .with_context(|| format!("moving {renamed:?} back failed"))?;
+        }
+    }
+    if let Some(trashed) = &entry.trashed {
+        untrash(trashed)?;
+    }
+    restore_tag_snapshot(&entry.original, &entry.previous)
+        .with_context(|| format!("restoring tag of {:?} failed", entry.original))?;
+    Ok(entry.original.clone())
+}
+
+/// Puts the file last trashed from `path` back
+#[cfg(any(
+    target_os = "windows",
+    all(
+        unix,
+        not(target_os = "macos"),
+        not(target_os = "ios"),
+        not(target_os = "android")
+    )
+))]
+fn untrash(path: &Path) -> Result<()> {
+    let item = trash::os_limited::list()
+        .context("listing the trash failed")?
+        .into_iter()
+        .filter(|x| x.original_path() == path)
+        .max_by_key(|x| x.time_deleted)
+        .ok_or(anyhow!("{path:?} is not in the trash"))?;
+    trash::os_limited::restore_all([item])
+        .with_context(|| format!("restoring {path:?} from the trash failed"))
+}
+
+#[cfg(not(any(
+    target_os = "windows",
+    all(
+        unix,
+        not(target_os = "macos"),
+        not(target_os = "ios"),
+        not(target_os = "android")
+    )
+)))]
+fn untrash(path: &Path) -> Result<()> {
+    Err(anyhow!("put {path:?} back from the trash by hand"))
+}
+
+/// Moves files back to their original names, takes trashed ones out of the trash and restores
+/// their tags, newest entry first
+pub fn undo(path: impl AsRef<Path>) -> Result<(Vec<PathBuf>, Vec<Error>)> {
+    let mut restored = vec![];
+    let mut errors = vec![];
+    for entry in read(path)?.iter().rev() {
+        match undo_entry(entry) {
+            Ok(original) => restored.push(original),
+            Err(err) => errors.push(err),
+        }
+    }
+    Ok((restored, errors))
+}
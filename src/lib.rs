@@ -1,26 +1,38 @@
 pub mod acoustid;
-mod cache;
+pub mod audio_hash;
+pub mod cache;
+pub mod dupes;
+pub mod duration;
+pub mod fingerprint;
+pub mod journal;
+pub mod ratings;
+pub mod rename;
+pub mod tags;
 
+use audio_hash::audio_hash;
 use cache::Db;
 use clap::builder::OsStr;
 use twox_hash::XxHash64;
 use walkdir::WalkDir;
 
 use std::{
+    cell::OnceCell,
     fmt::Display,
     fs::{self, File},
-    hash::{BuildHasher, BuildHasherDefault},
-    io::BufReader,
+    hash::{BuildHasher, BuildHasherDefault, Hasher},
+    io::{BufRead, BufReader},
     path::{Path, PathBuf},
     time::Duration,
 };
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use rodio::{Decoder, Source};
 
 #[derive(Debug, Clone)]
 pub struct AcoustId(String);
 
+/// Hash of a song's audio with its tags left out, everything cached about a song is keyed by
+/// it, or of all the file's bytes
 #[derive(Debug, Clone)]
 pub struct FileHash(String);
 impl Display for FileHash {
@@ -28,23 +40,65 @@ impl Display for FileHash {
         write!(f, "{}", self.0)
     }
 }
+impl From<&str> for FileHash {
+    fn from(hash: &str) -> Self {
+        FileHash(hash.to_owned())
+    }
+}
 impl AsRef<[u8]> for FileHash {
     fn as_ref(&self) -> &[u8] {
         self.0.as_ref()
     }
 }
 
+impl AcoustId {
+    /// The 32 bit items chromaprint compressed into this fingerprint
+    pub fn raw(&self) -> Result<Vec<u32>> {
+        Ok(fingerprint::decode(&self.0)?.1)
+    }
+}
+
+impl From<&str> for AcoustId {
+    fn from(fingerprint: &str) -> Self {
+        AcoustId(fingerprint.to_owned())
+    }
+}
+
 impl Display for AcoustId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.0)
     }
 }
 
-#[derive(Debug, Default)]
+/// How much of a song is fingerprinted by default, AcoustID only looks at the first two minutes
+pub const DEFAULT_MAX_ANALYSED: Duration = Duration::from_secs(120);
+
+/// Chromaprint's default algorithm, the one AcoustID expects
+pub const FINGERPRINT_ALGORITHM: u32 = 2;
+
+/// Frames, one sample of every channel, handed to chromaprint at once
+const CHUNK_FRAMES: usize = 4096;
+
+#[derive(Debug)]
 pub struct Song {
     pub path: PathBuf,
     acoustid: Option<AcoustId>,
-    cache_acoustid: Db,
+    /// The user's cache unless another one is given, opened on first use
+    cache_acoustid: OnceCell<Db>,
+    max_analysed: Duration,
+    hash: OnceCell<FileHash>,
+}
+
+impl Default for Song {
+    fn default() -> Self {
+        Song {
+            path: PathBuf::default(),
+            acoustid: None,
+            cache_acoustid: OnceCell::from(Db::default()),
+            max_analysed: DEFAULT_MAX_ANALYSED,
+            hash: OnceCell::new(),
+        }
+    }
 }
 
 impl Display for Song {
@@ -65,41 +119,100 @@ impl Song {
         Ok(Song {
             path: path.canonicalize()?.to_path_buf(),
             acoustid: None,
-            cache_acoustid: Db::new(),
+            cache_acoustid: OnceCell::new(),
+            max_analysed: DEFAULT_MAX_ANALYSED,
+            hash: OnceCell::new(),
         })
     }
 
+    /// Cache in `db` instead of the user's data dir
+    pub fn with_cache(mut self, db: Db) -> Self {
+        self.cache_acoustid = OnceCell::from(db);
+        self
+    }
+
+    fn cache(&self) -> &Db {
+        self.cache_acoustid.get_or_init(Db::new)
+    }
+
+    /// Fingerprint only the first `length` of the song
+    pub fn with_max_analysed(mut self, length: Duration) -> Self {
+        self.max_analysed = length;
+        self
+    }
+
+    /// Length of the song, from its headers when they tell or else by decoding it all
     pub fn get_duration(&self) -> Result<Duration> {
         let hash = self.hash()?;
-        if let Some(x) = self.cache_acoustid.get_duration(&hash) {
+        if let Some(x) = self.cache().get_duration(&hash) {
             return Ok(x);
         }
-        // HACK: decoding samples to get song length https://github.com/RustAudio/rodio/issues/190
-        let (sample_rate, channels, samples) = self.get_raw_samples()?;
-        let seconds: u64 = samples.len() as u64 / channels as u64 / sample_rate as u64;
-        let res = Duration::from_secs(seconds);
-        self.cache_acoustid.insert_duration(&hash, res);
+        let res = match duration::from_headers(&self.path) {
+            Ok(Some(x)) => x,
+            _ => self.decoded_duration()?,
+        };
+        self.cache().insert_duration(&hash, res);
         Ok(res)
     }
 
-    pub fn get_raw_samples(&self) -> Result<(u32, u32, Vec<i16>)> {
+    /// Bits per second over the whole file, tags included
+    pub fn bitrate(&self) -> Result<f64> {
+        let bits = fs::metadata(&self.path)?.len() as f64 * 8.0;
+        Ok(bits / self.get_duration()?.as_secs_f64().max(1.0))
+    }
+
+    /// Counts every decoded sample, slow but works whatever the headers say
+    fn decoded_duration(&self) -> Result<Duration> {
+        let decoder = self.decode()?;
+        let sample_rate = decoder.sample_rate().max(1);
+        let channels = u64::from(decoder.channels()).max(1);
+        let frames = decoder.count() as u64 / channels;
+        Ok(Duration::from_secs_f64(
+            frames as f64 / f64::from(sample_rate),
+        ))
+    }
+
+    /// Decoder yielding the song's interleaved samples one at a time
+    pub fn decode(&self) -> Result<Decoder<BufReader<File>>> {
         let file = BufReader::new(
             File::open(&self.path)
                 .with_context(|| format!("opening song {:?}", self.path.clone()))?,
         );
-        let decoder = Decoder::new(BufReader::new(file))
-            .with_context(|| format!("couldn't decode {}", self.path.display()))?;
-
-        let sample_rate = decoder.sample_rate();
-        let channels = decoder.channels().into();
+        Decoder::new(file).with_context(|| format!("couldn't decode {}", self.path.display()))
+    }
 
-        Ok((sample_rate, channels, decoder.collect()))
+    /// Audio hash, taken from the cache while the file's size and mtime stay the same
+    pub fn hash(&self) -> Result<FileHash> {
+        if let Some(hash) = self.hash.get() {
+            return Ok(hash.clone());
+        }
+        let metadata = fs::metadata(&self.path)
+            .with_context(|| format!("reading metadata of {}", self.path.display()))?;
+        let (size, modified) = (metadata.len(), metadata.modified()?);
+        let hash = match self.cache().get_audio_hash(&self.path, size, modified) {
+            Some(hash) => hash,
+            None => {
+                let hash = audio_hash(&self.path)?;
+                self.cache()
+                    .insert_audio_hash(&self.path, size, modified, &hash);
+                hash
+            }
+        };
+        Ok(self.hash.get_or_init(|| hash).clone())
     }
 
-    fn hash(&self) -> Result<FileHash> {
-        let data = fs::read(&self.path)?;
-        let hasher: BuildHasherDefault<XxHash64> = Default::default();
-        Ok(FileHash(hasher.hash_one(data).to_string()))
+    /// Content hash of the whole file, tags included, indexed like the audio hash
+    pub fn file_hash(&self) -> Result<FileHash> {
+        let metadata = fs::metadata(&self.path)
+            .with_context(|| format!("reading metadata of {}", self.path.display()))?;
+        let (size, modified) = (metadata.len(), metadata.modified()?);
+        if let Some(hash) = self.cache().get_file_hash(&self.path, size, modified) {
+            return Ok(hash);
+        }
+        let hash = hash_file(&self.path, size)?;
+        self.cache()
+            .insert_file_hash(&self.path, size, modified, &hash);
+        Ok(hash)
     }
 
     pub fn calc_acoustid(&mut self) -> Result<AcoustId> {
@@ -108,10 +221,29 @@ impl Song {
         // E.g. if sample_rate is  44100 and has 2 audio channels. It is expected that samples
         // are interleaved: in this case left channel samples are placed at even indices
         // and right channel - at odd ones.
-        let (sample_rate, num_channels, samples) = self.get_raw_samples()?;
+        let decoder = self.decode()?;
+        let sample_rate = decoder.sample_rate();
+        let channels = usize::from(decoder.channels());
+        if channels == 0 {
+            return Err(anyhow!("{} has no audio channels", self.path.display()));
+        }
         let mut ctx = chromaprint_native::Context::new();
-        ctx.start(sample_rate.try_into()?, num_channels.try_into()?)?;
-        ctx.feed(&samples)?;
+        ctx.start(sample_rate.try_into()?, channels.try_into()?)?;
+
+        // chromaprint wants whole frames, every channel of a sample, in each feed
+        let frames = self.max_analysed.as_millis() * u128::from(sample_rate) / 1000;
+        let limit = usize::try_from(frames)?.saturating_mul(channels);
+        let chunk_len = CHUNK_FRAMES * channels;
+        let mut chunk = Vec::with_capacity(chunk_len);
+        for sample in decoder.take(limit) {
+            chunk.push(sample);
+            if chunk.len() == chunk_len {
+                ctx.feed(&chunk)?;
+                chunk.clear();
+            }
+        }
+        let whole = chunk.len() - chunk.len() % channels;
+        ctx.feed(&chunk[..whole])?;
         ctx.finish()?;
 
         let acoustid = AcoustId(ctx.fingerprint()?);
@@ -120,7 +252,7 @@ impl Song {
 
     pub fn get_acoustid(&mut self) -> Result<AcoustId> {
         let hash = self.hash()?;
-        let acoustid = self.cache_acoustid.get_acoustid(&hash);
+        let acoustid = self.cache().get_acoustid(&hash, self.max_analysed);
 
         match acoustid {
             Some(acoustid) => {
@@ -129,13 +261,48 @@ impl Song {
             }
             None => {
                 let acoustid = self.calc_acoustid()?;
-                let _dont_care = self.cache_acoustid.insert_acoustid(&hash, acoustid.clone());
+                let _dont_care =
+                    self.cache()
+                        .insert_acoustid(&hash, self.max_analysed, acoustid.clone());
                 Ok(acoustid)
             }
         }
     }
 }
 
+/// XxHash64 of the file read a buffer at a time, the same as hashing all its bytes at once
+fn hash_file(path: &Path, size: u64) -> Result<FileHash> {
+    let mut reader = BufReader::with_capacity(
+        64 * 1024,
+        File::open(path).with_context(|| format!("opening {}", path.display()))?,
+    );
+    let mut hasher = BuildHasherDefault::<XxHash64>::default().build_hasher();
+    // `Hash` for a byte slice writes its length first
+    hasher.write_usize(size as usize);
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        hasher.write(buf);
+        let len = buf.len();
+        reader.consume(len);
+    }
+    Ok(FileHash(hasher.finish().to_string()))
+}
+
+/// Extensions of the files treated as songs
+pub const AUDIO_EXTENSIONS: [&str; 9] = [
+    "mp3", "flac", "ogg", "oga", "opus", "wav", "m4a", "m4b", "mp4",
+];
+
+/// Whether `path` has the extension of a song, in any case
+pub fn is_audio(path: &Path) -> bool {
+    path.extension()
+        .map(|x| x.to_string_lossy().to_lowercase())
+        .is_some_and(|x| AUDIO_EXTENSIONS.contains(&x.as_str()))
+}
+
 pub fn mp3_files<P: AsRef<Path>>(path: P) -> Vec<PathBuf> {
     WalkDir::new(path)
         .into_iter()
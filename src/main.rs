@@ -3,16 +3,30 @@
 
 mod cli;
 use anyhow::{Context, Result};
-use cli::{classify_music, read_files_from_stdin, rename_music_files};
-use risto::mp3_files;
-use std::path::{Path, PathBuf};
+use cli::{
+    cache, classify_music, find_dupes, match_library, read_files_from_stdin, rename_music_files,
+    tag_from_filename,
+};
+use risto::{
+    acoustid::{ArtistPolicy, CacheMode, Client, ClientConfig},
+    cache::Db,
+    dupes::{self, Keep, Log},
+    is_audio, mp3_files, ratings,
+    rename::{CollisionPolicy, Naming, Profile, Template, DEFAULT_TEMPLATE},
+    DEFAULT_MAX_ANALYSED,
+};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use termimad::{
     crossterm::style::{Attribute::Underlined, Color::DarkYellow},
     minimad::TextTemplate,
     MadSkin,
 };
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 
 #[derive(Parser, Debug)]
 #[command(name = "risto")]
@@ -34,16 +48,243 @@ enum Commands {
         /// Path to folder with music
         #[arg(value_name = "PATH")]
         music_dir: Option<PathBuf>,
+        /// Ask for comma separated labels of every song that's kept, e.g. a mood or "set opener"
+        #[arg(long)]
+        labels: bool,
+        /// Write stars and labels into the songs, as ID3 POPM and TXXX frames or `RATING` and
+        /// `LABELS` comments
+        #[arg(long)]
+        write_tags: bool,
+    },
+    /// Move the verdicts of a `likes.json` written by older versions of `listen` into the
+    /// database
+    ImportLikes {
+        #[arg(value_name = "FILE", default_value = classify_music::LEGACY_LIKES)]
+        file: PathBuf,
     },
-    /// Rename music files with lookup acoustid id3
+    /// Rename music files with lookup acoustid, tags mp3 (id3), flac, ogg, opus and m4a files
     RenameFiles {
         /// Path to folder with music
         #[arg(value_name = "PATH")]
         /// file or folder to calculate acoustid or instead a list of files via STDIN
         path: Option<PathBuf>,
+        /// AcoustID web service base url, defaults to env var ACOUSTID_API_URL or api.acoustid.org
+        #[arg(long, value_name = "URL")]
+        acoustid_url: Option<String>,
+        /// How many fingerprints are sent to AcoustID in a single request
+        #[arg(long, default_value_t = 10)]
+        batch_size: usize,
+        /// Days a cached AcoustID response stays valid, up to a hundred years
+        #[arg(
+            long,
+            value_name = "DAYS",
+            default_value_t = 30,
+            value_parser = clap::value_parser!(u64).range(..=36_500)
+        )]
+        cache_ttl: u64,
+        /// Ask AcoustID again and overwrite the cached responses
+        #[arg(long, conflicts_with = "no_cache")]
+        refresh: bool,
+        /// Don't read nor write cached AcoustID responses
+        #[arg(long)]
+        no_cache: bool,
+        /// Choose among the AcoustID candidates instead of taking the best scored one
+        #[arg(short, long)]
+        interactive: bool,
+        /// Ignore AcoustID matches scoring less than this, between 0 and 1
+        #[arg(long, value_name = "SCORE", default_value_t = 0.0)]
+        min_score: f64,
+        /// Seconds at the start of every song that are fingerprinted, longer is slower and
+        /// rarely matches better
+        #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_MAX_ANALYSED.as_secs())]
+        max_analysed: u64,
+        /// Print planned tag changes and renames without touching any file
+        #[arg(long)]
+        dry_run: bool,
+        /// Where to record the changes, defaults to `rename-journal-<timestamp>.jsonl`
+        #[arg(long, value_name = "FILE")]
+        journal: Option<PathBuf>,
+        /// How credited artists are written into artist and album artist
+        #[arg(long, value_enum, default_value_t = ArtistPolicy::Joined)]
+        artist_policy: ArtistPolicy,
+        /// Where renamed files go relative to their folder, e.g.
+        /// `{album_artist|artist}/[{year} - ]{album}/{track:02} {title}`.
+        /// `{field:02}` zero-pads, `{a|b|"text"}` falls back, `[...]` is left out when a field
+        /// inside is missing, `/` makes folders
+        #[arg(long, value_name = "TEMPLATE", default_value = DEFAULT_TEMPLATE)]
+        template: String,
+        /// Which filesystems new names must be valid on
+        #[arg(long, value_enum, default_value_t = Profile::Windows)]
+        sanitize: Profile,
+        /// Longest file or folder name in bytes, longer names are cut
+        #[arg(long, value_name = "BYTES", default_value_t = 255)]
+        max_name_len: usize,
+        /// What to do when the new name belongs to another file
+        #[arg(long, value_enum, default_value_t = CollisionPolicy::Suffix)]
+        on_collision: CollisionPolicy,
+        /// Rename after the tags files already have, without fingerprinting nor AcoustID
+        #[arg(
+            long,
+            conflicts_with_all = [
+                "acoustid_url",
+                "batch_size",
+                "cache_ttl",
+                "refresh",
+                "no_cache",
+                "interactive",
+                "min_score",
+                "max_analysed",
+                "artist_policy",
+            ]
+        )]
+        from_tags: bool,
+    },
+    /// Write tags read from file names like "Artist - Title.mp3", without network
+    #[command(arg_required_else_help = true)]
+    TagFromFilename {
+        /// file or folder to tag or instead a list of files via STDIN
+        #[arg(value_name = "PATH")]
+        path: Option<PathBuf>,
+        /// How names are read, same fields as rename-files --template, `/` reads folders too,
+        /// e.g. `{artist}/{album}/{track} {title}`
+        #[arg(long, value_name = "PATTERN", default_value = DEFAULT_TEMPLATE)]
+        pattern: String,
+        /// Print the tags that would be written without touching any file
+        #[arg(long)]
+        dry_run: bool,
+        /// Where to record the changes, defaults to `rename-journal-<timestamp>.jsonl`
+        #[arg(long, value_name = "FILE")]
+        journal: Option<PathBuf>,
+    },
+    /// Find songs with the same audio, e.g. an mp3 and a flac of the same recording
+    #[command(arg_required_else_help = true)]
+    Dupes {
+        /// file or folder to search or instead a list of files via STDIN
+        #[arg(value_name = "PATH")]
+        path: Option<PathBuf>,
+        #[command(flatten)]
+        matching: Matching,
+        /// Ask which copy of every song to keep, copies can be played first, the others are
+        /// moved to trash
+        #[arg(short, long, conflicts_with = "keep")]
+        interactive: bool,
+        /// Keep one copy of every song without asking and move the others to trash:
+        /// `best-quality` (lossless, then bitrate), `largest`, `oldest` or `in-dir=<PATH>`,
+        /// the folder follows an `=`, e.g. `--keep in-dir=~/Music`
+        #[arg(long, value_name = "POLICY", value_parser = Keep::from_str)]
+        keep: Option<Keep>,
+        /// Where to record the trashed copies, defaults to `dupes-log-<timestamp>.jsonl`
+        #[arg(long, value_name = "FILE")]
+        log: Option<PathBuf>,
+    },
+    /// Tell which incoming songs are already in the library, by their audio
+    #[command(arg_required_else_help = true)]
+    Match {
+        /// folder of songs already owned
+        #[arg(value_name = "LIBRARY")]
+        library: PathBuf,
+        /// file or folder of new songs or instead a list of files via STDIN
+        #[arg(value_name = "INCOMING")]
+        incoming: Option<PathBuf>,
+        #[command(flatten)]
+        matching: Matching,
+    },
+    /// Inspect and maintain the cache of durations, fingerprints and AcoustID responses
+    #[command(arg_required_else_help = true)]
+    Cache {
+        /// Cache database to use instead of the one in the user's data dir
+        #[arg(long, value_name = "DIR", global = true)]
+        dir: Option<PathBuf>,
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+    /// Restore names and tags of files changed by rename-files or tag-from-filename
+    #[command(arg_required_else_help = true)]
+    Undo {
+        /// Journal written by rename-files or tag-from-filename
+        #[arg(value_name = "JOURNAL")]
+        journal: PathBuf,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum CacheCommands {
+    /// Where the cache is, its size and how many records it holds
+    Stats,
+    /// Every file hashed so far with its duration, fingerprint and lookup, tab separated
+    Ls,
+    /// What's cached for songs
+    #[command(arg_required_else_help = true)]
+    Get {
+        /// file or folder or instead a list of files via STDIN
+        #[arg(value_name = "PATH")]
+        path: Option<PathBuf>,
+    },
+    /// Drop the records of files that aren't in any of the given folders anymore
+    #[command(arg_required_else_help = true)]
+    Prune {
+        /// Folders with all the music that's still around
+        #[arg(value_name = "ROOT", required = true)]
+        roots: Vec<PathBuf>,
+        /// Don't ask first
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Drop every record
+    Clear {
+        /// Don't ask first
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Write every record as JSON
+    Export {
+        /// File to write, defaults to STDOUT
+        #[arg(value_name = "FILE")]
+        file: Option<PathBuf>,
+    },
+    /// Add the records of a JSON export, replacing the ones already cached
+    #[command(arg_required_else_help = true)]
+    Import {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
     },
 }
 
+/// How dupes and match fingerprint and compare songs
+#[derive(Debug, Args)]
+struct Matching {
+    /// Share of differing fingerprint bits up to which songs are the same, between 0 and 1,
+    /// unrelated songs are around 0.5
+    #[arg(
+        long,
+        value_name = "RATE",
+        default_value_t = dupes::Options::default().max_bit_error_rate
+    )]
+    max_bit_error_rate: f64,
+    /// Songs whose lengths differ more than these seconds are never the same
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = dupes::Options::default().max_length_diff.as_secs()
+    )]
+    max_length_diff: u64,
+    /// Seconds at the start of every song that are fingerprinted
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_MAX_ANALYSED.as_secs())]
+    max_analysed: u64,
+}
+
+impl Matching {
+    fn options(&self) -> find_dupes::Options {
+        find_dupes::Options {
+            max_analysed: Duration::from_secs(self.max_analysed),
+            dupes: dupes::Options {
+                max_bit_error_rate: self.max_bit_error_rate,
+                max_length_diff: Duration::from_secs(self.max_length_diff),
+            },
+        }
+    }
+}
+
 fn shellexpand_or_read_files_from_stdin(path: Option<&Path>) -> Result<Vec<PathBuf>> {
     match path {
         Some(path) => {
@@ -59,6 +300,27 @@ fn shellexpand_or_read_files_from_stdin(path: Option<&Path>) -> Result<Vec<PathB
     }
 }
 
+/// `<name>-<timestamp>.jsonl` in the current folder
+/// Like `shellexpand_or_read_files_from_stdin`, leaving out covers, playlists and such
+fn audio_files(path: Option<&Path>) -> Result<Vec<PathBuf>> {
+    let files = shellexpand_or_read_files_from_stdin(path)?;
+    Ok(files.into_iter().filter(|x| is_audio(x)).collect())
+}
+
+fn timestamped(name: &str) -> PathBuf {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    PathBuf::from(format!("{name}-{}.jsonl", now.as_secs()))
+}
+
+fn print_errors(errors: Vec<anyhow::Error>) {
+    eprintln!("\n# Errors:");
+    for err in errors {
+        eprintln!("- {err:?}");
+    }
+}
+
 fn main() -> Result<()> {
     let mut skin = MadSkin::default();
     skin.bold.set_fg(DarkYellow);
@@ -73,21 +335,224 @@ fn main() -> Result<()> {
     let args = Cli::parse();
 
     match args.command {
-        Commands::Listen { music_dir } => {
-            classify_music::keep_asking(&skin, music_dir.as_ref())?;
+        Commands::Listen {
+            music_dir,
+            labels,
+            write_tags,
+        } => {
+            let options = classify_music::Options { labels, write_tags };
+            classify_music::keep_asking(&skin, music_dir.as_ref(), &options)?;
+        }
+        Commands::ImportLikes { file } => {
+            let (imported, gone, errors) = ratings::import_likes(&Db::new(), &file)?;
+            eprintln!("\n# Imported {imported} verdicts from {}", file.display());
+            if gone > 0 {
+                eprintln!("{gone} songs are gone, their verdicts are skipped");
+            }
+            if errors.is_empty() {
+                eprintln!("{} can be deleted now", file.display());
+            } else {
+                print_errors(errors);
+            }
         }
-        Commands::RenameFiles { path } => {
+        Commands::RenameFiles {
+            path,
+            acoustid_url,
+            batch_size,
+            cache_ttl,
+            refresh,
+            no_cache,
+            interactive,
+            min_score,
+            max_analysed,
+            dry_run,
+            journal,
+            artist_policy,
+            template,
+            sanitize,
+            max_name_len,
+            on_collision,
+            from_tags,
+        } => {
+            let template: Template = shellexpand::tilde(&template).parse()?;
+            let journal = journal.unwrap_or_else(|| timestamped("rename-journal"));
             let files = shellexpand_or_read_files_from_stdin(path.as_deref())?;
-            let (newfiles, errors) = rename_music_files::as_title_artist(&files)?;
+            let options = rename_music_files::Options {
+                batch_size,
+                interactive,
+                min_score,
+                max_analysed: Duration::from_secs(max_analysed),
+                dry_run,
+                journal: journal.clone(),
+                artist_policy,
+                naming: Naming {
+                    template,
+                    profile: sanitize,
+                    max_name_len,
+                    collisions: on_collision,
+                },
+            };
 
-            eprintln!("\n# Ok:");
-            for newfile in newfiles {
-                eprintln!("- {}", newfile.display());
+            let mut client = None;
+            let (outcomes, errors) = if from_tags {
+                rename_music_files::from_tags(&skin, &files, &options)?
+            } else {
+                let mut config = ClientConfig::from_env()?;
+                if let Some(acoustid_url) = acoustid_url {
+                    config.base_url = acoustid_url;
+                }
+                config.cache_ttl = Duration::from_secs(cache_ttl * 24 * 60 * 60);
+                config.cache = match (refresh, no_cache) {
+                    (_, true) => CacheMode::Bypass,
+                    (true, _) => CacheMode::Refresh,
+                    _ => CacheMode::Use,
+                };
+                let client = client.insert(Client::new(config)?);
+                rename_music_files::as_title_artist(&skin, client, &files, &options)?
+            };
+
+            eprintln!("\n# {}:", if dry_run { "Planned" } else { "Ok" });
+            for outcome in outcomes {
+                eprintln!("- {outcome}");
+            }
+            print_errors(errors);
+            if let Some(client) = client {
+                let stats = client.stats();
+                eprintln!(
+                    "\n# AcoustID: {} requests, {} retried, {} dropped",
+                    stats.requests, stats.retried, stats.dropped
+                );
+            }
+            if !dry_run {
+                eprintln!("\nUndo with `risto undo {}`", journal.display());
+            }
+        }
+        Commands::TagFromFilename {
+            path,
+            pattern,
+            dry_run,
+            journal,
+        } => {
+            let journal = journal.unwrap_or_else(|| timestamped("rename-journal"));
+            let files = shellexpand_or_read_files_from_stdin(path.as_deref())?;
+            let (tagged, errors) = tag_from_filename::tag_from_filename(
+                &skin,
+                &files,
+                &tag_from_filename::Options {
+                    pattern: pattern.parse()?,
+                    dry_run,
+                    journal: journal.clone(),
+                },
+            )?;
+
+            eprintln!("\n# {}:", if dry_run { "Planned" } else { "Ok" });
+            for file in tagged {
+                eprintln!("- {}", file.display());
+            }
+            print_errors(errors);
+            if !dry_run {
+                eprintln!("\nUndo with `risto undo {}`", journal.display());
             }
-            eprintln!("\n# Errors:");
-            for err in errors {
-                eprintln!("- {err:?}");
+        }
+        Commands::Dupes {
+            path,
+            matching,
+            interactive,
+            keep,
+            log,
+        } => {
+            let files = audio_files(path.as_deref())?;
+            let options = matching.options();
+            let (groups, mut errors) = find_dupes::find_dupes(&files, &options);
+            if (interactive || keep.is_some()) && !groups.is_empty() {
+                let mut log = Log::create(log.unwrap_or_else(|| timestamped("dupes-log")))?;
+                let (resolutions, resolve_errors) =
+                    find_dupes::resolve(&skin, &groups, keep.as_ref(), &options.dupes, &mut log);
+                errors.extend(resolve_errors);
+                let trashed: usize = resolutions.iter().map(|x| x.trashed.len()).sum();
+                let unmatched: usize = resolutions.iter().map(|x| x.unmatched.len()).sum();
+                eprintln!(
+                    "\n# {} of {} songs resolved, {trashed} copies trashed, {unmatched} kept for \
+                     not matching the kept copy, see {}",
+                    resolutions.len(),
+                    groups.len(),
+                    log.path().display()
+                );
+            } else {
+                find_dupes::print_groups(&skin, &groups);
+                eprintln!(
+                    "\n# {} songs with duplicates among {} files",
+                    groups.len(),
+                    files.len()
+                );
+            }
+            print_errors(errors);
+        }
+        Commands::Match {
+            library,
+            incoming,
+            matching,
+        } => {
+            let library = audio_files(Some(&library))?;
+            let incoming = audio_files(incoming.as_deref())?;
+            let (matches, errors) =
+                match_library::match_library(&library, &incoming, &matching.options());
+            match_library::print_matches(&skin, &matches);
+            eprintln!(
+                "\n# {} of {} incoming songs already in the library of {} songs",
+                matches.iter().filter(|x| x.found.is_some()).count(),
+                incoming.len(),
+                library.len()
+            );
+            print_errors(errors);
+        }
+        Commands::Cache { dir, command } => {
+            let db = match &dir {
+                Some(dir) => Db::open(dir)?,
+                None => Db::new(),
+            };
+            match command {
+                CacheCommands::Stats => cache::stats(&skin, &db, dir.as_deref())?,
+                CacheCommands::Ls => cache::ls(&db)?,
+                CacheCommands::Get { path } => {
+                    let files = shellexpand_or_read_files_from_stdin(path.as_deref())?;
+                    let errors = cache::get(&skin, &db, &files)?;
+                    if !errors.is_empty() {
+                        print_errors(errors);
+                    }
+                }
+                CacheCommands::Prune { roots, yes } => {
+                    let (pruned, errors) = cache::prune(&skin, &db, &roots, yes)?;
+                    if let Some(pruned) = pruned {
+                        eprintln!("\n# Dropped {pruned}");
+                    }
+                    if !errors.is_empty() {
+                        print_errors(errors);
+                    }
+                }
+                CacheCommands::Clear { yes } => {
+                    if let Some(cleared) = cache::clear(&skin, &db, yes)? {
+                        eprintln!("\n# Dropped {cleared}");
+                    }
+                }
+                CacheCommands::Export { file } => {
+                    let exported = cache::export(&db, file.as_deref())?;
+                    eprintln!("\n# Exported {exported}");
+                }
+                CacheCommands::Import { file } => {
+                    let imported = cache::import(&db, &file)?;
+                    eprintln!("\n# Imported {imported}");
+                }
+            }
+        }
+        Commands::Undo { journal } => {
+            let (restored, errors) = risto::journal::undo(&journal)?;
+
+            eprintln!("\n# Restored:");
+            for file in restored {
+                eprintln!("- {}", file.display());
             }
+            print_errors(errors);
         }
     };
 
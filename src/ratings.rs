@@ -0,0 +1,126 @@
+//! Listening verdicts, kept by audio hash so they follow songs that are moved or renamed
+
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Error, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::Db,
+    tags::{self, Field},
+    Song,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Like {
+    Yes,
+    No,
+    DontKnow,
+    ExtensionNotSupported,
+}
+
+/// What was thought of a song at one time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Verdict {
+    pub like: Like,
+    /// Where the song was when it was judged
+    pub path: PathBuf,
+    /// Since the unix epoch
+    pub at: Duration,
+    /// Stars from 1 to 5
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stars: Option<u8>,
+    /// Free-form labels, e.g. a mood or "set opener"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+}
+
+impl Verdict {
+    pub fn new(like: Like, path: &Path) -> Self {
+        Verdict {
+            like,
+            path: path.to_path_buf(),
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            stars: None,
+            labels: vec![],
+        }
+    }
+
+    pub fn with_rating(mut self, stars: Option<u8>, labels: Vec<String>) -> Self {
+        self.stars = stars;
+        self.labels = labels;
+        self
+    }
+
+    /// Writes the stars and labels into the tag of `path`, as ID3 POPM and TXXX frames or
+    /// `RATING` and `LABELS` comments. Fields without a value are left as they are
+    pub fn write_tag(&self, path: &Path) -> Result<()> {
+        let mut tag = tags::read(path)?;
+        if let Some(stars) = self.stars {
+            tag.set(Field::Rating, vec![stars.to_string()]);
+        }
+        if !self.labels.is_empty() {
+            tag.set(Field::Labels, self.labels.clone());
+        }
+        tag.save(path)
+            .with_context(|| format!("writing the rating of {} failed", path.display()))
+    }
+}
+
+/// A song of the `likes.json` older versions of `risto listen` wrote
+#[derive(Debug, Deserialize)]
+struct LikedSong {
+    path: String,
+    like: Like,
+}
+
+/// Adds the verdicts of a `likes.json`, dated when the file was last written, gives back how
+/// many were added and how many songs were skipped for being gone, e.g. trashed after a "no".
+/// Importing twice adds nothing
+pub fn import_likes(db: &Db, path: &Path) -> Result<(usize, usize, Vec<Error>)> {
+    let file = File::open(path).with_context(|| format!("couldn't load {path:?}"))?;
+    let songs: Vec<LikedSong> = serde_json::from_reader(BufReader::new(file))
+        .with_context(|| format!("{} is not a likes.json", path.display()))?;
+    let at = fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let (mut imported, mut gone) = (0, 0);
+    let mut errors = vec![];
+    for song in songs {
+        if !Path::new(&song.path).exists() {
+            gone += 1;
+            continue;
+        }
+        let verdict = Verdict {
+            like: song.like,
+            path: PathBuf::from(&song.path),
+            at,
+            stars: None,
+            labels: vec![],
+        };
+        let hash = Song::new(&verdict.path)
+            .and_then(|x| x.with_cache(db.clone()).hash())
+            .with_context(|| format!("❌ hashing failed {}", song.path));
+        let res = hash.and_then(|hash| {
+            if db.verdicts(&hash)?.contains(&verdict) {
+                return Ok(false);
+            }
+            db.add_verdict(&hash, verdict)?;
+            Ok(true)
+        });
+        match res {
+            Ok(added) => imported += usize::from(added),
+            Err(err) => errors.push(err),
+        }
+    }
+    Ok((imported, gone, errors))
+}
@@ -0,0 +1,360 @@
+//! Where renamed songs go
+
+mod sanitize;
+mod template;
+
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+
+use crate::{
+    acoustid::{read_tag_snapshot, TagSnapshot},
+    Song,
+};
+
+pub use sanitize::{sanitize, truncate, Profile};
+pub use template::{Template, DEFAULT as DEFAULT_TEMPLATE};
+
+/// What to do when the new name is taken by another file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum CollisionPolicy {
+    /// Leave the file with its old name
+    Skip,
+    /// Append a counter, "Artist - Title (2).mp3"
+    #[default]
+    Suffix,
+    /// Keep the file with the higher bitrate under the new name, the other goes to the trash
+    KeepHigherBitrate,
+}
+
+/// How new file names are made
+#[derive(Debug, Clone)]
+pub struct Naming {
+    pub template: Template,
+    pub profile: Profile,
+    /// Longest file or directory name in bytes, extension included
+    pub max_name_len: usize,
+    pub collisions: CollisionPolicy,
+}
+
+impl Default for Naming {
+    fn default() -> Self {
+        Naming {
+            template: Template::default(),
+            profile: Profile::default(),
+            max_name_len: 255,
+            collisions: CollisionPolicy::default(),
+        }
+    }
+}
+
+/// What happened, or would happen, to one file
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Renamed {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// `wanted` belongs to `taken_by`, the file kept its name
+    Skipped {
+        from: PathBuf,
+        wanted: PathBuf,
+        taken_by: PathBuf,
+    },
+    /// `wanted` was taken, `to` has a counter appended
+    Suffixed {
+        from: PathBuf,
+        to: PathBuf,
+        wanted: PathBuf,
+    },
+    /// `trashed` had a lower bitrate and made room
+    Replaced {
+        from: PathBuf,
+        to: PathBuf,
+        trashed: PathBuf,
+    },
+    /// The file had a lower bitrate than `kept` and went to the trash
+    Trashed {
+        from: PathBuf,
+        kept: PathBuf,
+    },
+}
+
+impl Outcome {
+    /// Where the file ends up, `None` if it wasn't renamed
+    pub fn renamed(&self) -> Option<&Path> {
+        match self {
+            Outcome::Renamed { to, .. }
+            | Outcome::Suffixed { to, .. }
+            | Outcome::Replaced { to, .. } => Some(to),
+            Outcome::Skipped { .. } | Outcome::Trashed { .. } => None,
+        }
+    }
+
+    /// The file that went to the trash, if any
+    pub fn trashed(&self) -> Option<&Path> {
+        match self {
+            Outcome::Replaced { trashed, .. } => Some(trashed),
+            Outcome::Trashed { from, .. } => Some(from),
+            Outcome::Renamed { .. } | Outcome::Skipped { .. } | Outcome::Suffixed { .. } => None,
+        }
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Renamed { from, to } => {
+                write!(f, "`{}` → `{}`", from.display(), to.display())
+            }
+            Outcome::Skipped {
+                from,
+                wanted,
+                taken_by,
+            } => write!(
+                f,
+                "`{}` skipped, `{}` is taken by `{}`",
+                from.display(),
+                wanted.display(),
+                taken_by.display()
+            ),
+            Outcome::Suffixed { from, to, wanted } => write!(
+                f,
+                "`{}` → `{}`, `{}` is taken",
+                from.display(),
+                to.display(),
+                wanted.display()
+            ),
+            Outcome::Replaced { from, to, trashed } => write!(
+                f,
+                "`{}` → `{}`, lower bitrate `{}` trashed",
+                from.display(),
+                to.display(),
+                trashed.display()
+            ),
+            Outcome::Trashed { from, kept } => write!(
+                f,
+                "`{}` trashed, `{}` has a higher bitrate",
+                from.display(),
+                kept.display()
+            ),
+        }
+    }
+}
+
+/// Renames songs after their tags, safe to share between threads
+#[derive(Debug, Default)]
+pub struct Renamer {
+    naming: Naming,
+    /// New names given out by `plan`, with the file that will get them
+    planned: Mutex<HashMap<PathBuf, PathBuf>>,
+    /// One lock per new name `rename` gives, files wanting the same name wait for each other
+    names: Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>,
+}
+
+impl Renamer {
+    pub fn new(naming: Naming) -> Self {
+        Renamer {
+            naming,
+            planned: Mutex::default(),
+            names: Mutex::default(),
+        }
+    }
+
+    pub fn naming(&self) -> &Naming {
+        &self.naming
+    }
+
+    /// `name` cleaned up and cut to fit `max_name_len` with `suffix` and `extension` appended,
+    /// an error when not even a character of it fits
+    fn fit(&self, name: &str, suffix: &str, extension: Option<&OsStr>) -> Result<String> {
+        let profile = self.naming.profile;
+        let extension = extension
+            .map(|x| format!(".{}", x.to_string_lossy()))
+            .unwrap_or_default();
+        let room = self
+            .naming
+            .max_name_len
+            .saturating_sub(suffix.len() + extension.len());
+        let stem = sanitize(truncate(&sanitize(name, profile), room), profile);
+        let stem = truncate(&stem, room);
+        if stem.is_empty() {
+            return Err(anyhow!(
+                "names of {} bytes leave no room for `{name}` before `{suffix}{extension}`",
+                self.naming.max_name_len
+            ));
+        }
+        Ok(format!("{stem}{suffix}{extension}"))
+    }
+
+    /// Directory and file name the template gives for `tags`, relative templates start at the
+    /// song's directory
+    fn components(&self, songfile: &Path, tags: &TagSnapshot) -> Result<(PathBuf, String)> {
+        let dir = songfile.parent().with_context(|| "no parent dir")?;
+        let template = &self.naming.template;
+        let mut components = template.render(tags)?;
+
+        let name = components.pop().unwrap_or_default();
+        if name.trim().is_empty() {
+            return Err(anyhow!("template `{template}` gives an empty file name"));
+        }
+        let mut newdir = if template.is_absolute() {
+            PathBuf::from("/")
+        } else {
+            dir.to_path_buf()
+        };
+        for component in components.iter().filter(|x| !x.is_empty()) {
+            newdir.push(self.fit(component, "", None)?);
+        }
+        Ok((newdir, name))
+    }
+
+    /// Where the template puts `songfile` given its `tags`, names are sanitised and the
+    /// extension is kept
+    pub fn path(&self, songfile: &Path, tags: &TagSnapshot) -> Result<PathBuf> {
+        let (dir, name) = self.components(songfile, tags)?;
+        Ok(dir.join(self.fit(&name, "", songfile.extension())?))
+    }
+
+    /// Decides where `songfile` goes, `taken` tells which file holds a path if any
+    fn resolve(
+        &self,
+        songfile: &Path,
+        tags: &TagSnapshot,
+        taken: impl Fn(&Path) -> Option<PathBuf>,
+    ) -> Result<Outcome> {
+        let from = songfile.to_path_buf();
+        let wanted = self.path(songfile, tags)?;
+        let taken_by = match taken(&wanted) {
+            Some(other) if !is_same_file(&other, songfile) => other,
+            _ => return Ok(Outcome::Renamed { from, to: wanted }),
+        };
+
+        match self.naming.collisions {
+            CollisionPolicy::Skip => Ok(Outcome::Skipped {
+                from,
+                wanted,
+                taken_by,
+            }),
+            CollisionPolicy::Suffix => {
+                let (dir, name) = self.components(songfile, tags)?;
+                let to = (2..)
+                    .map(|n| self.fit(&name, &format!(" ({n})"), songfile.extension()))
+                    .map(|x| x.map(|x| dir.join(x)))
+                    .find(|x| match x {
+                        Ok(x) => taken(x).is_none_or(|other| is_same_file(&other, songfile)),
+                        Err(_) => true,
+                    })
+                    .expect("some counter is free")?;
+                Ok(Outcome::Suffixed { from, to, wanted })
+            }
+            CollisionPolicy::KeepHigherBitrate => {
+                let ours = Song::new(songfile)
+                    .and_then(|x| x.bitrate())
+                    .with_context(|| format!("bitrate of {}", songfile.display()))?;
+                let theirs = Song::new(&taken_by)
+                    .and_then(|x| x.bitrate())
+                    .with_context(|| format!("bitrate of {}", taken_by.display()))?;
+                if ours > theirs {
+                    Ok(Outcome::Replaced {
+                        from,
+                        to: wanted,
+                        trashed: taken_by,
+                    })
+                } else {
+                    Ok(Outcome::Trashed {
+                        from,
+                        kept: taken_by,
+                    })
+                }
+            }
+        }
+    }
+
+    /// What `rename` would do, without touching any file. Planned names count as taken for
+    /// the next files planned by this renamer
+    pub fn plan(&self, songfile: &Path, tags: &TagSnapshot) -> Result<Outcome> {
+        let mut planned = self.planned.lock().expect("renamer lock poisoned");
+        let outcome = self.resolve(songfile, tags, |path| {
+            planned
+                .get(path)
+                .cloned()
+                .or_else(|| path.exists().then(|| path.to_path_buf()))
+        })?;
+        if let Some(to) = outcome.renamed() {
+            planned.insert(to.to_path_buf(), songfile.to_path_buf());
+        }
+        Ok(outcome)
+    }
+
+    /// The lock of the new name `wanted`, the lock of all names is only held to find it
+    fn name_lock(&self, wanted: &Path) -> Arc<Mutex<()>> {
+        let mut names = self.names.lock().expect("renamer lock poisoned");
+        names.entry(wanted.to_path_buf()).or_default().clone()
+    }
+
+    /// Renames `songfile` after its own tags, directories are created as needed
+    pub fn rename(&self, songfile: &Path) -> Result<Outcome> {
+        let tags = read_tag_snapshot(songfile);
+        let name = self.name_lock(&self.path(songfile, &tags)?);
+        // two files must not both see the name as free
+        let _guard = name.lock().expect("renamer lock poisoned");
+        let outcome = self.resolve(songfile, &tags, |path| {
+            path.exists().then(|| path.to_path_buf())
+        })?;
+
+        match &outcome {
+            Outcome::Renamed { from, to } | Outcome::Suffixed { from, to, .. } => {
+                move_file(from, to)?
+            }
+            Outcome::Replaced { from, to, trashed } => {
+                trash::delete(trashed)
+                    .with_context(|| format!("trashing {} failed", trashed.display()))?;
+                move_file(from, to)?;
+            }
+            Outcome::Trashed { from, .. } => {
+                trash::delete(from)
+                    .with_context(|| format!("trashing {} failed", from.display()))?;
+            }
+            Outcome::Skipped { .. } => {}
+        }
+        println!("{outcome}");
+        Ok(outcome)
+    }
+}
+
+/// Whether both paths lead to the same file, hard links and case-insensitive spellings
+/// included. Paths that can't be opened are compared as they are
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    same_file::is_same_file(a, b).unwrap_or_else(|_| a == b)
+}
+
+/// Moves `songfile` to `newfile`, creating directories as needed. A `newfile` that is
+/// `songfile` itself, e.g. only the case changed on a case-insensitive filesystem, is fine,
+/// any other existing file is never overwritten
+pub fn move_file(songfile: &Path, newfile: &Path) -> Result<()> {
+    if songfile == newfile {
+        return Ok(());
+    }
+    if newfile.exists() && !is_same_file(songfile, newfile) {
+        return Err(anyhow!("{} is another file", newfile.display()));
+    }
+    if let Some(dir) = newfile.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("creating {} failed", dir.display()))?;
+    }
+    fs::rename(songfile, newfile).with_context(|| {
+        format!(
+            "renaming {} to {} failed",
+            songfile.display(),
+            newfile.display()
+        )
+    })
+}
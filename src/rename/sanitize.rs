@@ -0,0 +1,65 @@
+//! Turning tag text into file names the filesystem accepts
+
+use clap::ValueEnum;
+use deunicode::deunicode;
+
+/// Which filesystems the names have to work on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Profile {
+    /// Only `/` and null are replaced, fine for ext4, btrfs, APFS...
+    Posix,
+    /// Also replaces `<>:"\|?*`, trailing dots and names like `CON`, safe on NTFS, FAT and SMB
+    #[default]
+    Windows,
+    /// Windows rules on top of transliterating to ASCII, "Motörhead" becomes "Motorhead"
+    Ascii,
+}
+
+const WINDOWS_RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Makes `name` usable as a single path component
+pub fn sanitize(name: &str, profile: Profile) -> String {
+    let name = match profile {
+        Profile::Ascii => deunicode(name),
+        Profile::Posix | Profile::Windows => name.to_owned(),
+    };
+    let forbidden = |c: char| match profile {
+        Profile::Posix => c == '/' || c == '\0',
+        Profile::Windows | Profile::Ascii => {
+            c.is_control() || matches!(c, '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*')
+        }
+    };
+    let name: String = name
+        .chars()
+        .map(|c| if forbidden(c) { '_' } else { c })
+        .collect();
+
+    // leading dots would hide the file
+    let mut name = name.trim().trim_start_matches('.').trim_start().to_owned();
+    if profile != Profile::Posix {
+        name = name.trim_end_matches(['.', ' ']).to_owned();
+        let stem = name.split('.').next().unwrap_or_default();
+        if WINDOWS_RESERVED.contains(&stem.to_uppercase().as_str()) {
+            name.insert(stem.len(), '_');
+        }
+    }
+    if name.is_empty() {
+        name.push('_');
+    }
+    name
+}
+
+/// At most `max_bytes` of `name`, cut at a character boundary
+pub fn truncate(name: &str, max_bytes: usize) -> &str {
+    if name.len() <= max_bytes {
+        return name;
+    }
+    let mut end = max_bytes;
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
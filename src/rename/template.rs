@@ -0,0 +1,394 @@
+//! Rename templates like `{album_artist|artist}/[{year} - ]{album}/{track:02} {title}`
+//!
+//! - `{field}` value of a tag field, the names of [`TagSnapshot`] fields
+//! - `{field:03}` zero-padded to 3 digits
+//! - `{album_artist|artist|"Unknown"}` first field with a value or the quoted text
+//! - `[...]` left out when a field inside has no value
+//! - `/` directory separator, `\` escapes the next character
+
+use std::{fmt::Display, path::Path, str::FromStr};
+
+use anyhow::{anyhow, Context, Error, Result};
+
+use crate::acoustid::TagSnapshot;
+
+pub const DEFAULT: &str = "{artist} - {title}";
+
+const FIELDS: &[&str] = &[
+    "title",
+    "artist",
+    "artists",
+    "album_artist",
+    "album",
+    "track",
+    "total_tracks",
+    "disc",
+    "total_discs",
+    "year",
+    "musicbrainz_recording_id",
+    "musicbrainz_release_id",
+    "musicbrainz_release_group_id",
+    "musicbrainz_artist_id",
+];
+
+fn field_value(tags: &TagSnapshot, name: &str) -> Option<String> {
+    let value = match name {
+        "title" => tags.title.clone(),
+        "artist" => tags.artist.clone(),
+        "artists" => tags.artists.clone(),
+        "album_artist" => tags.album_artist.clone(),
+        "album" => tags.album.clone(),
+        "track" => tags.track.map(|x| x.to_string()),
+        "total_tracks" => tags.total_tracks.map(|x| x.to_string()),
+        "disc" => tags.disc.map(|x| x.to_string()),
+        "total_discs" => tags.total_discs.map(|x| x.to_string()),
+        "year" => tags.year.map(|x| x.to_string()),
+        "musicbrainz_recording_id" => tags.musicbrainz_recording_id.clone(),
+        "musicbrainz_release_id" => tags.musicbrainz_release_id.clone(),
+        "musicbrainz_release_group_id" => tags.musicbrainz_release_group_id.clone(),
+        "musicbrainz_artist_id" => tags.musicbrainz_artist_id.clone(),
+        _ => None,
+    }?;
+    // fields with many values are null separated
+    let value = value.replace('\0', ", ");
+    (!value.trim().is_empty()).then_some(value)
+}
+
+fn set_field_value(tags: &mut TagSnapshot, name: &str, value: &str) -> Result<()> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(anyhow!("empty {name}"));
+    }
+    let text = Some(value.to_owned());
+    // rendered as ", " separated, kept null separated
+    let values = Some(value.split(", ").collect::<Vec<_>>().join("\0"));
+    let number = || {
+        value
+            .parse()
+            .map(Some)
+            .map_err(|_| anyhow!("{name} `{value}` is not a number"))
+    };
+    match name {
+        "title" => tags.title = text,
+        "artist" => tags.artist = text,
+        "artists" => tags.artists = values,
+        "album_artist" => tags.album_artist = text,
+        "album" => tags.album = text,
+        "track" => tags.track = number()?,
+        "total_tracks" => tags.total_tracks = number()?,
+        "disc" => tags.disc = number()?,
+        "total_discs" => tags.total_discs = number()?,
+        "year" => {
+            tags.year = Some(
+                value
+                    .parse()
+                    .map_err(|_| anyhow!("year `{value}` is not a number"))?,
+            )
+        }
+        "musicbrainz_recording_id" => tags.musicbrainz_recording_id = text,
+        "musicbrainz_release_id" => tags.musicbrainz_release_id = text,
+        "musicbrainz_release_group_id" => tags.musicbrainz_release_group_id = text,
+        "musicbrainz_artist_id" => tags.musicbrainz_artist_id = values,
+        _ => return Err(anyhow!("unknown field `{name}`")),
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Choice {
+    Field(String),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Placeholder {
+        choices: Vec<Choice>,
+        pad: Option<usize>,
+    },
+    Optional(Vec<Part>),
+    Separator,
+}
+
+/// Parsed rename template, see the module docs for the syntax
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    source: String,
+    parts: Vec<Part>,
+    /// Starts with `/`, the path doesn't start at the song's directory
+    absolute: bool,
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        DEFAULT.parse().expect("default template is valid")
+    }
+}
+
+impl Display for Template {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+struct Parser<'a> {
+    chars: std::str::Chars<'a>,
+}
+
+impl Parser<'_> {
+    /// Parts up to `end` or the end of the template
+    fn parts(&mut self, end: Option<char>) -> Result<Vec<Part>> {
+        let mut parts = vec![];
+        let mut text = String::new();
+        loop {
+            let Some(c) = self.chars.next() else {
+                return match end {
+                    Some(end) => Err(anyhow!("missing `{end}`")),
+                    None => {
+                        push_text(&mut parts, &mut text);
+                        Ok(parts)
+                    }
+                };
+            };
+            match c {
+                '\\' => text.push(self.chars.next().ok_or(anyhow!("nothing to escape"))?),
+                c if Some(c) == end => {
+                    push_text(&mut parts, &mut text);
+                    return Ok(parts);
+                }
+                '{' => {
+                    push_text(&mut parts, &mut text);
+                    parts.push(self.placeholder()?);
+                }
+                '[' => {
+                    push_text(&mut parts, &mut text);
+                    parts.push(Part::Optional(self.parts(Some(']'))?));
+                }
+                '/' => {
+                    push_text(&mut parts, &mut text);
+                    parts.push(Part::Separator);
+                }
+                '}' | ']' => return Err(anyhow!("unexpected `{c}`")),
+                c => text.push(c),
+            }
+        }
+    }
+
+    fn placeholder(&mut self) -> Result<Part> {
+        let mut inside = String::new();
+        let mut quoted = false;
+        loop {
+            match self.chars.next() {
+                None => return Err(anyhow!("missing `}}`")),
+                Some('"') => {
+                    quoted = !quoted;
+                    inside.push('"');
+                }
+                Some('}') if !quoted => break,
+                Some(c) => inside.push(c),
+            }
+        }
+
+        let (choices, pad) = match inside.rsplit_once(':') {
+            Some((choices, pad)) if !pad.contains('"') => {
+                let pad = pad
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow!("bad padding `{pad}` in `{{{inside}}}`"))?;
+                (choices, Some(pad))
+            }
+            _ => (inside.as_str(), None),
+        };
+        let choices = split_choices(choices)
+            .into_iter()
+            .map(|choice| {
+                let choice = choice.trim();
+                if let Some(text) = choice.strip_prefix('"').and_then(|x| x.strip_suffix('"')) {
+                    return Ok(Choice::Text(text.to_owned()));
+                }
+                if FIELDS.contains(&choice) {
+                    Ok(Choice::Field(choice.to_owned()))
+                } else {
+                    Err(anyhow!(
+                        "unknown field `{choice}`, use one of {}",
+                        FIELDS.join(", ")
+                    ))
+                }
+            })
+            .collect::<Result<_>>()?;
+        Ok(Part::Placeholder { choices, pad })
+    }
+}
+
+/// Splits on `|` outside of quotes
+fn split_choices(choices: &str) -> Vec<String> {
+    let mut split = vec![String::new()];
+    let mut quoted = false;
+    for c in choices.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                split.last_mut().unwrap().push(c);
+            }
+            '|' if !quoted => split.push(String::new()),
+            c => split.last_mut().unwrap().push(c),
+        }
+    }
+    split
+}
+
+fn push_text(parts: &mut Vec<Part>, text: &mut String) {
+    if !text.is_empty() {
+        parts.push(Part::Text(std::mem::take(text)));
+    }
+}
+
+impl FromStr for Template {
+    type Err = Error;
+
+    fn from_str(source: &str) -> Result<Self> {
+        let mut parser = Parser {
+            chars: source.chars(),
+        };
+        let parts = parser
+            .parts(None)
+            .map_err(|err| err.context(format!("bad template `{source}`")))?;
+        Ok(Template {
+            source: source.to_owned(),
+            absolute: parts.first() == Some(&Part::Separator),
+            parts,
+        })
+    }
+}
+
+/// Field that had no value while rendering
+struct Missing(String);
+
+fn render_parts(
+    parts: &[Part],
+    tags: &TagSnapshot,
+    components: &mut Vec<String>,
+) -> Result<(), Missing> {
+    for part in parts {
+        match part {
+            Part::Text(text) => components.last_mut().unwrap().push_str(text),
+            Part::Separator => components.push(String::new()),
+            Part::Placeholder { choices, pad } => {
+                let value = choices.iter().find_map(|choice| match choice {
+                    Choice::Field(name) => field_value(tags, name),
+                    Choice::Text(text) => Some(text.clone()),
+                });
+                let Some(value) = value else {
+                    let Some(Choice::Field(name)) = choices.first() else {
+                        unreachable!("quoted text always has a value")
+                    };
+                    return Err(Missing(name.clone()));
+                };
+                // tag text can't add directories
+                let value = value.replace(['/', '\\'], "-");
+                let value = match pad {
+                    Some(width) => format!("{value:0>width$}"),
+                    None => value,
+                };
+                components.last_mut().unwrap().push_str(&value);
+            }
+            Part::Optional(parts) => {
+                let mut optional = components.clone();
+                if render_parts(parts, tags, &mut optional).is_ok() {
+                    *components = optional;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Template {
+    /// Whether the paths the template gives start at the root
+    pub fn is_absolute(&self) -> bool {
+        self.absolute
+    }
+
+    /// Path components the template gives for `tags`, empty ones are left out of the path
+    pub fn render(&self, tags: &TagSnapshot) -> Result<Vec<String>> {
+        let mut components = vec![String::new()];
+        render_parts(&self.parts, tags, &mut components)
+            .map_err(|Missing(name)| anyhow!("tag {name} missing"))?;
+        Ok(components)
+    }
+}
+
+/// Piece of a template read backwards
+enum Piece<'a> {
+    Text(String),
+    Field(&'a str),
+}
+
+impl Template {
+    /// Reads the fields back out of a file name, and out of its directories when the
+    /// template has `/`. Only text and single field placeholders can be read back
+    pub fn parse_path(&self, songfile: &Path) -> Result<TagSnapshot> {
+        let mut pieces: Vec<Piece> = vec![];
+        for part in &self.parts {
+            let text = match part {
+                Part::Text(text) => text.clone(),
+                Part::Separator => "/".to_owned(),
+                Part::Placeholder { choices, .. } => match choices.as_slice() {
+                    [Choice::Field(name)] => {
+                        if let Some(Piece::Field(_)) = pieces.last() {
+                            return Err(anyhow!("`{self}` needs text between fields"));
+                        }
+                        pieces.push(Piece::Field(name));
+                        continue;
+                    }
+                    _ => return Err(anyhow!("`{self}` has fallbacks, they can't be read back")),
+                },
+                Part::Optional(_) => {
+                    return Err(anyhow!("`{self}` has `[...]`, it can't be read back"))
+                }
+            };
+            match pieces.last_mut() {
+                Some(Piece::Text(last)) => last.push_str(&text),
+                _ => pieces.push(Piece::Text(text)),
+            }
+        }
+
+        // as many directories as the template has
+        let depth = self.parts.iter().filter(|x| **x == Part::Separator).count();
+        let stem = songfile.file_stem().with_context(|| "no file name")?;
+        let mut components: Vec<_> = songfile
+            .parent()
+            .into_iter()
+            .flat_map(|x| x.iter().rev().take(depth))
+            .map(|x| x.to_string_lossy())
+            .collect();
+        components.reverse();
+        components.push(stem.to_string_lossy());
+        let path = components.join("/");
+
+        let no_match = || anyhow!("`{path}` doesn't match `{self}`");
+        let mut tags = TagSnapshot::default();
+        let mut rest = path.as_str();
+        for (i, piece) in pieces.iter().enumerate() {
+            match piece {
+                Piece::Text(text) => {
+                    rest = rest.strip_prefix(text.as_str()).ok_or_else(no_match)?
+                }
+                Piece::Field(name) => {
+                    let end = match pieces.get(i + 1) {
+                        Some(Piece::Text(next)) => rest.find(next.as_str()).ok_or_else(no_match)?,
+                        _ => rest.len(),
+                    };
+                    set_field_value(&mut tags, name, &rest[..end])
+                        .map_err(|err| err.context(no_match()))?;
+                    rest = &rest[end..];
+                }
+            }
+        }
+        if !rest.is_empty() {
+            return Err(no_match());
+        }
+        Ok(tags)
+    }
+}
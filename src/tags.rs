@@ -0,0 +1,112 @@
+//! Reading and writing song tags, whatever the container
+
+mod flac;
+mod id3;
+mod mp4;
+mod ogg;
+mod vorbis_comment;
+
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+
+/// Tag fields risto reads and writes, every format maps them to its own frames, keys or atoms
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Title,
+    /// The whole artist credit, e.g. "A feat. B"
+    Artist,
+    /// Each credited artist on its own
+    Artists,
+    AlbumArtist,
+    Album,
+    Track,
+    TotalTracks,
+    Disc,
+    TotalDiscs,
+    Year,
+    /// The date as the tag has it, e.g. "2015-06-01", written back verbatim
+    Date,
+    MusicBrainzRecordingId,
+    MusicBrainzReleaseId,
+    MusicBrainzReleaseGroupId,
+    MusicBrainzArtistId,
+    /// Stars from 1 to 5
+    Rating,
+    /// Free-form labels, e.g. a mood or "set opener"
+    Labels,
+}
+
+/// A song's tag loaded in memory
+pub trait SongTag {
+    /// Values of `field`, empty when the field is missing
+    fn get(&self, field: Field) -> Vec<String>;
+
+    /// Replaces the values of `field`, no values removes it
+    fn set(&mut self, field: Field, values: Vec<String>);
+
+    /// Writes the tag into `path`, the audio is left as it is
+    fn save(&self, path: &Path) -> Result<()>;
+
+    fn first(&self, field: Field) -> Option<String> {
+        self.get(field).into_iter().next()
+    }
+
+    fn number(&self, field: Field) -> Option<u32> {
+        self.first(field)?.trim().parse().ok()
+    }
+}
+
+/// Bytes taken by the ID3v2 tag `head` starts, footer included, 0 when there's none
+pub(crate) fn id3v2_len(head: &[u8]) -> u64 {
+    if head.len() < 10 || !head.starts_with(b"ID3") {
+        return 0;
+    }
+    // syncsafe, 7 bits a byte
+    let size = head[6..10]
+        .iter()
+        .fold(0u64, |size, x| size << 7 | u64::from(x & 0x7f));
+    let footer = if head[5] & 0x10 != 0 { 10 } else { 0 };
+    10 + size + footer
+}
+
+/// Stars of a rating written as a number, 0-100 scales as some players write them are
+/// turned into stars too
+pub(crate) fn stars(rating: &str) -> Option<u8> {
+    match rating.trim().parse::<u8>().ok()? {
+        0 => None,
+        stars @ 1..=5 => Some(stars),
+        percent => Some(percent.div_ceil(20).min(5)),
+    }
+}
+
+/// Loads the tag of `path`, the format is picked by file extension. Other containers, e.g.
+/// wav or aac, are refused rather than get an ID3 header they don't expect
+pub fn read(path: &Path) -> Result<Box<dyn SongTag>> {
+    let extension = path
+        .extension()
+        .map(|x| x.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let tag: Box<dyn SongTag> = match extension.as_str() {
+        "flac" => Box::new(flac::FlacTag::read(path).with_context(|| "failed to read flac tag")?),
+        "ogg" | "oga" | "opus" => {
+            Box::new(ogg::OggTag::read(path).with_context(|| "failed to read ogg tag")?)
+        }
+        "m4a" | "m4b" | "mp4" => {
+            Box::new(mp4::Mp4Tag::read(path).with_context(|| "failed to read mp4 tag")?)
+        }
+        "mp3" => Box::new(id3::Id3Tag::read(path)),
+        _ => return Err(anyhow!("unsupported format {}", path.display())),
+    };
+    Ok(tag)
+}
+
+/// Writes `data` next to `path` and moves it over, a failed write leaves the song intact
+fn replace_file(path: &Path, data: &[u8]) -> Result<()> {
+    let name = path.file_name().with_context(|| "no file name")?;
+    let tmp = path.with_file_name(format!(".{}.risto-tmp", name.to_string_lossy()));
+    fs::write(&tmp, data).with_context(|| format!("failed to write {}", tmp.display()))?;
+    let permissions = fs::metadata(path)?.permissions();
+    fs::set_permissions(&tmp, permissions)?;
+    fs::rename(&tmp, path).with_context(|| format!("failed to replace {}", path.display()))
+}
@@ -0,0 +1,99 @@
+//! FLAC metadata blocks, see https://xiph.org/flac/format.html#metadata_block
+
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Result};
+
+use super::{replace_file, vorbis_comment::VorbisComments, Field, SongTag};
+
+const STREAMINFO: u8 = 0;
+const VORBIS_COMMENT: u8 = 4;
+const LAST_BLOCK: u8 = 0x80;
+
+struct Block {
+    kind: u8,
+    data: Vec<u8>,
+}
+
+/// Metadata blocks and where the audio frames start
+fn blocks(file: &[u8]) -> Result<(Vec<Block>, usize)> {
+    if !file.starts_with(b"fLaC") {
+        return Err(anyhow!("not a flac file"));
+    }
+    let mut blocks = vec![];
+    let mut pos = 4;
+    loop {
+        let header = file
+            .get(pos..pos + 4)
+            .ok_or(anyhow!("metadata block header truncated"))?;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let data = file
+            .get(pos + 4..pos + 4 + len)
+            .ok_or(anyhow!("metadata block truncated"))?;
+        blocks.push(Block {
+            kind: header[0] & !LAST_BLOCK,
+            data: data.to_vec(),
+        });
+        pos += 4 + len;
+        if header[0] & LAST_BLOCK != 0 {
+            return Ok((blocks, pos));
+        }
+    }
+}
+
+pub struct FlacTag(VorbisComments);
+
+impl FlacTag {
+    pub fn read(path: &Path) -> Result<Self> {
+        let file = fs::read(path)?;
+        let (blocks, _) = blocks(&file)?;
+        let comments = match blocks.iter().find(|x| x.kind == VORBIS_COMMENT) {
+            Some(block) => VorbisComments::parse(&block.data)?.0,
+            None => VorbisComments::default(),
+        };
+        Ok(FlacTag(comments))
+    }
+}
+
+impl SongTag for FlacTag {
+    fn get(&self, field: Field) -> Vec<String> {
+        self.0.get(field)
+    }
+
+    fn set(&mut self, field: Field, values: Vec<String>) {
+        self.0.set(field, values)
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let file = fs::read(path)?;
+        let (mut blocks, audio) = blocks(&file)?;
+        let comment = Block {
+            kind: VORBIS_COMMENT,
+            data: self.0.to_bytes(),
+        };
+        match blocks.iter().position(|x| x.kind == VORBIS_COMMENT) {
+            Some(i) => blocks[i] = comment,
+            // streaminfo has to stay the first block
+            None => {
+                let at = blocks
+                    .iter()
+                    .position(|x| x.kind == STREAMINFO)
+                    .unwrap_or(0);
+                blocks.insert(at + 1, comment);
+            }
+        }
+
+        let mut data = b"fLaC".to_vec();
+        for (i, block) in blocks.iter().enumerate() {
+            if block.data.len() >= 1 << 24 {
+                return Err(anyhow!("metadata block too big"));
+            }
+            let is_last = i + 1 == blocks.len();
+            data.push(block.kind | if is_last { LAST_BLOCK } else { 0 });
+            data.extend(&(block.data.len() as u32).to_be_bytes()[1..]);
+            data.extend(&block.data);
+        }
+        data.extend(&file[audio..]);
+        replace_file(path, &data)
+    }
+}
@@ -0,0 +1,245 @@
+use std::path::Path;
+
+use ::id3::{
+    frame::{ExtendedText, Popularimeter, UniqueFileIdentifier},
+    Tag, TagLike, Timestamp, Version,
+};
+use anyhow::{Context, Result};
+
+use super::{stars, Field, SongTag};
+
+const MUSICBRAINZ_UFID_OWNER: &str = "http://musicbrainz.org";
+const TXXX_RELEASE_ID: &str = "MusicBrainz Album Id";
+const TXXX_RELEASE_GROUP_ID: &str = "MusicBrainz Release Group Id";
+const TXXX_ARTIST_ID: &str = "MusicBrainz Artist Id";
+const TXXX_LABELS: &str = "LABELS";
+/// The POPM user most players read ratings of
+const POPM_USER: &str = "Windows Media Player 9 Series";
+/// POPM rating of 1 to 5 stars
+const POPM_STARS: [u8; 5] = [1, 64, 128, 196, 255];
+
+/// ID3v2 tag of an mp3
+pub struct Id3Tag(Tag);
+
+impl Id3Tag {
+    /// A file without tag reads as an empty tag
+    pub fn read(path: &Path) -> Self {
+        Id3Tag(Tag::read_from_path(path).unwrap_or_default())
+    }
+
+    fn extended_text(&self, description: &str) -> Vec<String> {
+        self.0
+            .extended_texts()
+            .find(|x| x.description == description)
+            // id3v2.4 separates multiple values with null
+            .map(|x| x.value.split('\0').map(str::to_owned).collect())
+            .unwrap_or_default()
+    }
+
+    fn set_extended_text(&mut self, description: &str, values: Vec<String>) {
+        self.0.remove_extended_text(Some(description), None);
+        if !values.is_empty() {
+            self.0.add_frame(ExtendedText {
+                description: description.to_owned(),
+                value: values.join("\0"),
+            });
+        }
+    }
+
+    /// Stars of our POPM frame or else the first one with a rating
+    fn rating(&self) -> Option<String> {
+        let popms: Vec<&Popularimeter> = self
+            .0
+            .frames()
+            .filter_map(|x| x.content().popularimeter())
+            .filter(|x| x.rating != 0)
+            .collect();
+        let popm = popms
+            .iter()
+            .find(|x| x.user == POPM_USER)
+            .or(popms.first())?;
+        let stars = match popm.rating {
+            0..=31 => 1,
+            32..=95 => 2,
+            96..=159 => 3,
+            160..=223 => 4,
+            _ => 5,
+        };
+        Some(stars.to_string())
+    }
+
+    /// Replaces our POPM frame, play counts are kept and other users' frames left alone
+    fn set_rating(&mut self, stars: Option<u8>) {
+        let counter = self
+            .0
+            .frames()
+            .filter_map(|x| x.content().popularimeter())
+            .find(|x| x.user == POPM_USER)
+            .map(|x| x.counter)
+            .unwrap_or_default();
+        let others: Vec<_> = self
+            .0
+            .remove("POPM")
+            .into_iter()
+            .filter(|x| {
+                x.content()
+                    .popularimeter()
+                    .is_some_and(|x| x.user != POPM_USER)
+            })
+            .collect();
+        for frame in others {
+            self.0.add_frame(frame);
+        }
+        if let Some(stars) = stars {
+            self.0.add_frame(Popularimeter {
+                user: POPM_USER.to_owned(),
+                rating: POPM_STARS[usize::from(stars.clamp(1, 5)) - 1],
+                counter,
+            });
+        }
+    }
+
+    /// TPE1 values, id3v2.4 separates them with null
+    fn artists(&self) -> Vec<String> {
+        self.0
+            .artist()
+            .map(|x| x.split('\0').map(str::to_owned).collect())
+            .unwrap_or_default()
+    }
+
+    fn musicbrainz_recording_id(&self) -> Option<String> {
+        self.0
+            .unique_file_identifiers()
+            .find(|x| x.owner_identifier == MUSICBRAINZ_UFID_OWNER)
+            .and_then(|x| String::from_utf8(x.identifier.clone()).ok())
+    }
+
+    fn set_musicbrainz_recording_id(&mut self, recording_id: Option<String>) {
+        self.0
+            .remove_unique_file_identifier_by_owner_identifier(MUSICBRAINZ_UFID_OWNER);
+        if let Some(recording_id) = recording_id {
+            self.0.add_frame(UniqueFileIdentifier {
+                owner_identifier: MUSICBRAINZ_UFID_OWNER.to_owned(),
+                identifier: recording_id.into_bytes(),
+            });
+        }
+    }
+}
+
+fn year_timestamp(year: i32) -> Timestamp {
+    Timestamp {
+        year,
+        month: None,
+        day: None,
+        hour: None,
+        minute: None,
+        second: None,
+    }
+}
+
+fn parse<T: std::str::FromStr>(values: &[String]) -> Option<T> {
+    values.first()?.trim().parse().ok()
+}
+
+impl SongTag for Id3Tag {
+    fn get(&self, field: Field) -> Vec<String> {
+        let tag = &self.0;
+        let value = match field {
+            Field::Title => tag.title().map(str::to_owned),
+            Field::Artist => return self.artists(),
+            // several TPE1 values are the credited artists, one is just the artist
+            Field::Artists => {
+                let artists = self.artists();
+                return if artists.len() > 1 { artists } else { vec![] };
+            }
+            Field::AlbumArtist => tag.album_artist().map(str::to_owned),
+            Field::Album => tag.album().map(str::to_owned),
+            Field::Track => tag.track().map(|x| x.to_string()),
+            Field::TotalTracks => tag.total_tracks().map(|x| x.to_string()),
+            Field::Disc => tag.disc().map(|x| x.to_string()),
+            Field::TotalDiscs => tag.total_discs().map(|x| x.to_string()),
+            Field::Year => tag
+                .date_recorded()
+                .map(|x| x.year)
+                .or(tag.year())
+                .map(|x| x.to_string()),
+            Field::Date => tag
+                .get("TDRC")
+                .or(tag.get("TYER"))
+                .and_then(|x| x.content().text())
+                .map(str::to_owned),
+            Field::MusicBrainzRecordingId => self.musicbrainz_recording_id(),
+            Field::MusicBrainzReleaseId => return self.extended_text(TXXX_RELEASE_ID),
+            Field::MusicBrainzReleaseGroupId => return self.extended_text(TXXX_RELEASE_GROUP_ID),
+            Field::MusicBrainzArtistId => return self.extended_text(TXXX_ARTIST_ID),
+            Field::Rating => self.rating(),
+            Field::Labels => return self.extended_text(TXXX_LABELS),
+        };
+        value.into_iter().collect()
+    }
+
+    fn set(&mut self, field: Field, values: Vec<String>) {
+        let first = values.first().cloned();
+        let tag = &mut self.0;
+        match field {
+            Field::Title => match first {
+                Some(title) => tag.set_title(title),
+                None => tag.remove_title(),
+            },
+            Field::Artist if values.is_empty() => tag.remove_artist(),
+            Field::Artist => tag.set_text_values("TPE1", values),
+            // no values leaves TPE1, it's the artist's frame too
+            Field::Artists => {
+                if !values.is_empty() {
+                    tag.set_text_values("TPE1", values);
+                }
+            }
+            Field::AlbumArtist => match first {
+                Some(album_artist) => tag.set_album_artist(album_artist),
+                None => tag.remove_album_artist(),
+            },
+            Field::Album => match first {
+                Some(album) => tag.set_album(album),
+                None => tag.remove_album(),
+            },
+            Field::Track => match parse(&values) {
+                Some(track) => tag.set_track(track),
+                None => tag.remove_track(),
+            },
+            Field::TotalTracks => match parse(&values) {
+                Some(total_tracks) => tag.set_total_tracks(total_tracks),
+                None => tag.remove_total_tracks(),
+            },
+            Field::Disc => match parse(&values) {
+                Some(disc) => tag.set_disc(disc),
+                None => tag.remove_disc(),
+            },
+            Field::TotalDiscs => match parse(&values) {
+                Some(total_discs) => tag.set_total_discs(total_discs),
+                None => tag.remove_total_discs(),
+            },
+            Field::Year => match parse(&values) {
+                Some(year) => tag.set_date_recorded(year_timestamp(year)),
+                None => tag.remove_date_recorded(),
+            },
+            Field::Date => match first {
+                Some(date) => tag.set_text("TDRC", date),
+                None => tag.remove_date_recorded(),
+            },
+            Field::MusicBrainzRecordingId => self.set_musicbrainz_recording_id(first),
+            Field::MusicBrainzReleaseId => self.set_extended_text(TXXX_RELEASE_ID, values),
+            Field::MusicBrainzReleaseGroupId => {
+                self.set_extended_text(TXXX_RELEASE_GROUP_ID, values)
+            }
+            Field::MusicBrainzArtistId => self.set_extended_text(TXXX_ARTIST_ID, values),
+            Field::Rating => self.set_rating(first.as_deref().and_then(stars)),
+            Field::Labels => self.set_extended_text(TXXX_LABELS, values),
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        self.0
+            .write_to_path(path, Version::Id3v24)
+            .with_context(|| "failed to write id3 tag")
+    }
+}
@@ -0,0 +1,386 @@
+//! iTunes style metadata atoms of m4a/mp4 files, `moov.udta.meta.ilst`,
+//! see https://developer.apple.com/documentation/quicktime-file-format/metadata_item_list_atom
+
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Result};
+
+use super::{replace_file, Field, SongTag};
+
+const FREEFORM_MEAN: &str = "com.apple.iTunes";
+const UTF8: u32 = 1;
+const IMPLICIT: u32 = 0;
+
+/// Where an atom sits in its parent's data
+#[derive(Debug, Clone, Copy)]
+struct Atom {
+    kind: [u8; 4],
+    start: usize,
+    /// Header length, 8 or 16 with a 64 bit size
+    header: usize,
+    len: usize,
+}
+
+impl Atom {
+    fn payload<'a>(&self, data: &'a [u8]) -> &'a [u8] {
+        &data[self.start + self.header..self.start + self.len]
+    }
+
+    fn end(&self) -> usize {
+        self.start + self.len
+    }
+}
+
+fn atoms(data: &[u8]) -> Result<Vec<Atom>> {
+    let mut atoms = vec![];
+    let mut start = 0;
+    while start + 8 <= data.len() {
+        let size = u32::from_be_bytes(data[start..start + 4].try_into()?) as usize;
+        let kind: [u8; 4] = data[start + 4..start + 8].try_into()?;
+        let (header, len) = match size {
+            // up to the end of the file
+            0 => (8, data.len() - start),
+            1 => {
+                let large = data
+                    .get(start + 8..start + 16)
+                    .ok_or(anyhow!("mp4 atom size truncated"))?;
+                (16, u64::from_be_bytes(large.try_into()?) as usize)
+            }
+            size => (8, size),
+        };
+        if len < header || start + len > data.len() {
+            return Err(anyhow!(
+                "mp4 atom {} has a bad size",
+                String::from_utf8_lossy(&kind)
+            ));
+        }
+        atoms.push(Atom {
+            kind,
+            start,
+            header,
+            len,
+        });
+        start += len;
+    }
+    Ok(atoms)
+}
+
+fn find(data: &[u8], kind: &[u8; 4]) -> Result<Option<Atom>> {
+    Ok(atoms(data)?.into_iter().find(|x| &x.kind == kind))
+}
+
+fn atom(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut data = ((8 + payload.len()) as u32).to_be_bytes().to_vec();
+    data.extend(kind);
+    data.extend(payload);
+    data
+}
+
+/// Atom with version and flags before its payload, like `meta`
+fn full_atom(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    atom(kind, &[&[0; 4], payload].concat())
+}
+
+/// `data` atom holding one value of an item
+fn data_atom(kind: u32, value: &[u8]) -> Vec<u8> {
+    let header = [kind.to_be_bytes(), [0; 4]].concat();
+    atom(b"data", &[&header, value].concat())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Key {
+    Atom(&'static [u8; 4]),
+    /// `----` atom named by its `mean` and `name`, Picard keeps MusicBrainz ids there
+    Freeform(&'static str),
+}
+
+fn key(field: Field) -> Key {
+    match field {
+        Field::Title => Key::Atom(b"\xa9nam"),
+        Field::Artist => Key::Atom(b"\xa9ART"),
+        Field::Artists => Key::Freeform("ARTISTS"),
+        Field::AlbumArtist => Key::Atom(b"aART"),
+        Field::Album => Key::Atom(b"\xa9alb"),
+        Field::Track | Field::TotalTracks => Key::Atom(b"trkn"),
+        Field::Disc | Field::TotalDiscs => Key::Atom(b"disk"),
+        Field::Year | Field::Date => Key::Atom(b"\xa9day"),
+        Field::MusicBrainzRecordingId => Key::Freeform("MusicBrainz Track Id"),
+        Field::MusicBrainzReleaseId => Key::Freeform("MusicBrainz Album Id"),
+        Field::MusicBrainzReleaseGroupId => Key::Freeform("MusicBrainz Release Group Id"),
+        Field::MusicBrainzArtistId => Key::Freeform("MusicBrainz Artist Id"),
+        Field::Rating => Key::Freeform("RATING"),
+        Field::Labels => Key::Freeform("LABELS"),
+    }
+}
+
+/// One `ilst` child, kept as raw bytes so items risto doesn't know survive untouched
+struct Item(Vec<u8>);
+
+impl Item {
+    fn new(key: &Key, values: &[Vec<u8>], kind: u32) -> Self {
+        let data: Vec<u8> = values.iter().flat_map(|x| data_atom(kind, x)).collect();
+        Item(match key {
+            Key::Atom(kind) => atom(kind, &data),
+            Key::Freeform(name) => {
+                let mean = full_atom(b"mean", FREEFORM_MEAN.as_bytes());
+                let name = full_atom(b"name", name.as_bytes());
+                atom(b"----", &[mean, name, data].concat())
+            }
+        })
+    }
+
+    fn children(&self) -> Vec<Atom> {
+        atoms(&self.0[8..]).unwrap_or_default()
+    }
+
+    fn matches(&self, key: &Key) -> bool {
+        match key {
+            Key::Atom(kind) => self.0[4..8] == kind[..],
+            Key::Freeform(name) => {
+                if &self.0[4..8] != b"----" {
+                    return false;
+                }
+                let children = &self.0[8..];
+                let text = |kind| {
+                    self.children()
+                        .into_iter()
+                        .find(|x| &x.kind == kind)
+                        .and_then(|x| x.payload(children).get(4..))
+                        .map(|x| String::from_utf8_lossy(x).into_owned())
+                };
+                text(b"mean").as_deref() == Some(FREEFORM_MEAN)
+                    && text(b"name").is_some_and(|x| x.eq_ignore_ascii_case(name))
+            }
+        }
+    }
+
+    /// Payloads of the `data` atoms, without type and locale
+    fn values(&self) -> Vec<&[u8]> {
+        let children = &self.0[8..];
+        self.children()
+            .into_iter()
+            .filter(|x| &x.kind == b"data")
+            .filter_map(|x| x.payload(children).get(8..))
+            .collect()
+    }
+}
+
+pub struct Mp4Tag {
+    items: Vec<Item>,
+}
+
+/// `moov.udta.meta.ilst` payload, if there is one
+fn ilst(moov: &[u8]) -> Result<Option<&[u8]>> {
+    let Some(udta) = find(moov, b"udta")? else {
+        return Ok(None);
+    };
+    let udta = udta.payload(moov);
+    let Some(meta) = find(udta, b"meta")? else {
+        return Ok(None);
+    };
+    let meta = meta
+        .payload(udta)
+        .get(4..)
+        .ok_or(anyhow!("meta atom truncated"))?;
+    Ok(find(meta, b"ilst")?.map(|x| x.payload(meta)))
+}
+
+/// `parent` with the atom `kind` changed by `change`, or added when missing
+fn replace_child(
+    parent: &[u8],
+    kind: &[u8; 4],
+    change: impl FnOnce(Option<&[u8]>) -> Result<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    match find(parent, kind)? {
+        Some(child) => Ok([
+            &parent[..child.start],
+            &change(Some(&parent[child.start..child.end()]))?[..],
+            &parent[child.end()..],
+        ]
+        .concat()),
+        None => Ok([parent, &change(None)?[..]].concat()),
+    }
+}
+
+/// Adds `delta` to every chunk offset past `from`, they point into `mdat`
+fn shift_chunk_offsets(moov: &mut [u8], from: u64, delta: i64) -> Result<()> {
+    for atom in atoms(moov)? {
+        let start = atom.start + atom.header;
+        let payload = &mut moov[start..atom.end()];
+        match &atom.kind {
+            b"trak" | b"mdia" | b"minf" | b"stbl" => shift_chunk_offsets(payload, from, delta)?,
+            b"stco" | b"co64" => {
+                let width = if &atom.kind == b"stco" { 4 } else { 8 };
+                let count = u32::from_be_bytes(
+                    payload
+                        .get(4..8)
+                        .ok_or(anyhow!("chunk offsets truncated"))?
+                        .try_into()?,
+                ) as usize;
+                let offsets = payload
+                    .get_mut(8..8 + count * width)
+                    .ok_or(anyhow!("chunk offsets truncated"))?;
+                for offset in offsets.chunks_mut(width) {
+                    let old = if width == 4 {
+                        u32::from_be_bytes(offset.try_into()?) as u64
+                    } else {
+                        u64::from_be_bytes(offset.try_into()?)
+                    };
+                    if old < from {
+                        continue;
+                    }
+                    let new = old.saturating_add_signed(delta);
+                    if width == 4 {
+                        let new: u32 = new
+                            .try_into()
+                            .map_err(|_| anyhow!("chunk offset over 4GiB"))?;
+                        offset.copy_from_slice(&new.to_be_bytes());
+                    } else {
+                        offset.copy_from_slice(&new.to_be_bytes());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+impl Mp4Tag {
+    pub fn read(path: &Path) -> Result<Self> {
+        let file = fs::read(path)?;
+        let moov = find(&file, b"moov")?.ok_or(anyhow!("moov atom missing"))?;
+        let items = match ilst(moov.payload(&file))? {
+            Some(ilst) => atoms(ilst)?
+                .into_iter()
+                .map(|x| Item(ilst[x.start..x.end()].to_vec()))
+                .collect(),
+            None => vec![],
+        };
+        Ok(Mp4Tag { items })
+    }
+
+    fn item(&self, key: &Key) -> Option<&Item> {
+        self.items.iter().find(|x| x.matches(key))
+    }
+
+    /// Number and total of `trkn` or `disk`, zero when unset
+    fn pair(&self, key: &Key) -> (u16, u16) {
+        let value = self.item(key).and_then(|x| x.values().first().copied());
+        match value {
+            Some([_, _, a, b, c, d, ..]) => {
+                (u16::from_be_bytes([*a, *b]), u16::from_be_bytes([*c, *d]))
+            }
+            _ => (0, 0),
+        }
+    }
+
+    fn set_item(&mut self, key: Key, values: Vec<Vec<u8>>, kind: u32) {
+        let at = self.items.iter().position(|x| x.matches(&key));
+        self.items.retain(|x| !x.matches(&key));
+        if values.is_empty() {
+            return;
+        }
+        let item = Item::new(&key, &values, kind);
+        match at {
+            Some(at) => self.items.insert(at, item),
+            None => self.items.push(item),
+        }
+    }
+
+    fn ilst(&self) -> Vec<u8> {
+        atom(
+            b"ilst",
+            &self
+                .items
+                .iter()
+                .flat_map(|x| x.0.clone())
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+impl SongTag for Mp4Tag {
+    fn get(&self, field: Field) -> Vec<String> {
+        let key = key(field);
+        let number = match field {
+            Field::Track | Field::Disc => Some(self.pair(&key).0),
+            Field::TotalTracks | Field::TotalDiscs => Some(self.pair(&key).1),
+            _ => None,
+        };
+        if let Some(number) = number {
+            return (number != 0)
+                .then(|| number.to_string())
+                .into_iter()
+                .collect();
+        }
+        let values = self.item(&key).map(Item::values).unwrap_or_default();
+        let values = values
+            .into_iter()
+            .map(|x| String::from_utf8_lossy(x).into_owned());
+        match field {
+            // full dates like "2015-06-01T07:00:00Z" are common
+            Field::Year => values
+                .take(1)
+                .map(|x| x.chars().take_while(char::is_ascii_digit).collect())
+                .filter(|x: &String| !x.is_empty())
+                .collect(),
+            _ => values.collect(),
+        }
+    }
+
+    fn set(&mut self, field: Field, values: Vec<String>) {
+        let key = key(field);
+        let number = values.first().and_then(|x| x.trim().parse::<u16>().ok());
+        let pair = match field {
+            Field::Track | Field::Disc => Some((number.unwrap_or(0), self.pair(&key).1)),
+            Field::TotalTracks | Field::TotalDiscs => {
+                Some((self.pair(&key).0, number.unwrap_or(0)))
+            }
+            _ => None,
+        };
+        match pair {
+            Some((0, 0)) => self.set_item(key, vec![], IMPLICIT),
+            Some((number, total)) => {
+                let mut value = [[0; 2], number.to_be_bytes(), total.to_be_bytes()].concat();
+                if matches!(field, Field::Track | Field::TotalTracks) {
+                    value.extend([0; 2]);
+                }
+                self.set_item(key, vec![value], IMPLICIT)
+            }
+            None => {
+                let values = values.into_iter().map(String::into_bytes).collect();
+                self.set_item(key, values, UTF8)
+            }
+        }
+    }
+
+    /// Rewrites `moov`, chunk offsets are fixed when `mdat` comes after it
+    fn save(&self, path: &Path) -> Result<()> {
+        let file = fs::read(path)?;
+        let moov = find(&file, b"moov")?.ok_or(anyhow!("moov atom missing"))?;
+        if moov.header != 8 {
+            return Err(anyhow!("moov atom with 64 bit size"));
+        }
+        let payload = replace_child(moov.payload(&file), b"udta", |udta| {
+            let udta = udta.map(|x| &x[8..]).unwrap_or_default();
+            let udta = replace_child(udta, b"meta", |meta| {
+                let Some(meta) = meta else {
+                    let hdlr = full_atom(b"hdlr", &[&[0; 4], &b"mdirappl"[..], &[0; 9]].concat());
+                    return Ok(full_atom(b"meta", &[hdlr, self.ilst()].concat()));
+                };
+                let children = meta.get(12..).ok_or(anyhow!("meta atom truncated"))?;
+                let children = replace_child(children, b"ilst", |_| Ok(self.ilst()))?;
+                Ok(full_atom(b"meta", &children))
+            })?;
+            Ok(atom(b"udta", &udta))
+        })?;
+        let mut new_moov = atom(b"moov", &payload);
+
+        let delta = new_moov.len() as i64 - moov.len as i64;
+        shift_chunk_offsets(&mut new_moov[8..], moov.end() as u64, delta)?;
+
+        let data = [&file[..moov.start], &new_moov, &file[moov.end()..]].concat();
+        replace_file(path, &data)
+    }
+}
@@ -0,0 +1,95 @@
+//! Comment header of Ogg Vorbis and Opus streams,
+//! see https://xiph.org/vorbis/doc/Vorbis_I_spec.html#x1-620004.2.1 and RFC 7845
+
+use std::{fs, io::Cursor, path::Path};
+
+use ::ogg::{PacketReader, PacketWriteEndInfo, PacketWriter};
+use anyhow::{anyhow, Context, Result};
+
+use super::{replace_file, vorbis_comment::VorbisComments, Field, SongTag};
+
+const VORBIS_COMMENT: &[u8] = b"\x03vorbis";
+const OPUS_TAGS: &[u8] = b"OpusTags";
+
+pub struct OggTag {
+    /// Magic at the start of the comment packet
+    magic: &'static [u8],
+    comments: VorbisComments,
+}
+
+/// Vorbis or Opus comment packet, the second packet of the first stream
+fn parse_comment_packet(packet: &[u8]) -> Result<OggTag> {
+    let magic = [VORBIS_COMMENT, OPUS_TAGS]
+        .into_iter()
+        .find(|magic| packet.starts_with(magic))
+        .ok_or(anyhow!("second ogg packet is not a vorbis or opus comment"))?;
+    let (comments, _) = VorbisComments::parse(&packet[magic.len()..])?;
+    Ok(OggTag { magic, comments })
+}
+
+impl OggTag {
+    pub fn read(path: &Path) -> Result<Self> {
+        let file = fs::File::open(path)?;
+        let mut reader = PacketReader::new(file);
+        let first = reader.read_packet()?.with_context(|| "empty ogg file")?;
+        loop {
+            let packet = reader
+                .read_packet()?
+                .with_context(|| "ogg comment packet missing")?;
+            if packet.stream_serial() == first.stream_serial() {
+                return parse_comment_packet(&packet.data);
+            }
+        }
+    }
+
+    fn comment_packet(&self) -> Vec<u8> {
+        let mut packet = self.magic.to_vec();
+        packet.extend(self.comments.to_bytes());
+        if self.magic == VORBIS_COMMENT {
+            // framing bit
+            packet.push(1);
+        }
+        packet
+    }
+}
+
+impl SongTag for OggTag {
+    fn get(&self, field: Field) -> Vec<String> {
+        self.comments.get(field)
+    }
+
+    fn set(&mut self, field: Field, values: Vec<String>) {
+        self.comments.set(field, values)
+    }
+
+    /// Pages get written again, packets, granule positions and page breaks are kept
+    fn save(&self, path: &Path) -> Result<()> {
+        let file = fs::read(path)?;
+        let mut reader = PacketReader::new(Cursor::new(file));
+        let mut writer = PacketWriter::new(vec![]);
+        let mut serial = None;
+        let mut index = 0;
+        while let Some(packet) = reader.read_packet()? {
+            let stream = packet.stream_serial();
+            let serial = *serial.get_or_insert(stream);
+            let end = if packet.last_in_stream() {
+                PacketWriteEndInfo::EndStream
+            } else if packet.last_in_page() {
+                PacketWriteEndInfo::EndPage
+            } else {
+                PacketWriteEndInfo::NormalPacket
+            };
+            let absgp = packet.absgp_page();
+            let mut data = packet.data;
+            if stream == serial {
+                if index == 1 {
+                    parse_comment_packet(&data)?;
+                    data = self.comment_packet();
+                }
+                index += 1;
+            }
+            writer.write_packet(data.into_boxed_slice(), stream, end, absgp)?;
+        }
+        replace_file(path, &writer.into_inner())
+    }
+}
@@ -0,0 +1,194 @@
+//! Vorbis comments, the `KEY=value` tags of FLAC, Ogg Vorbis and Opus,
+//! see https://www.xiph.org/vorbis/doc/v-comment.html
+
+use anyhow::{anyhow, Context, Result};
+
+use super::{stars, Field};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VorbisComments {
+    pub vendor: String,
+    /// Keys as found in the file, compared ignoring case
+    pub comments: Vec<(String, String)>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> Result<&[u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or(anyhow!("vorbis comment truncated"))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into()?))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+}
+
+fn push_string(data: &mut Vec<u8>, text: &str) {
+    data.extend((text.len() as u32).to_le_bytes());
+    data.extend(text.as_bytes());
+}
+
+impl VorbisComments {
+    /// Parses a comment block, returns it with the number of bytes it took
+    pub fn parse(data: &[u8]) -> Result<(Self, usize)> {
+        let mut reader = Reader { data, pos: 0 };
+        let vendor = reader.string().with_context(|| "bad vendor string")?;
+        let count = reader.u32()?;
+        let mut comments = vec![];
+        for _ in 0..count {
+            let comment = reader.string()?;
+            // comments without `=` are invalid, nothing to keep
+            if let Some((key, value)) = comment.split_once('=') {
+                comments.push((key.to_owned(), value.to_owned()));
+            }
+        }
+        Ok((VorbisComments { vendor, comments }, reader.pos))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![];
+        push_string(&mut data, &self.vendor);
+        data.extend((self.comments.len() as u32).to_le_bytes());
+        for (key, value) in &self.comments {
+            push_string(&mut data, &format!("{key}={value}"));
+        }
+        data
+    }
+
+    fn values(&self, key: &str) -> Vec<String> {
+        self.comments
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.clone())
+            .collect()
+    }
+
+    /// Replaces every `key` comment with `values`, in place of the first one
+    fn set_values(&mut self, key: &str, values: Vec<String>) {
+        let at = self
+            .comments
+            .iter()
+            .position(|(k, _)| k.eq_ignore_ascii_case(key))
+            .unwrap_or(self.comments.len());
+        self.comments.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+        let at = at.min(self.comments.len());
+        let new = values.into_iter().map(|v| (key.to_owned(), v));
+        self.comments.splice(at..at, new);
+    }
+
+    /// Numbers like "3/12" hold the total too
+    fn number_part(&self, key: &str, total: bool) -> Vec<String> {
+        self.values(key)
+            .into_iter()
+            .take(1)
+            .filter_map(|x| {
+                let mut parts = x.splitn(2, '/');
+                let number = parts.next().map(str::trim);
+                let total_part = parts.next().map(str::trim);
+                if total { total_part } else { number }
+                    .filter(|x| !x.is_empty())
+                    .map(str::to_owned)
+            })
+            .collect()
+    }
+
+    pub fn get(&self, field: Field) -> Vec<String> {
+        match field {
+            Field::Track => self.number_part("TRACKNUMBER", false),
+            Field::TotalTracks => [
+                self.values("TRACKTOTAL"),
+                self.values("TOTALTRACKS"),
+                self.number_part("TRACKNUMBER", true),
+            ]
+            .into_iter()
+            .find(|x| !x.is_empty())
+            .unwrap_or_default(),
+            Field::Disc => self.number_part("DISCNUMBER", false),
+            Field::TotalDiscs => [
+                self.values("DISCTOTAL"),
+                self.values("TOTALDISCS"),
+                self.number_part("DISCNUMBER", true),
+            ]
+            .into_iter()
+            .find(|x| !x.is_empty())
+            .unwrap_or_default(),
+            // full dates like "2015-06-01" are common
+            Field::Year => self
+                .values("DATE")
+                .into_iter()
+                .take(1)
+                .map(|x| x.chars().take_while(char::is_ascii_digit).collect())
+                .filter(|x: &String| !x.is_empty())
+                .collect(),
+            Field::Rating => self
+                .values("RATING")
+                .iter()
+                .take(1)
+                .filter_map(|x| stars(x))
+                .map(|x| x.to_string())
+                .collect(),
+            _ => self.values(key(field)),
+        }
+    }
+
+    pub fn set(&mut self, field: Field, values: Vec<String>) {
+        let total = match field {
+            Field::Track => Some(Field::TotalTracks),
+            Field::Disc => Some(Field::TotalDiscs),
+            Field::TotalTracks => {
+                self.set_values("TOTALTRACKS", vec![]);
+                None
+            }
+            Field::TotalDiscs => {
+                self.set_values("TOTALDISCS", vec![]);
+                None
+            }
+            _ => None,
+        };
+        // a "3/12" number would lose its total, keep it under its own key
+        let total = total.map(|x| (x, self.get(x)));
+        self.set_values(key(field), values);
+        if let Some((total, values)) = total {
+            self.set(total, values);
+        }
+    }
+}
+
+/// Keys MusicBrainz Picard uses
+fn key(field: Field) -> &'static str {
+    match field {
+        Field::Title => "TITLE",
+        Field::Artist => "ARTIST",
+        Field::Artists => "ARTISTS",
+        Field::AlbumArtist => "ALBUMARTIST",
+        Field::Album => "ALBUM",
+        Field::Track => "TRACKNUMBER",
+        Field::TotalTracks => "TRACKTOTAL",
+        Field::Disc => "DISCNUMBER",
+        Field::TotalDiscs => "DISCTOTAL",
+        Field::Year | Field::Date => "DATE",
+        Field::MusicBrainzRecordingId => "MUSICBRAINZ_TRACKID",
+        Field::MusicBrainzReleaseId => "MUSICBRAINZ_ALBUMID",
+        Field::MusicBrainzReleaseGroupId => "MUSICBRAINZ_RELEASEGROUPID",
+        Field::MusicBrainzArtistId => "MUSICBRAINZ_ARTISTID",
+        Field::Rating => "RATING",
+        Field::Labels => "LABELS",
+    }
+}
@@ -0,0 +1,391 @@
+mod common;
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{form_value, Response, StubServer};
+use risto::{
+    acoustid::{
+        ArtistPolicy, CacheMode, Client, ClientConfig, LookupStats, MusicBrainzIds, SongData,
+    },
+    cache::Db,
+};
+use tempfile::TempDir;
+
+const FINGERPRINT: &str = "AQABz0qUkZK4oOfhL-CPc4e5C_wW2H2QH9uDL4cvoT8UNQ-eHtsE8cceeFJx";
+
+fn client_for(server: &StubServer) -> Client {
+    let mut config = ClientConfig::new("test-api-key");
+    config.base_url = server.url.clone();
+    config.timeout = Duration::from_secs(5);
+    config.cache = CacheMode::Bypass;
+    Client::new(config).unwrap()
+}
+
+/// Clients of one test share `db`, sled locks its directory until every handle is dropped
+/// and its flush thread is done, so reopening it races
+fn caching_client_for(server: &StubServer, db: &Db, cache: CacheMode) -> Client {
+    let mut config = ClientConfig::new("test-api-key");
+    config.base_url = server.url.clone();
+    config.cache = cache;
+    Client::with_db(config, db.clone()).unwrap()
+}
+
+#[test]
+fn picks_best_scored_recording_with_title_and_artist() {
+    let server = StubServer::serve(vec![Response::fixture("lookup_funky_kingston.json")]);
+
+    let song = client_for(&server)
+        .lookup(FINGERPRINT, Duration::from_secs(641))
+        .unwrap();
+
+    assert_eq!(song.title, "Funky Kingston");
+    assert_eq!(song.artist, "Toots & The Maytals");
+    assert_eq!(
+        song.musicbrainz.recording_id.as_deref(),
+        Some("cd2e7c47-16f5-46c6-a37c-a1eb7bf599ff")
+    );
+    assert_eq!(song.album, None);
+}
+
+#[test]
+fn release_metadata_prefers_the_original_album() {
+    let server = StubServer::serve(vec![Response::fixture("lookup_with_releases.json")]);
+
+    let song = client_for(&server)
+        .lookup(FINGERPRINT, Duration::from_secs(641))
+        .unwrap();
+
+    assert_eq!(
+        song,
+        SongData {
+            title: "Funky Kingston".to_owned(),
+            artist: "Toots & The Maytals".to_owned(),
+            artists: vec![],
+            album: Some("Funky Kingston".to_owned()),
+            album_artist: Some("Toots & The Maytals".to_owned()),
+            track: Some(7),
+            total_tracks: Some(8),
+            disc: Some(1),
+            total_discs: Some(1),
+            year: Some(1973),
+            musicbrainz: MusicBrainzIds {
+                recording_id: Some("cd2e7c47-16f5-46c6-a37c-a1eb7bf599ff".to_owned()),
+                release_id: Some("6b5e1ab4-2b8a-4bd5-8a3c-7d1c7b6c1a01".to_owned()),
+                release_group_id: Some("3c6b1e32-4c8e-3a0c-9f6a-2b4b2a7e5d10".to_owned()),
+                artist_ids: vec!["0a8d9c4e-3f3b-4f25-9dbd-9d4b1a4e3a8c".to_owned()],
+            },
+        }
+    );
+    let body = &server.requests()[0].body;
+    assert_eq!(
+        form_value(body, "meta").as_deref(),
+        Some("recordings releasegroups releases tracks compress")
+    );
+}
+
+#[test]
+fn artist_policies_map_collaborations() {
+    let server = StubServer::serve(vec![Response::fixture("lookup_collaboration.json")]);
+    let candidates = client_for(&server)
+        .lookup_candidates(FINGERPRINT, Duration::from_secs(176))
+        .unwrap();
+    let song = |policy| candidates[0].song_data(policy).unwrap();
+
+    let joined = song(ArtistPolicy::Joined);
+    assert_eq!(joined.artist, "Major Lazer feat. MØ & DJ Snake");
+    assert!(joined.artists.is_empty());
+    assert_eq!(joined.album_artist.as_deref(), Some("Major Lazer"));
+
+    let first = song(ArtistPolicy::First);
+    assert_eq!(first.artist, "Major Lazer");
+    assert_eq!(first.album_artist.as_deref(), Some("Major Lazer"));
+
+    let multi = song(ArtistPolicy::Multi);
+    assert_eq!(multi.artist, "Major Lazer feat. MØ & DJ Snake");
+    assert_eq!(multi.artists, vec!["Major Lazer", "MØ", "DJ Snake"]);
+
+    let primary = song(ArtistPolicy::PrimaryAlbumArtist);
+    assert_eq!(primary.artist, "Major Lazer feat. MØ & DJ Snake");
+    assert_eq!(primary.album_artist.as_deref(), Some("Major Lazer"));
+}
+
+#[test]
+fn album_artist_is_not_the_title_without_release_data() {
+    let server = StubServer::serve(vec![Response::fixture("lookup_funky_kingston.json")]);
+
+    let song = client_for(&server)
+        .lookup(FINGERPRINT, Duration::from_secs(641))
+        .unwrap();
+
+    assert_eq!(song.album_artist, None);
+}
+
+#[test]
+fn candidates_are_ranked_by_score() {
+    let server = StubServer::serve(vec![Response::fixture("lookup_funky_kingston.json")]);
+
+    let candidates = client_for(&server)
+        .lookup_candidates(FINGERPRINT, Duration::from_secs(641))
+        .unwrap();
+
+    let titles: Vec<_> = candidates.iter().map(|x| x.title.as_deref()).collect();
+    assert_eq!(
+        titles,
+        vec![None, Some("Funky Kingston"), Some("Funky Kingston (live)")]
+    );
+    assert_eq!(candidates[1].score, 0.948);
+    assert_eq!(candidates[1].artists, vec!["Toots & The Maytals"]);
+    assert_eq!(candidates[2].duration, Some(Duration::from_secs(298)));
+}
+
+#[test]
+fn sends_configured_key_fingerprint_and_duration() {
+    let server = StubServer::serve(vec![Response::fixture("lookup_funky_kingston.json")]);
+
+    client_for(&server)
+        .lookup(FINGERPRINT, Duration::from_millis(641_900))
+        .unwrap();
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].path, "/v2/lookup");
+    let body = &requests[0].body;
+    assert_eq!(form_value(body, "client").as_deref(), Some("test-api-key"));
+    assert_eq!(
+        form_value(body, "fingerprint").as_deref(),
+        Some(FINGERPRINT)
+    );
+    assert_eq!(form_value(body, "duration").as_deref(), Some("641"));
+    assert_eq!(form_value(body, "format").as_deref(), Some("json"));
+}
+
+#[test]
+fn no_results_is_an_error() {
+    let server = StubServer::serve(vec![Response::fixture("lookup_no_results.json")]);
+
+    let err = client_for(&server)
+        .lookup(FINGERPRINT, Duration::from_secs(641))
+        .unwrap_err();
+
+    assert!(err.to_string().contains("no match"), "{err:?}");
+}
+
+#[test]
+fn service_error_message_is_reported() {
+    let server = StubServer::serve(vec![Response::fixture("error_invalid_api_key.json")]);
+
+    let err = client_for(&server)
+        .lookup(FINGERPRINT, Duration::from_secs(641))
+        .unwrap_err();
+
+    assert!(err.to_string().contains("invalid API key"), "{err:?}");
+}
+
+#[test]
+fn http_errors_are_reported() {
+    let server = StubServer::serve(vec![Response::status(500)]);
+
+    let res = retrying_client_for(&server, 0).lookup(FINGERPRINT, Duration::from_secs(641));
+
+    assert!(res.is_err());
+}
+
+fn retrying_client_for(server: &StubServer, max_retries: u32) -> Client {
+    let mut config = ClientConfig::new("test-api-key");
+    config.base_url = server.url.clone();
+    config.max_retries = max_retries;
+    config.retry_backoff = Duration::from_millis(10);
+    config.requests_per_second = 100.0;
+    config.cache = CacheMode::Bypass;
+    Client::new(config).unwrap()
+}
+
+#[test]
+fn transient_failures_are_retried() {
+    let server = StubServer::serve(vec![
+        Response::status(429),
+        Response::status(503),
+        Response::fixture("lookup_funky_kingston.json"),
+    ]);
+    let client = retrying_client_for(&server, 3);
+
+    let song = client
+        .lookup(FINGERPRINT, Duration::from_secs(641))
+        .unwrap();
+
+    assert_eq!(song.title, "Funky Kingston");
+    assert_eq!(
+        client.stats(),
+        LookupStats {
+            requests: 3,
+            retried: 2,
+            dropped: 0
+        }
+    );
+}
+
+#[test]
+fn gives_up_after_max_retries() {
+    let server = StubServer::serve(vec![Response::status(503)]);
+    let client = retrying_client_for(&server, 2);
+
+    let res = client.lookup(FINGERPRINT, Duration::from_secs(641));
+
+    assert!(res.is_err());
+    assert_eq!(
+        client.stats(),
+        LookupStats {
+            requests: 3,
+            retried: 2,
+            dropped: 1
+        }
+    );
+}
+
+#[test]
+fn client_errors_are_not_retried() {
+    let server = StubServer::serve(vec![Response::status(400)]);
+    let client = retrying_client_for(&server, 3);
+
+    assert!(client
+        .lookup(FINGERPRINT, Duration::from_secs(641))
+        .is_err());
+    assert_eq!(client.stats().requests, 1);
+}
+
+#[test]
+fn rate_limit_is_shared_between_threads() {
+    let server = StubServer::serve(vec![Response::fixture("lookup_funky_kingston.json")]);
+    let mut config = ClientConfig::new("test-api-key");
+    config.base_url = server.url.clone();
+    config.requests_per_second = 20.0;
+    config.cache = CacheMode::Bypass;
+    let client = Client::new(config).unwrap();
+
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                client
+                    .lookup(FINGERPRINT, Duration::from_secs(641))
+                    .unwrap()
+            });
+        }
+    });
+
+    // first request goes through immediately, the other three wait 50ms each
+    assert!(
+        start.elapsed() >= Duration::from_millis(140),
+        "{:?}",
+        start.elapsed()
+    );
+    assert_eq!(client.stats().requests, 4);
+}
+
+#[test]
+fn batch_results_are_mapped_back_by_index() {
+    let server = StubServer::serve(vec![Response::fixture("batch_lookup.json")]);
+
+    let results = client_for(&server)
+        .lookup_batch(&[
+            ("AQAAfunky", Duration::from_secs(641)),
+            ("AQAAunknown", Duration::from_secs(100)),
+            ("AQAApressure", Duration::from_secs(205)),
+            ("AQAAmissing", Duration::from_secs(300)),
+        ])
+        .unwrap();
+
+    assert_eq!(results.len(), 4);
+    assert_eq!(results[0].as_ref().unwrap().title, "Funky Kingston");
+    assert!(results[1].is_err());
+    assert_eq!(results[2].as_ref().unwrap().title, "Pressure Drop");
+    assert!(results[3].is_err());
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    let body = &requests[0].body;
+    assert_eq!(
+        form_value(body, "fingerprint.2").as_deref(),
+        Some("AQAApressure")
+    );
+    assert_eq!(form_value(body, "duration.2").as_deref(), Some("205"));
+    assert_eq!(form_value(body, "fingerprint"), None);
+}
+
+#[test]
+fn cached_responses_are_reused() {
+    let server = StubServer::serve(vec![Response::fixture("lookup_funky_kingston.json")]);
+    let cache_dir = TempDir::new().unwrap();
+    let db = Db::open(cache_dir.path()).unwrap();
+    let client = caching_client_for(&server, &db, CacheMode::Use);
+
+    let first = client
+        .lookup(FINGERPRINT, Duration::from_secs(641))
+        .unwrap();
+    let second = client
+        .lookup(FINGERPRINT, Duration::from_secs(641))
+        .unwrap();
+
+    assert_eq!(first, second);
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[test]
+fn batch_only_requests_uncached_fingerprints() {
+    let server = StubServer::serve(vec![
+        Response::fixture("lookup_funky_kingston.json"),
+        Response::fixture("batch_lookup.json"),
+    ]);
+    let cache_dir = TempDir::new().unwrap();
+    let db = Db::open(cache_dir.path()).unwrap();
+    let client = caching_client_for(&server, &db, CacheMode::Use);
+    client
+        .lookup("AQAAfunky", Duration::from_secs(641))
+        .unwrap();
+
+    let results = client
+        .lookup_batch(&[
+            ("AQAAfunky", Duration::from_secs(641)),
+            ("AQAAunknown", Duration::from_secs(100)),
+        ])
+        .unwrap();
+
+    assert_eq!(results[0].as_ref().unwrap().title, "Funky Kingston");
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(
+        form_value(&requests[1].body, "fingerprint.0").as_deref(),
+        Some("AQAAunknown")
+    );
+    assert_eq!(form_value(&requests[1].body, "fingerprint.1"), None);
+}
+
+#[test]
+fn refresh_asks_again_and_expired_entries_are_ignored() {
+    let server = StubServer::serve(vec![Response::fixture("lookup_funky_kingston.json")]);
+    let cache_dir = TempDir::new().unwrap();
+
+    let db = Db::open(cache_dir.path()).unwrap();
+    let client = caching_client_for(&server, &db, CacheMode::Refresh);
+    client
+        .lookup(FINGERPRINT, Duration::from_secs(641))
+        .unwrap();
+    client
+        .lookup(FINGERPRINT, Duration::from_secs(641))
+        .unwrap();
+    assert_eq!(server.requests().len(), 2);
+    drop(client);
+
+    let mut config = ClientConfig::new("test-api-key");
+    config.base_url = server.url.clone();
+    config.cache_ttl = Duration::ZERO;
+    let client = Client::with_db(config, db).unwrap();
+    std::thread::sleep(Duration::from_millis(1100));
+    client
+        .lookup(FINGERPRINT, Duration::from_secs(641))
+        .unwrap();
+    assert_eq!(server.requests().len(), 3);
+}
@@ -0,0 +1,299 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, SystemTime},
+};
+
+use risto::{
+    cache::{Counts, Db, SCHEMA_VERSION},
+    ratings::{import_likes, Like, Verdict},
+    AcoustId, FileHash, Song, DEFAULT_MAX_ANALYSED,
+};
+use tempfile::TempDir;
+
+#[test]
+fn file_hashes_are_indexed_by_size_and_mtime() {
+    let cache_dir = TempDir::new().unwrap();
+    let db = Db::open(cache_dir.path()).unwrap();
+    let song = Path::new("/music/Funky Kingston.mp3");
+    let modified = SystemTime::now();
+    let hash = FileHash::from("1234");
+
+    assert!(db.get_audio_hash(song, 100, modified).is_none());
+    db.insert_audio_hash(song, 100, modified, &hash);
+
+    let cached = db.get_audio_hash(song, 100, modified).unwrap();
+    assert_eq!(cached.to_string(), "1234");
+    assert!(db.get_audio_hash(song, 101, modified).is_none());
+    assert!(db
+        .get_audio_hash(song, 100, modified + Duration::from_secs(1))
+        .is_none());
+    assert!(db
+        .get_audio_hash(Path::new("/music/other.mp3"), 100, modified)
+        .is_none());
+
+    // both hashes share the entry
+    assert!(db.get_file_hash(song, 100, modified).is_none());
+    db.insert_file_hash(song, 100, modified, &FileHash::from("5678"));
+    let cached = db.get_file_hash(song, 100, modified).unwrap();
+    assert_eq!(cached.to_string(), "5678");
+    assert!(db.get_audio_hash(song, 100, modified).is_some());
+    // and a changed file starts over
+    db.insert_file_hash(song, 101, modified, &FileHash::from("9"));
+    assert!(db.get_audio_hash(song, 101, modified).is_none());
+}
+
+fn temporary() -> sled::Db {
+    sled::Config::new().temporary(true).open().unwrap()
+}
+
+#[test]
+fn unversioned_databases_are_upgraded() {
+    let legacy = temporary();
+    let mut precise = 641u64.to_be_bytes().to_vec();
+    precise.extend(500_000_000u32.to_be_bytes());
+    legacy.insert("123-duration", precise).unwrap();
+    legacy.insert("123-acoustid", "AQAAfunky").unwrap();
+    legacy.insert("4567-641-lookup", "{}").unwrap();
+    legacy
+        .insert(
+            "/music/Funky Kingston.mp3-hash",
+            r#"{"size":100,"modified":{"secs":1,"nanos":0},"hash":"123"}"#,
+        )
+        .unwrap();
+    legacy
+        .insert("/music/broken.mp3-hash", &[0xff, 0xfe][..])
+        .unwrap();
+
+    let db = Db::from_sled(&legacy).unwrap();
+    // keyed by whole-file hashes, no song asks for them anymore
+    let funky = FileHash::from("123");
+    assert!(db.get_duration(&funky).is_none());
+    assert!(db.cached(&funky).fingerprint.is_none());
+    assert_eq!(
+        db.stats().unwrap().0,
+        Counts {
+            files: 1,
+            ..Default::default()
+        }
+    );
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+    // the old index hashed whole files, those aren't audio hashes
+    let funky_path = Path::new("/music/Funky Kingston.mp3");
+    let hash = db.get_file_hash(funky_path, 100, modified).unwrap();
+    assert_eq!(hash.to_string(), "123");
+    assert!(db.get_audio_hash(funky_path, 100, modified).is_none());
+
+    // only the schema version is left in the default tree
+    assert_eq!(legacy.len(), 1);
+    // and upgrading again changes nothing
+    let db = Db::from_sled(&legacy).unwrap();
+    assert!(db.get_file_hash(funky_path, 100, modified).is_some());
+}
+
+#[test]
+fn newer_schemas_are_refused() {
+    let db = temporary();
+    Db::from_sled(&db).unwrap();
+    db.insert("schema-version", &(SCHEMA_VERSION + 1).to_be_bytes())
+        .unwrap();
+    assert!(Db::from_sled(&db).is_err());
+}
+
+#[test]
+fn fingerprints_of_other_algorithms_are_ignored() {
+    let sled = temporary();
+    let db = Db::from_sled(&sled).unwrap();
+    sled.open_tree("fingerprints")
+        .unwrap()
+        .insert(
+            "123",
+            r#"{"version":1,"value":{"algorithm":1,"fingerprint":"AQAAold"}}"#,
+        )
+        .unwrap();
+    let hash = FileHash::from("123");
+    assert!(db.get_acoustid(&hash, DEFAULT_MAX_ANALYSED).is_none());
+
+    db.insert_acoustid(&hash, DEFAULT_MAX_ANALYSED, AcoustId::from("AQAAnew"));
+    assert_eq!(
+        db.get_acoustid(&hash, DEFAULT_MAX_ANALYSED)
+            .unwrap()
+            .to_string(),
+        "AQAAnew"
+    );
+}
+
+#[test]
+fn fingerprints_of_other_lengths_are_misses() {
+    let db = Db::from_sled(&temporary()).unwrap();
+    let hash = FileHash::from("123");
+    let (short, long) = (Duration::from_secs(30), Duration::from_secs(600));
+    db.insert_acoustid(&hash, short, AcoustId::from("AQAAshort"));
+    assert!(db.get_acoustid(&hash, long).is_none());
+    db.insert_acoustid(&hash, long, AcoustId::from("AQAAlong"));
+    assert_eq!(
+        db.get_acoustid(&hash, long).unwrap().to_string(),
+        "AQAAlong"
+    );
+    assert!(db.get_acoustid(&hash, short).is_none());
+}
+
+/// Two files with their duration and fingerprint cached
+fn two_songs() -> Db {
+    let db = Db::from_sled(&temporary()).unwrap();
+    for (path, hash) in [("/music/a.mp3", "1"), ("/music/b.mp3", "2")] {
+        let hash = FileHash::from(hash);
+        db.insert_audio_hash(Path::new(path), 100, SystemTime::now(), &hash);
+        db.insert_duration(&hash, Duration::from_secs(200));
+        db.insert_acoustid(&hash, DEFAULT_MAX_ANALYSED, AcoustId::from("AQAA"));
+    }
+    db
+}
+
+#[test]
+fn prune_keeps_only_the_given_files() {
+    let db = two_songs();
+    let keep = [(PathBuf::from("/music/a.mp3"), FileHash::from("1"))];
+    let counted = db.prune(&keep, true).unwrap();
+    assert_eq!(db.entries().unwrap().len(), 2);
+    let dropped = db.prune(&keep, false).unwrap();
+    assert_eq!(dropped, counted);
+    assert_eq!(
+        dropped,
+        Counts {
+            files: 1,
+            durations: 1,
+            fingerprints: 1,
+            lookups: 0,
+            verdicts: 0
+        }
+    );
+    let entries = db.entries().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].path, Path::new("/music/a.mp3"));
+    assert_eq!(entries[0].cached.duration, Some(Duration::from_secs(200)));
+    assert!(db.get_duration(&FileHash::from("2")).is_none());
+}
+
+#[test]
+fn prune_refuses_roots_without_songs() {
+    let dir = TempDir::new().unwrap();
+    let covers = dir.path().join("covers");
+    fs::create_dir(&covers).unwrap();
+    fs::write(covers.join("cover.jpg"), b"not a song").unwrap();
+    for root in [dir.path().join("unmounted"), covers] {
+        let output = Command::new(env!("CARGO_BIN_EXE_risto"))
+            .env("XDG_DATA_HOME", dir.path().join("data"))
+            .args(["cache", "prune", "--yes"])
+            .arg(&root)
+            .output()
+            .unwrap();
+        assert!(!output.status.success(), "{}", root.display());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("is it mounted?"), "{stderr}");
+    }
+}
+
+#[test]
+fn exports_import_into_another_database() {
+    let db = two_songs();
+    let verdict = Verdict::new(Like::Yes, Path::new("/music/a.mp3"));
+    db.add_verdict(&FileHash::from("1"), verdict.clone())
+        .unwrap();
+    let mut export = vec![];
+    let exported = db.export(&mut export).unwrap();
+    assert_eq!(exported.verdicts, 1);
+
+    let other = Db::from_sled(&temporary()).unwrap();
+    assert_eq!(other.import(export.as_slice()).unwrap(), exported);
+    assert_eq!(other.stats().unwrap().0, db.stats().unwrap().0);
+    assert_eq!(
+        other
+            .get_acoustid(&FileHash::from("2"), DEFAULT_MAX_ANALYSED)
+            .unwrap()
+            .to_string(),
+        "AQAA"
+    );
+
+    assert_eq!(other.verdicts(&FileHash::from("1")).unwrap(), [verdict]);
+
+    // verdicts aren't cache, clearing keeps them
+    let cleared = db.clear().unwrap();
+    assert_eq!(
+        cleared,
+        Counts {
+            verdicts: 0,
+            ..exported
+        }
+    );
+    assert_eq!(
+        db.stats().unwrap().0,
+        Counts {
+            verdicts: 1,
+            ..Counts::default()
+        }
+    );
+}
+
+#[test]
+fn verdicts_follow_moved_songs_and_survive_clearing() {
+    let dir = TempDir::new().unwrap();
+    let db = Db::from_sled(&temporary()).unwrap();
+    let song = dir.path().join("song.flac");
+    fs::copy("tests/fixtures/tags/song.flac", &song).unwrap();
+    let hash = Song::new(&song)
+        .unwrap()
+        .with_cache(db.clone())
+        .hash()
+        .unwrap();
+    db.add_verdict(&hash, Verdict::new(Like::DontKnow, &song))
+        .unwrap();
+    db.add_verdict(&hash, Verdict::new(Like::Yes, &song))
+        .unwrap();
+
+    let moved = dir.path().join("moved.flac");
+    fs::rename(&song, &moved).unwrap();
+    db.clear().unwrap();
+    let hash = Song::new(&moved)
+        .unwrap()
+        .with_cache(db.clone())
+        .hash()
+        .unwrap();
+    let likes: Vec<Like> = db.verdicts(&hash).unwrap().iter().map(|x| x.like).collect();
+    assert_eq!(likes, [Like::DontKnow, Like::Yes]);
+}
+
+#[test]
+fn likes_json_is_imported_once() {
+    let dir = TempDir::new().unwrap();
+    let db = Db::from_sled(&temporary()).unwrap();
+    let song = dir.path().join("song.flac");
+    fs::copy("tests/fixtures/tags/song.flac", &song).unwrap();
+    let likes = dir.path().join("likes.json");
+    let gone = dir.path().join("gone.mp3");
+    fs::write(
+        &likes,
+        serde_json::json!([
+            {"path": song, "like": "No"},
+            {"path": gone, "like": "Yes"},
+        ])
+        .to_string(),
+    )
+    .unwrap();
+
+    let (imported, gone, errors) = import_likes(&db, &likes).unwrap();
+    assert_eq!((imported, gone), (1, 1));
+    assert!(errors.is_empty(), "{errors:?}");
+    let (imported, _, _) = import_likes(&db, &likes).unwrap();
+    assert_eq!(imported, 0);
+
+    let hash = Song::new(&song)
+        .unwrap()
+        .with_cache(db.clone())
+        .hash()
+        .unwrap();
+    let verdicts = db.verdicts(&hash).unwrap();
+    assert_eq!(verdicts.len(), 1);
+    assert_eq!((verdicts[0].like, &verdicts[0].path), (Like::No, &song));
+}
@@ -0,0 +1,199 @@
+use std::{
+    fs,
+    path::PathBuf,
+    process::Command,
+    time::{Duration, SystemTime},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use risto::{
+    dupes::{groups, resolve, Audio, DupeFile, Index, Keep, Log, Options},
+    fingerprint::{bit_error_rate, decode},
+};
+use tempfile::TempDir;
+
+fn decoded(data: &[u8]) -> Vec<u32> {
+    let (algorithm, raw) = decode(&URL_SAFE_NO_PAD.encode(data)).unwrap();
+    assert_eq!(algorithm, 0);
+    raw
+}
+
+// the cases of chromaprint's test_fingerprint_decompressor.cpp
+#[test]
+fn fingerprints_decode_into_raw_items() {
+    assert_eq!(decoded(&[0, 0, 0, 1, 1]), [1]);
+    assert_eq!(decoded(&[0, 0, 0, 1, 73, 0]), [7]);
+    assert_eq!(decoded(&[0, 0, 0, 1, 7, 0]), [1 << 6]);
+    assert_eq!(decoded(&[0, 0, 0, 1, 7, 2]), [1 << 8]);
+    assert_eq!(decoded(&[0, 0, 0, 2, 65, 0]), [1, 0]);
+    assert!(decode("AQAAEw").is_err());
+    assert!(decode("not base64!").is_err());
+}
+
+/// Items of a xorshift generator, as unrelated as fingerprints of different songs
+fn noise(seed: u32, len: usize) -> Vec<u32> {
+    let mut x = seed;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x
+        })
+        .collect()
+}
+
+#[test]
+fn bit_error_rate_finds_the_best_offset() {
+    let song = noise(1, 500);
+    assert_eq!(bit_error_rate(&song, &song, 0, 100), Some(0.0));
+    // 2 seconds of silence trimmed off
+    assert_eq!(bit_error_rate(&song, &song[16..], 20, 100), Some(0.0));
+    assert_eq!(bit_error_rate(&song[16..], &song, 20, 100), Some(0.0));
+
+    let other = noise(2, 500);
+    let unrelated = bit_error_rate(&song, &other, 20, 100).unwrap();
+    assert!((0.4..0.6).contains(&unrelated), "{unrelated}");
+    assert_eq!(bit_error_rate(&song, &song[..50], 0, 100), None);
+}
+
+fn audio(name: &str, seconds: u64, fingerprint: Vec<u32>) -> Audio {
+    Audio {
+        path: PathBuf::from(name),
+        duration: Duration::from_secs(seconds),
+        fingerprint,
+    }
+}
+
+#[test]
+fn songs_are_grouped_with_their_encodes() {
+    let song = noise(1, 900);
+    // a lossy encode flips a bit here and there
+    let encode: Vec<u32> = song
+        .iter()
+        .enumerate()
+        .map(|(i, x)| if i % 3 == 0 { x ^ 0x0101 } else { *x })
+        .collect();
+    let songs = [
+        audio("song.flac", 200, song.clone()),
+        audio("other.mp3", 200, noise(2, 900)),
+        audio("song.mp3", 199, encode[8..].to_vec()),
+        audio("song edit.mp3", 150, song.clone()),
+        audio("song.m4a", 201, song),
+    ];
+
+    assert_eq!(groups(&songs, &Options::default()), [vec![0, 2, 4]]);
+}
+
+#[test]
+fn incoming_songs_are_found_in_the_library() {
+    let library = Index::new(vec![
+        audio("library/b.flac", 300, noise(2, 900)),
+        audio("library/a.flac", 200, noise(1, 900)),
+        audio("library/c.flac", 100, noise(3, 900)),
+    ]);
+    let options = Options::default();
+
+    let incoming = audio("incoming/a.mp3", 201, noise(1, 900)[4..].to_vec());
+    let (found, rate) = library.find(&incoming, &options).unwrap();
+    assert_eq!(found.path, PathBuf::from("library/a.flac"));
+    assert_eq!(rate, 0.0);
+
+    let new = audio("incoming/d.mp3", 300, noise(4, 900));
+    assert!(library.find(&new, &options).is_none());
+    // same audio but a minute longer, e.g. another mix
+    let longer = audio("incoming/a.mp3", 260, noise(1, 900));
+    assert!(library.find(&longer, &options).is_none());
+}
+
+fn copy(path: &str, bitrate: f64, size: u64, days_old: u64) -> DupeFile {
+    DupeFile {
+        audio: audio(path, 200, vec![]),
+        format: path.rsplit('.').next().unwrap().to_uppercase(),
+        bitrate,
+        size,
+        modified: SystemTime::now() - Duration::from_secs(days_old * 24 * 60 * 60),
+    }
+}
+
+#[test]
+fn policies_pick_the_copy_to_keep() {
+    let copies = [
+        copy("/music/mp3/song.mp3", 320_000.0, 8_000_000, 10),
+        copy("/music/flac/song.flac", 300_000.0, 30_000_000, 5),
+        copy("/old/song.ogg", 500_000.0, 12_000_000, 400),
+    ];
+    let pick = |policy: &str| policy.parse::<Keep>().unwrap().pick(&copies);
+
+    assert_eq!(pick("best-quality"), Some(1));
+    assert_eq!(pick("largest"), Some(1));
+    assert_eq!(pick("oldest"), Some(2));
+    assert_eq!(pick("in-dir=/music/mp3"), Some(0));
+    assert_eq!(pick("in-dir=/elsewhere"), None);
+    assert_eq!(Keep::BestQuality.pick(&[]), None);
+
+    assert_eq!(
+        "in-dir=/music".parse::<Keep>().unwrap().to_string(),
+        "in-dir=/music"
+    );
+    assert_eq!(
+        "in-dir=~/Music".parse::<Keep>().unwrap(),
+        Keep::InDir(PathBuf::from(shellexpand::tilde("~/Music").as_ref()))
+    );
+    assert!("in-dir".parse::<Keep>().is_err());
+    assert!("smallest".parse::<Keep>().is_err());
+}
+
+#[test]
+fn only_copies_matching_the_kept_one_are_trashed() {
+    let dir = TempDir::new().unwrap();
+    // the trash is made in the temp dir, on the copies' filesystem
+    std::env::set_var("XDG_DATA_HOME", dir.path().join("data"));
+    let song = noise(1, 900);
+    // 3 of 32 bits differ between neighbours, 6 between the ends of the chain
+    let flipped = |mask: u32| song.iter().map(|x| x ^ mask).collect::<Vec<_>>();
+    let chain = [
+        ("a.flac", song.clone()),
+        ("b.mp3", flipped(0x0000_0111)),
+        ("c.ogg", flipped(0x0111_0111)),
+    ];
+    let copies: Vec<DupeFile> = chain
+        .into_iter()
+        .map(|(name, fingerprint)| {
+            let path = dir.path().join(name);
+            fs::write(&path, name).unwrap();
+            let mut copy = copy(path.to_str().unwrap(), 320_000.0, 8_000_000, 10);
+            copy.audio.fingerprint = fingerprint;
+            copy
+        })
+        .collect();
+    let options = Options::default();
+    let audios: Vec<Audio> = copies.iter().map(|x| x.audio.clone()).collect();
+    assert_eq!(groups(&audios, &options), [vec![0, 1, 2]]);
+
+    let mut log = Log::create(dir.path().join("log.jsonl")).unwrap();
+    let resolution = resolve(&copies, 0, "best-quality", &options, &mut log).unwrap();
+
+    assert_eq!(resolution.trashed, [copies[1].path()]);
+    assert_eq!(resolution.unmatched, [copies[2].path()]);
+    assert!(copies[0].path().exists() && copies[2].path().exists());
+    assert!(!copies[1].path().exists());
+}
+
+#[test]
+fn only_audio_files_are_compared() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("cover.jpg"), b"not a song").unwrap();
+    fs::write(dir.path().join("release.nfo"), b"not a song").unwrap();
+    let folder = dir.path().to_str().unwrap();
+    for args in [vec!["dupes", folder], vec!["match", folder, folder]] {
+        let output = Command::new(env!("CARGO_BIN_EXE_risto"))
+            .env("XDG_DATA_HOME", dir.path().join("data"))
+            .args(args)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{stderr}");
+        assert!(!stderr.contains('❌'), "{stderr}");
+    }
+}
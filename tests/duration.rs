@@ -0,0 +1,102 @@
+use std::{fs, path::Path, time::Duration};
+
+use risto::duration::from_headers;
+use tempfile::TempDir;
+
+fn length(path: impl AsRef<Path>) -> Duration {
+    from_headers(path.as_ref()).unwrap().unwrap()
+}
+
+fn secs(count: u64, sample_rate: u64) -> Duration {
+    Duration::from_secs_f64(count as f64 / sample_rate as f64)
+}
+
+#[test]
+fn containers_tell_their_length() {
+    assert_eq!(length("tests/fixtures/tags/song.flac"), secs(44100, 44100));
+    assert_eq!(length("tests/fixtures/tags/song.ogg"), secs(4096, 44100));
+    // pre-skip is not part of the song
+    assert_eq!(length("tests/fixtures/tags/song.opus"), secs(1920, 48000));
+    assert_eq!(length("tests/fixtures/tags/song.m4a"), secs(44100, 44100));
+}
+
+#[test]
+fn wav_length_comes_from_the_data_chunk() {
+    let dir = TempDir::new().unwrap();
+    let song = dir.path().join("song.wav");
+    let data = vec![0u8; 44100 * 4 / 2];
+    let mut wav = b"RIFF".to_vec();
+    wav.extend((36 + data.len() as u32).to_le_bytes());
+    wav.extend(b"WAVEfmt ");
+    wav.extend(16u32.to_le_bytes());
+    // pcm, stereo, 44100 Hz, 4 bytes a frame, 16 bits
+    wav.extend([1, 0, 2, 0]);
+    wav.extend(44100u32.to_le_bytes());
+    wav.extend((44100u32 * 4).to_le_bytes());
+    wav.extend([4, 0, 16, 0]);
+    wav.extend(b"data");
+    wav.extend((data.len() as u32).to_le_bytes());
+    wav.extend(data);
+    fs::write(&song, wav).unwrap();
+
+    assert_eq!(length(&song), Duration::from_millis(500));
+}
+
+/// ID3v2 tag and an MPEG-1 layer III frame, 44100 Hz joint stereo, with `info` after the
+/// side information
+fn mp3_with(info: &[u8]) -> Vec<u8> {
+    let mut mp3 = b"ID3\x04\x00\x00\x00\x00\x00\x0a".to_vec();
+    mp3.extend([0; 10]);
+    mp3.extend([0xff, 0xfb, 0x90, 0x64]);
+    mp3.extend([0; 32]);
+    mp3.extend(info);
+    mp3.resize(mp3.len() + 417, 0);
+    mp3
+}
+
+#[test]
+fn mp3_length_comes_from_the_xing_frame() {
+    let dir = TempDir::new().unwrap();
+    let song = dir.path().join("song.mp3");
+    let mut xing = b"Xing".to_vec();
+    xing.extend(1u32.to_be_bytes());
+    xing.extend(100u32.to_be_bytes());
+    // LAME tag with 576 samples of delay and 1000 of padding, 12 bits each
+    xing.extend(b"LAME3.100");
+    xing.extend([0; 12]);
+    xing.extend(&(576u32 << 12 | 1000).to_be_bytes()[1..]);
+    fs::write(&song, mp3_with(&xing)).unwrap();
+
+    assert_eq!(length(&song), secs(100 * 1152 - 576 - 1000, 44100));
+}
+
+#[test]
+fn mp3_without_vbr_frame_has_no_header_length() {
+    let dir = TempDir::new().unwrap();
+    let song = dir.path().join("song.mp3");
+    fs::write(&song, mp3_with(&[])).unwrap();
+
+    assert_eq!(from_headers(&song).unwrap(), None);
+}
+
+#[test]
+fn mp4_moov_may_run_to_the_end_of_the_file() {
+    let dir = TempDir::new().unwrap();
+    let song = dir.path().join("song.m4a");
+    let mut mp4 = 16u32.to_be_bytes().to_vec();
+    mp4.extend(b"ftypM4A \0\0\0\0");
+    // size 0, the last atom
+    mp4.extend(0u32.to_be_bytes());
+    mp4.extend(b"moov");
+    // version 0 mvhd, 1000 units a second, 2500 units long
+    let mut mvhd = vec![0; 12];
+    mvhd.extend(1000u32.to_be_bytes());
+    mvhd.extend(2500u32.to_be_bytes());
+    mvhd.resize(100, 0);
+    mp4.extend((mvhd.len() as u32 + 8).to_be_bytes());
+    mp4.extend(b"mvhd");
+    mp4.extend(mvhd);
+    fs::write(&song, mp4).unwrap();
+
+    assert_eq!(length(&song), Duration::from_millis(2500));
+}
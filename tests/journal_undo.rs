@@ -0,0 +1,240 @@
+use std::{fs, path::Path, process::Command};
+
+use risto::{
+    acoustid::{
+        read_tag_snapshot, restore_tag_snapshot, write_song_data, MusicBrainzIds, SongData,
+        TagSnapshot,
+    },
+    journal::{self, Entry, Journal},
+    rename::{CollisionPolicy, Naming, Outcome, Renamer},
+};
+use tempfile::TempDir;
+
+#[test]
+fn undo_restores_name_and_tags() {
+    let dir = TempDir::new().unwrap();
+    let original = dir.path().join("track01.mp3");
+    let renamed = dir.path().join("Toots & The Maytals - Funky Kingston.mp3");
+    fs::write(&original, b"not really audio").unwrap();
+    let previous = TagSnapshot {
+        title: Some("Track 01".to_owned()),
+        album_artist: Some("Various".to_owned()),
+        track: Some(1),
+        ..Default::default()
+    };
+    restore_tag_snapshot(&original, &previous).unwrap();
+
+    let journal_path = dir.path().join("journal.jsonl");
+    let journal = Journal::create(&journal_path).unwrap();
+    write_song_data(
+        &original,
+        &SongData {
+            title: "Funky Kingston".to_owned(),
+            artist: "Toots & The Maytals".to_owned(),
+            album: Some("Funky Kingston".to_owned()),
+            track: Some(7),
+            total_tracks: Some(8),
+            year: Some(1973),
+            musicbrainz: MusicBrainzIds {
+                recording_id: Some("cd2e7c47-16f5-46c6-a37c-a1eb7bf599ff".to_owned()),
+                artist_ids: vec!["0a8d9c4e-3f3b-4f25-9dbd-9d4b1a4e3a8c".to_owned()],
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let written = read_tag_snapshot(&original);
+    assert_eq!(written.track, Some(7));
+    assert_eq!(written.total_tracks, Some(8));
+    assert_eq!(written.year, Some(1973));
+    assert_eq!(
+        written.musicbrainz_recording_id.as_deref(),
+        Some("cd2e7c47-16f5-46c6-a37c-a1eb7bf599ff")
+    );
+    fs::rename(&original, &renamed).unwrap();
+    journal
+        .record(&Entry {
+            original: original.clone(),
+            renamed: Some(renamed.clone()),
+            previous: previous.clone(),
+            trashed: None,
+        })
+        .unwrap();
+    drop(journal);
+
+    let (restored, errors) = journal::undo(&journal_path).unwrap();
+
+    assert!(errors.is_empty(), "{errors:?}");
+    assert_eq!(restored, vec![original.clone()]);
+    assert!(!renamed.exists());
+    assert_eq!(read_tag_snapshot(&original), previous);
+}
+
+#[test]
+fn undo_refuses_to_overwrite() {
+    let dir = TempDir::new().unwrap();
+    let original = dir.path().join("a.mp3");
+    let renamed = dir.path().join("b.mp3");
+    fs::write(&original, b"new file with the old name").unwrap();
+    fs::write(&renamed, b"renamed file").unwrap();
+
+    let journal_path = dir.path().join("journal.jsonl");
+    Journal::create(&journal_path)
+        .unwrap()
+        .record(&Entry {
+            original: original.clone(),
+            renamed: Some(renamed.clone()),
+            previous: TagSnapshot::default(),
+            trashed: None,
+        })
+        .unwrap();
+
+    let (restored, errors) = journal::undo(&journal_path).unwrap();
+
+    assert!(restored.is_empty());
+    assert_eq!(errors.len(), 1);
+    assert_eq!(fs::read(&renamed).unwrap(), b"renamed file");
+}
+
+/// Copy of the flac fixture tagged as Funky Kingston, `padding` zeros appended raise its bitrate
+fn funky_kingston(path: &Path, padding: usize) {
+    fs::copy("tests/fixtures/tags/song.flac", path).unwrap();
+    let tags = TagSnapshot {
+        title: Some("Funky Kingston".to_owned()),
+        artist: Some("Toots & The Maytals".to_owned()),
+        ..Default::default()
+    };
+    restore_tag_snapshot(path, &tags).unwrap();
+    let mut bytes = fs::read(path).unwrap();
+    bytes.resize(bytes.len() + padding, 0);
+    fs::write(path, bytes).unwrap();
+}
+
+#[test]
+fn undo_takes_lower_bitrate_copies_out_of_the_trash() {
+    let dir = TempDir::new().unwrap();
+    // the trash and the cache are made in the temp dir, on the songs' filesystem
+    std::env::set_var("XDG_DATA_HOME", dir.path().join("data"));
+    let music = dir.path().canonicalize().unwrap().join("music");
+    fs::create_dir(&music).unwrap();
+    let named = music.join("Toots & The Maytals - Funky Kingston.flac");
+    let better = music.join("better.flac");
+    let worse = music.join("worse.flac");
+    funky_kingston(&named, 1000);
+    funky_kingston(&better, 100_000);
+    funky_kingston(&worse, 0);
+
+    let renamer = Renamer::new(Naming {
+        collisions: CollisionPolicy::KeepHigherBitrate,
+        ..Default::default()
+    });
+    let journal_path = dir.path().join("journal.jsonl");
+    let journal = Journal::create(&journal_path).unwrap();
+    let mut outcomes = vec![];
+    for file in [&better, &worse] {
+        let previous = read_tag_snapshot(file);
+        let outcome = renamer.rename(file).unwrap();
+        journal
+            .record(&Entry {
+                original: file.clone(),
+                renamed: outcome.renamed().map(Path::to_path_buf),
+                previous,
+                trashed: outcome.trashed().map(Path::to_path_buf),
+            })
+            .unwrap();
+        outcomes.push(outcome);
+    }
+    assert_eq!(
+        outcomes,
+        [
+            Outcome::Replaced {
+                from: better.clone(),
+                to: named.clone(),
+                trashed: named.clone()
+            },
+            Outcome::Trashed {
+                from: worse.clone(),
+                kept: named.clone()
+            }
+        ]
+    );
+    assert!(!better.exists() && !worse.exists());
+    drop(journal);
+
+    let (restored, errors) = journal::undo(&journal_path).unwrap();
+
+    assert!(errors.is_empty(), "{errors:?}");
+    assert_eq!(restored, vec![worse.clone(), better.clone()]);
+    assert!(worse.exists());
+    assert!(fs::metadata(&better).unwrap().len() > 100_000);
+    // the copy that made room is back under the new name
+    assert!(fs::metadata(&named).unwrap().len() < 100_000);
+}
+
+/// Runs the risto binary, panics with its output unless it succeeds
+fn risto(args: &[&str]) {
+    let output = Command::new(env!("CARGO_BIN_EXE_risto"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+}
+
+#[test]
+fn tags_read_from_file_names_are_undone() {
+    let dir = TempDir::new().unwrap();
+    let music = dir.path().join("music");
+    fs::create_dir(&music).unwrap();
+    let song = music.join("Toots & The Maytals - Funky Kingston.flac");
+    fs::copy("tests/fixtures/tags/song.flac", &song).unwrap();
+    let before = read_tag_snapshot(&song);
+    let journal = dir.path().join("journal.jsonl");
+    let (music, journal) = (music.to_str().unwrap(), journal.to_str().unwrap());
+
+    risto(&["tag-from-filename", music, "--journal", journal]);
+    let tagged = read_tag_snapshot(&song);
+    assert_eq!(tagged.artist.as_deref(), Some("Toots & The Maytals"));
+    assert_eq!(tagged.title.as_deref(), Some("Funky Kingston"));
+    assert_ne!(tagged, before);
+
+    risto(&["undo", journal]);
+    assert_eq!(read_tag_snapshot(&song), before);
+}
+
+#[test]
+fn renames_from_tags_are_undone() {
+    let dir = TempDir::new().unwrap();
+    let music = dir.path().join("music");
+    fs::create_dir(&music).unwrap();
+    let song = music.join("track07.flac");
+    funky_kingston(&song, 0);
+    let before = read_tag_snapshot(&song);
+    let journal = dir.path().join("journal.jsonl");
+    let (music_arg, journal) = (music.to_str().unwrap(), journal.to_str().unwrap());
+
+    risto(&[
+        "rename-files",
+        music_arg,
+        "--from-tags",
+        "--journal",
+        journal,
+    ]);
+    let renamed = music.join("Toots & The Maytals - Funky Kingston.flac");
+    assert!(renamed.exists() && !song.exists());
+
+    risto(&["undo", journal]);
+    assert!(song.exists() && !renamed.exists());
+    assert_eq!(read_tag_snapshot(&song), before);
+}
+
+#[test]
+fn from_tags_refuses_lookup_options() {
+    for option in ["--batch-size=5", "--min-score=0.5", "--artist-policy=first"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_risto"))
+            .args(["rename-files", ".", "--from-tags", option])
+            .output()
+            .unwrap();
+        assert!(!output.status.success(), "{option}");
+    }
+}
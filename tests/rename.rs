@@ -0,0 +1,414 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use risto::{
+    acoustid::{restore_tag_snapshot, TagSnapshot},
+    rename::{move_file, sanitize, CollisionPolicy, Naming, Outcome, Profile, Renamer, Template},
+};
+use tempfile::TempDir;
+
+fn tags() -> TagSnapshot {
+    TagSnapshot {
+        title: Some("Funky Kingston".to_owned()),
+        artist: Some("Toots & The Maytals".to_owned()),
+        album_artist: Some("Toots & The Maytals".to_owned()),
+        album: Some("Funky Kingston".to_owned()),
+        track: Some(7),
+        year: Some(1973),
+        ..Default::default()
+    }
+}
+
+fn renamer(template: &str) -> Renamer {
+    Renamer::new(Naming {
+        template: template.parse().unwrap(),
+        profile: Profile::Posix,
+        ..Default::default()
+    })
+}
+
+fn render(template: &str, tags: &TagSnapshot) -> PathBuf {
+    renamer(template)
+        .path(Path::new("/music/incoming/track07.mp3"), tags)
+        .unwrap()
+}
+
+#[test]
+fn default_template_is_artist_dash_title() {
+    assert_eq!(
+        render("{artist} - {title}", &tags()),
+        Path::new("/music/incoming/Toots & The Maytals - Funky Kingston.mp3")
+    );
+    assert_eq!(Template::default().to_string(), "{artist} - {title}");
+}
+
+#[test]
+fn directories_and_padding() {
+    assert_eq!(
+        render(
+            "{album_artist|artist}/[{year} - ]{album}/{track:02} {title}",
+            &tags()
+        ),
+        Path::new(
+            "/music/incoming/Toots & The Maytals/1973 - Funky Kingston/07 Funky Kingston.mp3"
+        )
+    );
+}
+
+#[test]
+fn optional_sections_are_left_out() {
+    let tags = TagSnapshot {
+        year: None,
+        disc: None,
+        ..tags()
+    };
+    assert_eq!(
+        render("[{year} - ]{album}/[{disc}-]{track:02} {title}", &tags),
+        Path::new("/music/incoming/Funky Kingston/07 Funky Kingston.mp3")
+    );
+}
+
+#[test]
+fn fallbacks_pick_the_first_value() {
+    let tags = TagSnapshot {
+        album_artist: None,
+        album: None,
+        ..tags()
+    };
+    assert_eq!(
+        render(r#"{album_artist|artist}/{album|"Singles"}/{title}"#, &tags),
+        Path::new("/music/incoming/Toots & The Maytals/Singles/Funky Kingston.mp3")
+    );
+}
+
+#[test]
+fn absolute_templates_leave_the_song_directory() {
+    assert_eq!(
+        render("/library/{artist}/{title}", &tags()),
+        Path::new("/library/Toots & The Maytals/Funky Kingston.mp3")
+    );
+}
+
+#[test]
+fn empty_leading_optionals_keep_templates_relative() {
+    let tags = TagSnapshot {
+        album_artist: None,
+        ..tags()
+    };
+    assert!(!"[{album_artist}]/{title}"
+        .parse::<Template>()
+        .unwrap()
+        .is_absolute());
+    assert_eq!(
+        render("[{album_artist}]/{title}", &tags),
+        Path::new("/music/incoming/Funky Kingston.mp3")
+    );
+    assert!("/{title}".parse::<Template>().unwrap().is_absolute());
+}
+
+#[test]
+fn tag_text_does_not_make_directories() {
+    let tags = TagSnapshot {
+        title: Some("AC/DC Tribute".to_owned()),
+        ..tags()
+    };
+    assert_eq!(
+        render("{title}", &tags),
+        Path::new("/music/incoming/AC-DC Tribute.mp3")
+    );
+}
+
+#[test]
+fn missing_fields_are_errors() {
+    let tags = TagSnapshot {
+        album: None,
+        ..tags()
+    };
+    let err = renamer("{album}/{title}")
+        .path(Path::new("/music/song.mp3"), &tags)
+        .unwrap_err();
+    assert_eq!(err.to_string(), "tag album missing");
+}
+
+#[test]
+fn bad_templates_are_rejected() {
+    for template in ["{titel}", "{title", "[{year}", "{track:xx}", "{title}]"] {
+        assert!(template.parse::<Template>().is_err(), "{template}");
+    }
+}
+
+#[test]
+fn sanitize_profiles() {
+    let title = "What's Up?: Live / Remix...";
+    assert_eq!(
+        sanitize(title, Profile::Posix),
+        "What's Up?: Live _ Remix..."
+    );
+    assert_eq!(
+        sanitize(title, Profile::Windows),
+        "What's Up__ Live _ Remix"
+    );
+    assert_eq!(sanitize("Motörhead: Ace", Profile::Ascii), "Motorhead_ Ace");
+    assert_eq!(sanitize("con", Profile::Windows), "con_");
+    assert_eq!(
+        sanitize("...Baby One More Time", Profile::Posix),
+        "Baby One More Time"
+    );
+    assert_eq!(sanitize("..", Profile::Posix), "_");
+}
+
+#[test]
+fn long_names_are_cut_keeping_the_extension() {
+    let tags = TagSnapshot {
+        title: Some("ö".repeat(200)),
+        ..tags()
+    };
+    let renamer = Renamer::new(Naming {
+        template: "{title}".parse().unwrap(),
+        max_name_len: 100,
+        ..Default::default()
+    });
+    let path = renamer
+        .path(Path::new("/music/incoming/track07.flac"), &tags)
+        .unwrap();
+    let name = path.file_name().unwrap().to_str().unwrap();
+    assert!(name.len() <= 100, "{}", name.len());
+    assert!(name.ends_with("ö.flac"));
+}
+
+#[test]
+fn names_too_short_for_the_extension_are_errors() {
+    let renamer = Renamer::new(Naming {
+        max_name_len: 5,
+        ..Default::default()
+    });
+    let song = Path::new("/music/incoming/track07.flac");
+    assert!(renamer.path(song, &tags()).is_err());
+    let renamer = Renamer::new(Naming {
+        max_name_len: 6,
+        ..Default::default()
+    });
+    assert_eq!(
+        renamer.path(song, &tags()).unwrap(),
+        Path::new("/music/incoming/T.flac")
+    );
+}
+
+fn collision_renamer(collisions: CollisionPolicy) -> Renamer {
+    Renamer::new(Naming {
+        collisions,
+        ..Default::default()
+    })
+}
+
+#[test]
+fn collisions_are_skipped() {
+    let dir = TempDir::new().unwrap();
+    let taken = dir.path().join("Toots & The Maytals - Funky Kingston.mp3");
+    let song = dir.path().join("track07.mp3");
+    fs::write(&taken, b"one").unwrap();
+    fs::write(&song, b"two").unwrap();
+
+    let outcome = collision_renamer(CollisionPolicy::Skip)
+        .plan(&song, &tags())
+        .unwrap();
+
+    assert_eq!(
+        outcome,
+        Outcome::Skipped {
+            from: song,
+            wanted: taken.clone(),
+            taken_by: taken,
+        }
+    );
+    assert_eq!(outcome.renamed(), None);
+}
+
+#[test]
+fn collisions_get_a_counter() {
+    let dir = TempDir::new().unwrap();
+    let first = dir.path().join("a.mp3");
+    let second = dir.path().join("b.mp3");
+    let third = dir.path().join("c.mp3");
+    fs::write(
+        dir.path().join("Toots & The Maytals - Funky Kingston.mp3"),
+        b"0",
+    )
+    .unwrap();
+    let renamer = collision_renamer(CollisionPolicy::Suffix);
+
+    // names planned for earlier files in the same run count as taken
+    let outcomes: Vec<_> = [&first, &second, &third]
+        .iter()
+        .map(|song| renamer.plan(song, &tags()).unwrap())
+        .collect();
+
+    let renamed: Vec<_> = outcomes
+        .iter()
+        .map(|x| x.renamed().unwrap().file_name().unwrap().to_owned())
+        .collect();
+    assert_eq!(
+        renamed,
+        [
+            "Toots & The Maytals - Funky Kingston (2).mp3",
+            "Toots & The Maytals - Funky Kingston (3).mp3",
+            "Toots & The Maytals - Funky Kingston (4).mp3",
+        ]
+    );
+}
+
+#[test]
+fn a_file_does_not_collide_with_itself() {
+    let dir = TempDir::new().unwrap();
+    let song = dir.path().join("Toots & The Maytals - Funky Kingston.mp3");
+    fs::write(&song, b"0").unwrap();
+
+    let outcome = collision_renamer(CollisionPolicy::Skip)
+        .plan(&song, &tags())
+        .unwrap();
+
+    assert_eq!(
+        outcome,
+        Outcome::Renamed {
+            from: song.clone(),
+            to: song,
+        }
+    );
+}
+
+#[test]
+fn names_are_read_back_into_tags() {
+    let template: Template = "{artist} - {title}".parse().unwrap();
+    let tags = template
+        .parse_path(Path::new("/music/Mr. Bungle - Retrovertigo.mp3"))
+        .unwrap();
+    assert_eq!(
+        tags,
+        TagSnapshot {
+            artist: Some("Mr. Bungle".to_owned()),
+            title: Some("Retrovertigo".to_owned()),
+            ..Default::default()
+        }
+    );
+}
+
+#[test]
+fn folders_are_read_back_when_the_template_has_them() {
+    let template: Template = "{album_artist}/{year} - {album}/{track:02} {title}"
+        .parse()
+        .unwrap();
+    let tags = template
+        .parse_path(Path::new(
+            "/music/Toots & The Maytals/1973 - Funky Kingston/07 Funky Kingston.flac",
+        ))
+        .unwrap();
+    assert_eq!(
+        tags,
+        TagSnapshot {
+            title: Some("Funky Kingston".to_owned()),
+            album_artist: Some("Toots & The Maytals".to_owned()),
+            album: Some("Funky Kingston".to_owned()),
+            track: Some(7),
+            year: Some(1973),
+            ..Default::default()
+        }
+    );
+}
+
+#[test]
+fn names_not_matching_are_errors() {
+    let template: Template = "{track} {title}".parse().unwrap();
+    assert!(template
+        .parse_path(Path::new("/music/Funky Kingston.mp3"))
+        .is_err());
+    let template: Template = "{artist} - {title}".parse().unwrap();
+    assert!(template
+        .parse_path(Path::new("/music/track07.mp3"))
+        .is_err());
+    let template: Template = "[{year} ]{title}".parse().unwrap();
+    assert!(template.parse_path(Path::new("/music/1973 x.mp3")).is_err());
+}
+
+fn names_in(dir: &Path) -> Vec<String> {
+    let mut names: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn moving_a_file_onto_itself_keeps_it() {
+    let dir = TempDir::new().unwrap();
+    let song = dir.path().join("Artist - Title.mp3");
+    fs::write(&song, b"0").unwrap();
+
+    move_file(&song, &song).unwrap();
+    move_file(&song, &dir.path().join(".").join("Artist - Title.mp3")).unwrap();
+    assert_eq!(names_in(dir.path()), ["Artist - Title.mp3"]);
+}
+
+#[test]
+fn moving_to_a_new_name_creates_folders() {
+    let dir = TempDir::new().unwrap();
+    let song = dir.path().join("track01.mp3");
+    let new = dir.path().join("Artist").join("Title.mp3");
+    fs::write(&song, b"0").unwrap();
+
+    move_file(&song, &new).unwrap();
+    assert!(!song.exists());
+    assert_eq!(fs::read(new).unwrap(), b"0");
+}
+
+#[test]
+fn moving_changes_only_the_case() {
+    let dir = TempDir::new().unwrap();
+    let song = dir.path().join("artist - title.mp3");
+    fs::write(&song, b"0").unwrap();
+
+    move_file(&song, &dir.path().join("Artist - Title.mp3")).unwrap();
+    assert_eq!(names_in(dir.path()), ["Artist - Title.mp3"]);
+}
+
+#[test]
+fn moving_onto_another_file_fails() {
+    let dir = TempDir::new().unwrap();
+    let song = dir.path().join("track01.mp3");
+    let other = dir.path().join("Artist - Title.mp3");
+    fs::write(&song, b"song").unwrap();
+    fs::write(&other, b"other").unwrap();
+
+    assert!(move_file(&song, &other).is_err());
+    assert_eq!(fs::read(song).unwrap(), b"song");
+    assert_eq!(fs::read(other).unwrap(), b"other");
+}
+
+#[test]
+fn songs_are_renamed_after_their_tags() {
+    let dir = TempDir::new().unwrap();
+    let song = dir.path().join("track01.flac");
+    fs::copy("tests/fixtures/tags/song.flac", &song).unwrap();
+    restore_tag_snapshot(&song, &tags()).unwrap();
+
+    let outcome = renamer("{artist}/{title}").rename(&song).unwrap();
+    let renamed = dir.path().join("Toots & The Maytals/Funky Kingston.flac");
+    assert_eq!(
+        outcome,
+        Outcome::Renamed {
+            from: song.clone(),
+            to: renamed.clone()
+        }
+    );
+    assert!(!song.exists() && renamed.exists());
+
+    // already in place
+    let renamer = renamer("{title}");
+    assert_eq!(
+        renamer.rename(&renamed).unwrap().renamed(),
+        Some(renamed.as_path())
+    );
+    assert!(renamed.exists());
+}
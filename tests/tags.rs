@@ -0,0 +1,390 @@
+use std::{
+    fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use id3::{frame::Popularimeter, Tag, TagLike};
+use ogg::PacketReader;
+use risto::{
+    acoustid::{
+        read_tag_snapshot, restore_tag_snapshot, write_song_data, write_tag_changes,
+        MusicBrainzIds, SongData, TagSnapshot,
+    },
+    audio_hash::audio_hash,
+    cache::Db,
+    ratings::{Like, Verdict},
+    tags::{self, Field},
+    Song,
+};
+use tempfile::TempDir;
+
+fn copy_fixture(dir: &TempDir, name: &str) -> PathBuf {
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/tags")
+        .join(name);
+    let file = dir.path().join(name);
+    fs::copy(fixture, &file).unwrap();
+    file
+}
+
+fn lean_on() -> SongData {
+    SongData {
+        title: "Lean On".to_owned(),
+        artist: "Major Lazer feat. MØ & DJ Snake".to_owned(),
+        artists: vec![
+            "Major Lazer".to_owned(),
+            "MØ".to_owned(),
+            "DJ Snake".to_owned(),
+        ],
+        album: Some("Peace Is the Mission".to_owned()),
+        album_artist: Some("Major Lazer".to_owned()),
+        track: Some(3),
+        total_tracks: Some(9),
+        disc: Some(1),
+        total_discs: Some(1),
+        year: Some(2015),
+        musicbrainz: MusicBrainzIds {
+            recording_id: Some("e5a2f1c0-7b3d-4c8e-9a6f-1d2e3f4a5b6c".to_owned()),
+            release_id: Some("c7d8e9f0-2a3b-4c4d-e5f6-a7b8c9d0e1f2".to_owned()),
+            release_group_id: Some("b6c7d8e9-1f2a-4b3c-d4e5-f6a7b8c9d0e1".to_owned()),
+            artist_ids: vec![
+                "e3f4a1b2-8c9d-4e5f-a6b7-c8d9e0f1a2b3".to_owned(),
+                "f4a5b6c7-9d0e-4f1a-b2c3-d4e5f6a7b8c9".to_owned(),
+            ],
+        },
+    }
+}
+
+fn lean_on_snapshot() -> TagSnapshot {
+    TagSnapshot {
+        title: Some("Lean On".to_owned()),
+        artist: Some("Major Lazer feat. MØ & DJ Snake".to_owned()),
+        artists: Some("Major Lazer\0MØ\0DJ Snake".to_owned()),
+        album_artist: Some("Major Lazer".to_owned()),
+        album: Some("Peace Is the Mission".to_owned()),
+        track: Some(3),
+        total_tracks: Some(9),
+        disc: Some(1),
+        total_discs: Some(1),
+        year: Some(2015),
+        date: Some("2015".to_owned()),
+        musicbrainz_recording_id: Some("e5a2f1c0-7b3d-4c8e-9a6f-1d2e3f4a5b6c".to_owned()),
+        musicbrainz_release_id: Some("c7d8e9f0-2a3b-4c4d-e5f6-a7b8c9d0e1f2".to_owned()),
+        musicbrainz_release_group_id: Some("b6c7d8e9-1f2a-4b3c-d4e5-f6a7b8c9d0e1".to_owned()),
+        musicbrainz_artist_id: Some(
+            "e3f4a1b2-8c9d-4e5f-a6b7-c8d9e0f1a2b3\0f4a5b6c7-9d0e-4f1a-b2c3-d4e5f6a7b8c9".to_owned(),
+        ),
+    }
+}
+
+/// Writes every field, checks they read back and that undoing gives the fixture's tag again
+fn round_trip(dir: &TempDir, name: &str) -> (Vec<u8>, Vec<u8>) {
+    let file = copy_fixture(dir, name);
+    let original = fs::read(&file).unwrap();
+    let previous = read_tag_snapshot(&file);
+    assert_eq!(previous.title.as_deref(), Some("track01"));
+
+    write_song_data(&file, &lean_on()).unwrap();
+    assert_eq!(read_tag_snapshot(&file), lean_on_snapshot());
+    let written = fs::read(&file).unwrap();
+
+    restore_tag_snapshot(&file, &previous).unwrap();
+    assert_eq!(read_tag_snapshot(&file), previous);
+    (original, written)
+}
+
+/// Packets with their granule positions, except the comment header
+fn ogg_packets(data: Vec<u8>) -> Vec<(Vec<u8>, u64)> {
+    let mut reader = PacketReader::new(Cursor::new(data));
+    let mut packets = vec![];
+    while let Some(packet) = reader.read_packet().unwrap() {
+        packets.push((packet.data.clone(), packet.absgp_page()));
+    }
+    packets.remove(1);
+    packets
+}
+
+#[test]
+fn flac_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let (original, written) = round_trip(&dir, "song.flac");
+
+    assert!(written.starts_with(b"fLaC"));
+    assert!(!written.starts_with(b"ID3"));
+    // audio frames are the fixture's last bytes
+    assert!(written.ends_with(&original[original.len() - 71..]));
+    assert!(written.windows(15).any(|x| x == b"ENCODER=fixture"));
+}
+
+#[test]
+fn ogg_vorbis_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let (original, written) = round_trip(&dir, "song.ogg");
+
+    assert!(written.windows(15).any(|x| x == b"ENCODER=fixture"));
+    assert_eq!(ogg_packets(written), ogg_packets(original));
+}
+
+#[test]
+fn opus_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let (original, written) = round_trip(&dir, "song.opus");
+
+    assert!(written.windows(8).any(|x| x == b"OpusTags"));
+    assert_eq!(ogg_packets(written), ogg_packets(original));
+}
+
+#[test]
+fn mp4_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let (original, written) = round_trip(&dir, "song.m4a");
+
+    assert!(written.windows(4).any(|x| x == b"\xa9too"));
+    // the chunk offset still points at the audio after moov grew
+    assert!(written.len() > original.len());
+    let stco = written.windows(4).position(|x| x == b"stco").unwrap();
+    let offset = u32::from_be_bytes(written[stco + 12..stco + 16].try_into().unwrap());
+    assert!(written[offset as usize..].starts_with(b"AUDIOFRAMES"));
+}
+
+#[test]
+fn album_artist_is_left_alone_when_unknown() {
+    let dir = TempDir::new().unwrap();
+    let file = dir.path().join("song.mp3");
+    fs::write(&file, b"not really audio").unwrap();
+    let mut tag = Tag::new();
+    tag.set_album_artist("The Original Album Artist");
+    tag.write_to_path(&file, id3::Version::Id3v24).unwrap();
+
+    write_song_data(
+        &file,
+        &SongData {
+            title: "Lean On".to_owned(),
+            artist: "Major Lazer feat. MØ & DJ Snake".to_owned(),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let snapshot = read_tag_snapshot(&file);
+    assert_eq!(
+        snapshot.album_artist.as_deref(),
+        Some("The Original Album Artist")
+    );
+    assert_eq!(
+        snapshot.artist.as_deref(),
+        Some("Major Lazer feat. MØ & DJ Snake")
+    );
+}
+
+#[test]
+fn multiple_artists_are_separate_values() {
+    let dir = TempDir::new().unwrap();
+    let file = dir.path().join("song.mp3");
+    fs::write(&file, b"not really audio").unwrap();
+
+    write_song_data(
+        &file,
+        &SongData {
+            title: "Lean On".to_owned(),
+            artist: "Major Lazer feat. MØ & DJ Snake".to_owned(),
+            artists: vec![
+                "Major Lazer".to_owned(),
+                "MØ".to_owned(),
+                "DJ Snake".to_owned(),
+            ],
+            album_artist: Some("Major Lazer".to_owned()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let tag = Tag::read_from_path(&file).unwrap();
+    assert_eq!(tag.artists(), Some(vec!["Major Lazer", "MØ", "DJ Snake"]));
+    assert_eq!(tag.album_artist(), Some("Major Lazer"));
+}
+
+#[test]
+fn id3_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let file = dir.path().join("song.mp3");
+    fs::write(&file, b"not really audio").unwrap();
+    let mut tag = Tag::new();
+    tag.set_artist("track01 artist");
+    tag.write_to_path(&file, id3::Version::Id3v24).unwrap();
+    let previous = read_tag_snapshot(&file);
+
+    write_song_data(&file, &lean_on()).unwrap();
+    // ID3 keeps the credited artists as TPE1 values instead of the whole credit
+    let artists = Some("Major Lazer\0MØ\0DJ Snake".to_owned());
+    assert_eq!(
+        read_tag_snapshot(&file),
+        TagSnapshot {
+            artist: artists.clone(),
+            artists,
+            ..lean_on_snapshot()
+        }
+    );
+
+    restore_tag_snapshot(&file, &previous).unwrap();
+    assert_eq!(read_tag_snapshot(&file), previous);
+    let tag = Tag::read_from_path(&file).unwrap();
+    assert_eq!(tag.artist(), Some("track01 artist"));
+}
+
+#[test]
+fn full_dates_are_undone() {
+    let dir = TempDir::new().unwrap();
+    let mp3 = dir.path().join("song.mp3");
+    fs::write(&mp3, b"not really audio").unwrap();
+    let files = ["song.flac", "song.ogg", "song.opus", "song.m4a"]
+        .map(|x| copy_fixture(&dir, x))
+        .into_iter()
+        .chain([mp3]);
+
+    for file in files {
+        let mut tag = tags::read(&file).unwrap();
+        tag.set(Field::Date, vec!["2015-06-01".to_owned()]);
+        tag.save(&file).unwrap();
+        let previous = read_tag_snapshot(&file);
+        assert_eq!(previous.year, Some(2015), "{}", file.display());
+        assert_eq!(previous.date.as_deref(), Some("2015-06-01"));
+
+        write_song_data(&file, &lean_on()).unwrap();
+        assert_eq!(read_tag_snapshot(&file).date.as_deref(), Some("2015"));
+
+        restore_tag_snapshot(&file, &previous).unwrap();
+        assert_eq!(read_tag_snapshot(&file), previous, "{}", file.display());
+        let date = tags::read(&file).unwrap().first(Field::Date);
+        assert_eq!(date.as_deref(), Some("2015-06-01"), "{}", file.display());
+    }
+}
+
+#[test]
+fn tag_changes_leave_other_fields_as_they_are() {
+    let dir = TempDir::new().unwrap();
+    let file = copy_fixture(&dir, "song.flac");
+    let mut tag = tags::read(&file).unwrap();
+    tag.set(Field::Date, vec!["2015-06-01".to_owned()]);
+    tag.set(Field::Track, vec!["3/12".to_owned()]);
+    tag.save(&file).unwrap();
+
+    let changes = TagSnapshot {
+        title: Some("Lean On".to_owned()),
+        ..Default::default()
+    };
+    write_tag_changes(&file, &changes).unwrap();
+
+    let written = fs::read(&file).unwrap();
+    assert!(written.windows(15).any(|x| x == b"DATE=2015-06-01"));
+    assert!(written.windows(16).any(|x| x == b"TRACKNUMBER=3/12"));
+    assert_eq!(read_tag_snapshot(&file).title.as_deref(), Some("Lean On"));
+}
+
+#[test]
+fn audio_hash_ignores_tags() {
+    let dir = TempDir::new().unwrap();
+    for name in ["song.flac", "song.ogg", "song.opus", "song.m4a"] {
+        let file = copy_fixture(&dir, name);
+        let file_hash = || {
+            let song = Song::new(&file).unwrap().with_cache(Db::default());
+            song.file_hash().unwrap().to_string()
+        };
+        let (before, whole) = (audio_hash(&file).unwrap().to_string(), file_hash());
+        write_song_data(&file, &lean_on()).unwrap();
+        assert_eq!(audio_hash(&file).unwrap().to_string(), before, "{name}");
+        assert_ne!(file_hash(), whole, "{name}");
+    }
+}
+
+/// APEv2 tag without header, a single Title item
+fn ape_tag() -> Vec<u8> {
+    let mut item = 11u32.to_le_bytes().to_vec();
+    item.extend(0u32.to_le_bytes());
+    item.extend(b"Title\0Funky Kingston");
+    let mut tag = item.clone();
+    tag.extend(b"APETAGEX");
+    tag.extend(2000u32.to_le_bytes());
+    tag.extend((item.len() as u32 + 32).to_le_bytes());
+    tag.extend(1u32.to_le_bytes());
+    tag.extend([0; 12]);
+    tag
+}
+
+#[test]
+fn mp3_audio_hash_ignores_id3_and_ape() {
+    let dir = TempDir::new().unwrap();
+    let file = dir.path().join("song.mp3");
+    let frames: Vec<u8> = (0..4096u32).map(|x| (x % 251) as u8).collect();
+    fs::write(&file, &frames).unwrap();
+    let bare = audio_hash(&file).unwrap().to_string();
+
+    write_song_data(&file, &lean_on()).unwrap();
+    let mut tagged = fs::read(&file).unwrap();
+    tagged.extend(ape_tag());
+    let mut id3v1 = b"TAG".to_vec();
+    id3v1.resize(128, 0);
+    tagged.extend(id3v1);
+    fs::write(&file, tagged).unwrap();
+    assert_eq!(audio_hash(&file).unwrap().to_string(), bare);
+
+    fs::write(&file, &frames[1..]).unwrap();
+    assert_ne!(audio_hash(&file).unwrap().to_string(), bare);
+}
+
+#[test]
+fn ratings_are_written_for_other_players() {
+    let dir = TempDir::new().unwrap();
+    let mp3 = dir.path().join("song.mp3");
+    fs::write(&mp3, b"not really audio").unwrap();
+    let mut tag = Tag::new();
+    tag.add_frame(Popularimeter {
+        user: "someone@example.com".to_owned(),
+        rating: 1,
+        counter: 7,
+    });
+    tag.write_to_path(&mp3, id3::Version::Id3v24).unwrap();
+    let labels = vec!["chill".to_owned(), "set opener".to_owned()];
+    let verdict = Verdict::new(Like::Yes, &mp3).with_rating(Some(4), labels.clone());
+
+    verdict.write_tag(&mp3).unwrap();
+    let tag = Tag::read_from_path(&mp3).unwrap();
+    let popms: Vec<_> = tag
+        .frames()
+        .filter_map(|x| x.content().popularimeter())
+        .map(|x| (x.user.as_str(), x.rating))
+        .collect();
+    assert_eq!(
+        popms,
+        [
+            ("someone@example.com", 1),
+            ("Windows Media Player 9 Series", 196)
+        ]
+    );
+    let read = tags::read(&mp3).unwrap();
+    assert_eq!(read.get(Field::Rating), ["4"]);
+    assert_eq!(read.get(Field::Labels), labels);
+
+    let flac = copy_fixture(&dir, "song.flac");
+    verdict.write_tag(&flac).unwrap();
+    let written = fs::read(&flac).unwrap();
+    assert!(written.windows(8).any(|x| x == b"RATING=4"));
+    assert!(written.windows(17).any(|x| x == b"LABELS=set opener"));
+    let mut read = tags::read(&flac).unwrap();
+    assert_eq!(read.get(Field::Labels), labels);
+    // some players rate out of 100
+    read.set(Field::Rating, vec!["80".to_owned()]);
+    assert_eq!(read.get(Field::Rating), ["4"]);
+}
+
+#[test]
+fn unknown_containers_are_left_alone() {
+    let dir = TempDir::new().unwrap();
+    let file = dir.path().join("song.wav");
+    fs::write(&file, b"RIFF\0\0\0\0WAVE").unwrap();
+
+    assert!(write_song_data(&file, &lean_on()).is_err());
+    assert_eq!(fs::read(&file).unwrap(), b"RIFF\0\0\0\0WAVE");
+    assert_eq!(read_tag_snapshot(&file), TagSnapshot::default());
+}